@@ -0,0 +1,460 @@
+use std::fs::File;
+use std::io::Result;
+use std::path::PathBuf;
+
+use gdnative::api::{InputEventKey, InputMap, OS};
+use serde::{Deserialize, Serialize};
+
+use crate::camera::BOOKMARK_COUNT;
+
+const BINDINGS_FILE: &str = "input.json";
+
+// Godot scancodes
+const KEY_SPACE: i64 = 32;
+const KEY_0: i64 = 48;
+const KEY_ESCAPE: i64 = 16777217;
+const KEY_PAUSE: i64 = 16777225;
+const KEY_LEFT: i64 = 16777231;
+const KEY_UP: i64 = 16777232;
+const KEY_RIGHT: i64 = 16777233;
+const KEY_DOWN: i64 = 16777234;
+const KEY_F1: i64 = 16777244;
+const KEY_F5: i64 = 16777248;
+const KEY_F9: i64 = 16777252;
+
+const CONTROL_GROUP_COUNT: u8 = 9;
+
+fn file_path() -> PathBuf {
+    let os = OS::godot_singleton();
+    let mut path = PathBuf::from(os.get_user_data_dir().to_string());
+    path.push(BINDINGS_FILE);
+    path
+}
+
+// -----------------------------------------------------------------------------
+//     - Action -
+// -----------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Action {
+    // Camera
+    CameraLeft,
+    CameraRight,
+    CameraForward,
+    CameraBack,
+    CameraRotateLeft,
+    CameraRotateRight,
+    CameraFollow,
+    CameraFocus,
+    BookmarkStore(u8),
+    BookmarkRecall(u8),
+
+    // Selection
+    SelectAll,
+    ControlGroupAssign(u8),
+    ControlGroupAdd(u8),
+    ControlGroupRecall(u8),
+
+    // Commands
+    March,
+    SquadCreate,
+    SquadSplit,
+    SquadMerge,
+    FormationUndo,
+    FormationRedo,
+    FormationLeft,
+    FormationRight,
+    FormationUp,
+    FormationDown,
+    OverheadBars,
+    AssignmentMode,
+
+    // Game
+    TimePause,
+    TimeStep,
+    TimeFaster,
+    TimeSlower,
+    Save,
+    Load,
+    Quit,
+}
+
+impl Action {
+    /// The name of the action in Godot's `InputMap`
+    pub fn name(&self) -> String {
+        match self {
+            Self::CameraLeft => "camera_left".into(),
+            Self::CameraRight => "camera_right".into(),
+            Self::CameraForward => "camera_forward".into(),
+            Self::CameraBack => "camera_back".into(),
+            Self::CameraRotateLeft => "camera_rotate_left".into(),
+            Self::CameraRotateRight => "camera_rotate_right".into(),
+            Self::CameraFollow => "camera_follow".into(),
+            Self::CameraFocus => "camera_focus".into(),
+            Self::BookmarkStore(i) => format!("bookmark_store_{}", i + 1),
+            Self::BookmarkRecall(i) => format!("bookmark_recall_{}", i + 1),
+            Self::SelectAll => "select_all".into(),
+            Self::ControlGroupAssign(i) => format!("control_group_assign_{}", i + 1),
+            Self::ControlGroupAdd(i) => format!("control_group_add_{}", i + 1),
+            Self::ControlGroupRecall(i) => format!("control_group_recall_{}", i + 1),
+            Self::March => "march".into(),
+            Self::SquadCreate => "squad_create".into(),
+            Self::SquadSplit => "squad_split".into(),
+            Self::SquadMerge => "squad_merge".into(),
+            Self::FormationUndo => "formation_undo".into(),
+            Self::FormationRedo => "formation_redo".into(),
+            Self::FormationLeft => "formation_left".into(),
+            Self::FormationRight => "formation_right".into(),
+            Self::FormationUp => "formation_up".into(),
+            Self::FormationDown => "formation_down".into(),
+            Self::OverheadBars => "overhead_bars".into(),
+            Self::AssignmentMode => "assignment_mode".into(),
+            Self::TimePause => "time_pause".into(),
+            Self::TimeStep => "time_step".into(),
+            Self::TimeFaster => "time_faster".into(),
+            Self::TimeSlower => "time_slower".into(),
+            Self::Save => "save".into(),
+            Self::Load => "load".into(),
+            Self::Quit => "quit".into(),
+        }
+    }
+
+    /// Actions that are active for as long as the key is held
+    pub fn is_held(&self) -> bool {
+        match self {
+            Self::CameraLeft
+            | Self::CameraRight
+            | Self::CameraForward
+            | Self::CameraBack
+            | Self::CameraRotateLeft
+            | Self::CameraRotateRight => true,
+            _ => false,
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Binding -
+// -----------------------------------------------------------------------------
+/// A key and the modifiers that have to be held with it.
+/// Modifiers have to match exactly, so Ctrl + Z and Z are different bindings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Binding {
+    pub scancode: i64,
+    #[serde(default)]
+    pub control: bool,
+    #[serde(default)]
+    pub shift: bool,
+    #[serde(default)]
+    pub alt: bool,
+}
+
+impl Binding {
+    pub fn key(scancode: i64) -> Self {
+        Self {
+            scancode,
+            control: false,
+            shift: false,
+            alt: false,
+        }
+    }
+
+    pub fn ctrl(scancode: i64) -> Self {
+        Self {
+            control: true,
+            ..Self::key(scancode)
+        }
+    }
+
+    pub fn shift(scancode: i64) -> Self {
+        Self {
+            shift: true,
+            ..Self::key(scancode)
+        }
+    }
+
+    pub fn from_event(event: &InputEventKey) -> Self {
+        Self {
+            scancode: event.scancode(),
+            control: event.control(),
+            shift: event.shift(),
+            alt: event.alt(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ActionBinding {
+    pub action: Action,
+    pub binding: Binding,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RebindError {
+    /// The binding is already used by this action
+    Conflict(Action),
+}
+
+// -----------------------------------------------------------------------------
+//     - Resources -
+// -----------------------------------------------------------------------------
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ActionMap {
+    bindings: Vec<ActionBinding>,
+}
+
+impl Default for ActionMap {
+    fn default() -> Self {
+        let mut bindings = vec![
+            (Action::CameraLeft, Binding::key('A' as i64)),
+            (Action::CameraRight, Binding::key('D' as i64)),
+            (Action::CameraForward, Binding::key('W' as i64)),
+            (Action::CameraBack, Binding::key('S' as i64)),
+            (Action::CameraRotateLeft, Binding::key('Q' as i64)),
+            (Action::CameraRotateRight, Binding::key('E' as i64)),
+            (Action::CameraFollow, Binding::key('T' as i64)),
+            (Action::CameraFocus, Binding::key(KEY_SPACE)),
+            (Action::SelectAll, Binding::ctrl('A' as i64)),
+            (Action::March, Binding::key('M' as i64)),
+            (Action::SquadCreate, Binding::key('N' as i64)),
+            (Action::SquadSplit, Binding::key('B' as i64)),
+            (Action::SquadMerge, Binding::key('J' as i64)),
+            (Action::FormationUndo, Binding::ctrl('Z' as i64)),
+            (Action::FormationRedo, Binding::ctrl('Y' as i64)),
+            (Action::FormationLeft, Binding::key(KEY_LEFT)),
+            (Action::FormationRight, Binding::key(KEY_RIGHT)),
+            (Action::FormationUp, Binding::key(KEY_UP)),
+            (Action::FormationDown, Binding::key(KEY_DOWN)),
+            (Action::OverheadBars, Binding::key('H' as i64)),
+            (Action::AssignmentMode, Binding::key('G' as i64)),
+            (Action::TimePause, Binding::key(KEY_PAUSE)),
+            (Action::TimePause, Binding::ctrl('P' as i64)),
+            (Action::TimeStep, Binding::key('.' as i64)),
+            (Action::TimeFaster, Binding::key('=' as i64)),
+            (Action::TimeSlower, Binding::key('-' as i64)),
+            (Action::Save, Binding::key(KEY_F5)),
+            (Action::Load, Binding::key(KEY_F9)),
+            (Action::Quit, Binding::key(KEY_ESCAPE)),
+        ];
+
+        for i in 0..CONTROL_GROUP_COUNT {
+            let key = KEY_0 + 1 + i as i64;
+            bindings.push((Action::ControlGroupAssign(i), Binding::ctrl(key)));
+            bindings.push((Action::ControlGroupAdd(i), Binding::shift(key)));
+            bindings.push((Action::ControlGroupRecall(i), Binding::key(key)));
+        }
+
+        for i in 0..BOOKMARK_COUNT as u8 {
+            let key = KEY_F1 + i as i64;
+            bindings.push((Action::BookmarkStore(i), Binding::ctrl(key)));
+            bindings.push((Action::BookmarkRecall(i), Binding::key(key)));
+        }
+
+        let bindings = bindings
+            .into_iter()
+            .map(|(action, binding)| ActionBinding { action, binding })
+            .collect();
+
+        Self { bindings }
+    }
+}
+
+impl ActionMap {
+    /// Load the bindings from the config file.
+    /// Actions missing from the file keep their default binding.
+    pub fn load() -> Self {
+        let file = match File::open(file_path()) {
+            Ok(f) => f,
+            Err(_) => return Self::default(),
+        };
+
+        let loaded: Self = match serde_json::from_reader(&file) {
+            Ok(map) => map,
+            Err(e) => {
+                eprintln!("Could not read input bindings: {:?}", e);
+                return Self::default();
+            }
+        };
+
+        let map = loaded.with_defaults();
+        for (binding, actions) in map.conflicts() {
+            eprintln!("{:?} is bound to more than one action: {:?}", binding, actions);
+        }
+        map
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let mut file = File::create(file_path())?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        Ok(())
+    }
+
+    fn with_defaults(mut self) -> Self {
+        for default in Self::default().bindings {
+            if !self.bindings.iter().any(|b| b.action == default.action) {
+                self.bindings.push(default);
+            }
+        }
+        self
+    }
+
+    pub fn bindings(&self, action: Action) -> impl Iterator<Item = &Binding> {
+        self.bindings
+            .iter()
+            .filter(move |b| b.action == action)
+            .map(|b| &b.binding)
+    }
+
+    /// Actions triggered by pressing this key combination
+    pub fn pressed(&self, binding: Binding) -> Vec<Action> {
+        self.bindings
+            .iter()
+            .filter(|b| b.binding == binding)
+            .map(|b| b.action)
+            .collect()
+    }
+
+    /// Held actions stopped by releasing this key.
+    /// Modifiers are ignored, as they might have been
+    /// pressed or released while the key was held.
+    pub fn released(&self, scancode: i64) -> Vec<Action> {
+        self.bindings
+            .iter()
+            .filter(|b| b.binding.scancode == scancode && b.action.is_held())
+            .map(|b| b.action)
+            .collect()
+    }
+
+    /// Bindings used by more than one action
+    pub fn conflicts(&self) -> Vec<(Binding, Vec<Action>)> {
+        let mut conflicts: Vec<(Binding, Vec<Action>)> = Vec::new();
+
+        for b in &self.bindings {
+            let actions = self.pressed(b.binding);
+            let seen = conflicts.iter().any(|(binding, _)| *binding == b.binding);
+            if actions.len() > 1 && !seen {
+                conflicts.push((b.binding, actions));
+            }
+        }
+
+        conflicts
+    }
+
+    /// Replace all bindings of the action with this one.
+    /// Fails if another action already uses the binding.
+    pub fn rebind(&mut self, action: Action, binding: Binding) -> std::result::Result<(), RebindError> {
+        let conflict = self
+            .bindings
+            .iter()
+            .find(|b| b.binding == binding && b.action != action);
+
+        if let Some(b) = conflict {
+            return Err(RebindError::Conflict(b.action));
+        }
+
+        self.bindings.retain(|b| b.action != action);
+        self.bindings.push(ActionBinding { action, binding });
+        Ok(())
+    }
+
+    pub fn action_by_name(&self, name: &str) -> Option<Action> {
+        self.bindings
+            .iter()
+            .map(|b| b.action)
+            .find(|action| action.name() == name)
+    }
+
+    /// Register the actions with Godot, so `is_action_pressed` and friends
+    /// see the same bindings
+    pub fn register(&self) {
+        let input_map = InputMap::godot_singleton();
+
+        for b in &self.bindings {
+            let name = b.action.name();
+            if input_map.has_action(name.as_str().into()) {
+                input_map.action_erase_events(name.as_str().into());
+            }
+        }
+
+        for b in &self.bindings {
+            let name = b.action.name();
+            if !input_map.has_action(name.as_str().into()) {
+                input_map.add_action(name.as_str().into(), 0.5);
+            }
+
+            let event = InputEventKey::new();
+            event.set_scancode(b.binding.scancode);
+            event.set_control(b.binding.control);
+            event.set_shift(b.binding.shift);
+            event.set_alt(b.binding.alt);
+            input_map.action_add_event(name.as_str().into(), Some(event.upcast()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn modifiers_have_to_match() {
+        let map = ActionMap::default();
+        assert_eq!(map.pressed(Binding::key('Z' as i64)), vec![]);
+        assert_eq!(map.pressed(Binding::ctrl('Z' as i64)), vec![Action::FormationUndo]);
+        assert_eq!(map.pressed(Binding::key('A' as i64)), vec![Action::CameraLeft]);
+        assert_eq!(map.pressed(Binding::ctrl('A' as i64)), vec![Action::SelectAll]);
+    }
+
+    #[test]
+    fn release_ignores_modifiers() {
+        let map = ActionMap::default();
+        assert_eq!(map.released('A' as i64), vec![Action::CameraLeft]);
+        // Only held actions are released
+        assert_eq!(map.released('M' as i64), vec![]);
+    }
+
+    #[test]
+    fn defaults_have_no_conflicts() {
+        assert!(ActionMap::default().conflicts().is_empty());
+    }
+
+    #[test]
+    fn defaults_cover_every_bookmark() {
+        let map = ActionMap::default();
+        for i in 0..BOOKMARK_COUNT as u8 {
+            let key = KEY_F1 + i as i64;
+            let recall = map.pressed(Binding::key(key));
+            assert_eq!(recall, vec![Action::BookmarkRecall(i)]);
+            let store = map.pressed(Binding::ctrl(key));
+            assert_eq!(store, vec![Action::BookmarkStore(i)]);
+        }
+    }
+
+    #[test]
+    fn rebind_detects_conflicts() {
+        let mut map = ActionMap::default();
+        let err = map.rebind(Action::March, Binding::key('W' as i64));
+        assert_eq!(err, Err(RebindError::Conflict(Action::CameraForward)));
+
+        assert!(map.rebind(Action::March, Binding::shift('M' as i64)).is_ok());
+        assert_eq!(map.pressed(Binding::key('M' as i64)), vec![]);
+        assert_eq!(map.pressed(Binding::shift('M' as i64)), vec![Action::March]);
+
+        // Rebinding to its own binding is fine
+        assert!(map.rebind(Action::March, Binding::shift('M' as i64)).is_ok());
+    }
+
+    #[test]
+    fn missing_actions_get_defaults() {
+        let json = r#"{ "bindings": [
+            { "action": "March", "binding": { "scancode": 80 } },
+            { "action": { "ControlGroupRecall": 0 }, "binding": { "scancode": 48, "shift": true } }
+        ] }"#;
+
+        let map = serde_json::from_str::<ActionMap>(json).unwrap().with_defaults();
+        assert_eq!(map.pressed(Binding::key('P' as i64)), vec![Action::March]);
+        assert_eq!(map.pressed(Binding::key('M' as i64)), vec![]);
+        assert_eq!(map.pressed(Binding::shift('0' as i64)), vec![Action::ControlGroupRecall(0)]);
+        assert_eq!(map.pressed(Binding::key('W' as i64)), vec![Action::CameraForward]);
+        assert!(map.conflicts().is_empty());
+    }
+}
@@ -0,0 +1,320 @@
+// Assign units to formation slots.
+//
+// Costs are given as a square matrix where `cost[unit][slot]` is the
+// distance from a unit to a slot.
+
+use serde::{Deserialize, Serialize};
+
+// -----------------------------------------------------------------------------
+//     - Resources -
+// -----------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum AssignmentMode {
+    /// Every unit goes to the slot of its own `FormationPos`
+    Strict,
+    /// Minimise the sum of all travel distances
+    MinTotal,
+    /// Minimise the longest travel distance, then the sum
+    MinMax,
+}
+
+impl AssignmentMode {
+    /// Cycle through the options
+    pub fn next(self) -> Self {
+        match self {
+            Self::Strict => Self::MinTotal,
+            Self::MinTotal => Self::MinMax,
+            Self::MinMax => Self::Strict,
+        }
+    }
+
+    /// Returns the slot index for each unit, or `None` for `Strict`
+    pub fn solve(&self, cost: &[Vec<f32>]) -> Option<Vec<usize>> {
+        match self {
+            Self::Strict => None,
+            Self::MinTotal => Some(min_total(cost)),
+            Self::MinMax => Some(min_max(cost)),
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Solvers -
+// -----------------------------------------------------------------------------
+
+/// Hungarian algorithm (Kuhn-Munkres with potentials), O(n^3).
+/// Returns the slot for each unit so the total cost is minimal.
+pub fn min_total(cost: &[Vec<f32>]) -> Vec<usize> {
+    let n = cost.len();
+    if n == 0 {
+        return Vec::new();
+    }
+
+    // 1-based, index 0 is a virtual unit / slot
+    let mut u = vec![0f32; n + 1];
+    let mut v = vec![0f32; n + 1];
+    let mut slot_owner = vec![0usize; n + 1];
+    let mut way = vec![0usize; n + 1];
+
+    for unit in 1..=n {
+        slot_owner[0] = unit;
+        let mut slot0 = 0;
+        let mut min_v = vec![std::f32::INFINITY; n + 1];
+        let mut used = vec![false; n + 1];
+
+        loop {
+            used[slot0] = true;
+            let unit0 = slot_owner[slot0];
+            let mut delta = std::f32::INFINITY;
+            let mut slot1 = 0;
+
+            for slot in 1..=n {
+                if used[slot] {
+                    continue;
+                }
+
+                let cur = cost[unit0 - 1][slot - 1] - u[unit0] - v[slot];
+                if cur < min_v[slot] {
+                    min_v[slot] = cur;
+                    way[slot] = slot0;
+                }
+
+                if min_v[slot] < delta {
+                    delta = min_v[slot];
+                    slot1 = slot;
+                }
+            }
+
+            for slot in 0..=n {
+                if used[slot] {
+                    u[slot_owner[slot]] += delta;
+                    v[slot] -= delta;
+                } else {
+                    min_v[slot] -= delta;
+                }
+            }
+
+            slot0 = slot1;
+            if slot_owner[slot0] == 0 {
+                break;
+            }
+        }
+
+        // Walk back along the augmenting path
+        loop {
+            let slot1 = way[slot0];
+            slot_owner[slot0] = slot_owner[slot1];
+            slot0 = slot1;
+            if slot0 == 0 {
+                break;
+            }
+        }
+    }
+
+    let mut assignment = vec![0; n];
+    for slot in 1..=n {
+        assignment[slot_owner[slot] - 1] = slot - 1;
+    }
+
+    assignment
+}
+
+/// Bottleneck assignment: find the smallest possible longest distance,
+/// then minimise the total distance among the assignments that respect it.
+pub fn min_max(cost: &[Vec<f32>]) -> Vec<usize> {
+    let n = cost.len();
+    if n == 0 {
+        return Vec::new();
+    }
+
+    let mut thresholds = cost.iter().flatten().cloned().collect::<Vec<_>>();
+    thresholds.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    thresholds.dedup();
+
+    // Binary search the smallest threshold that still has a perfect matching
+    let (mut low, mut high) = (0, thresholds.len() - 1);
+    while low < high {
+        let mid = (low + high) / 2;
+        if has_perfect_matching(cost, thresholds[mid]) {
+            high = mid;
+        } else {
+            low = mid + 1;
+        }
+    }
+    let threshold = thresholds[low];
+
+    // Anything above the threshold is made too expensive to ever be picked
+    let penalty = thresholds[thresholds.len() - 1] * n as f32 + 1.;
+    let capped = cost
+        .iter()
+        .map(|row| {
+            row.iter()
+                .map(|c| if *c > threshold { *c + penalty } else { *c })
+                .collect()
+        })
+        .collect::<Vec<Vec<f32>>>();
+
+    min_total(&capped)
+}
+
+fn has_perfect_matching(cost: &[Vec<f32>], threshold: f32) -> bool {
+    let n = cost.len();
+    let mut slot_owner: Vec<Option<usize>> = vec![None; n];
+
+    for unit in 0..n {
+        let mut visited = vec![false; n];
+        if !augment(cost, threshold, unit, &mut visited, &mut slot_owner) {
+            return false;
+        }
+    }
+
+    true
+}
+
+fn augment(
+    cost: &[Vec<f32>],
+    threshold: f32,
+    unit: usize,
+    visited: &mut [bool],
+    slot_owner: &mut [Option<usize>],
+) -> bool {
+    for slot in 0..cost.len() {
+        if cost[unit][slot] > threshold || visited[slot] {
+            continue;
+        }
+        visited[slot] = true;
+
+        let free = match slot_owner[slot] {
+            None => true,
+            Some(owner) => augment(cost, threshold, owner, visited, slot_owner),
+        };
+
+        if free {
+            slot_owner[slot] = Some(unit);
+            return true;
+        }
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn total(cost: &[Vec<f32>], assignment: &[usize]) -> f32 {
+        assignment.iter().enumerate().map(|(unit, slot)| cost[unit][*slot]).sum()
+    }
+
+    fn longest(cost: &[Vec<f32>], assignment: &[usize]) -> f32 {
+        assignment
+            .iter()
+            .enumerate()
+            .map(|(unit, slot)| cost[unit][*slot])
+            .fold(0., f32::max)
+    }
+
+    fn is_permutation(assignment: &[usize]) -> bool {
+        let mut seen = vec![false; assignment.len()];
+        for slot in assignment {
+            if seen[*slot] {
+                return false;
+            }
+            seen[*slot] = true;
+        }
+        true
+    }
+
+    #[test]
+    fn test_empty() {
+        assert_eq!(min_total(&[]), Vec::<usize>::new());
+        assert_eq!(min_max(&[]), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn test_min_total() {
+        let cost = vec![
+            vec![4., 1., 3.],
+            vec![2., 0., 5.],
+            vec![3., 2., 2.],
+        ];
+
+        let assignment = min_total(&cost);
+        assert!(is_permutation(&assignment));
+        assert_eq!(total(&cost, &assignment), 5.);
+        assert_eq!(assignment, vec![1, 0, 2]);
+    }
+
+    #[test]
+    fn test_min_total_uncrosses_paths() {
+        // Two units on a line, approaching their slots from the "wrong" side.
+        // Strict identity would make them cross.
+        let units = [0f32, 10.];
+        let slots = [12f32, 2.];
+        let cost = units
+            .iter()
+            .map(|u| slots.iter().map(|s| (u - s).abs()).collect())
+            .collect::<Vec<Vec<f32>>>();
+
+        assert_eq!(min_total(&cost), vec![1, 0]);
+    }
+
+    #[test]
+    fn test_min_max() {
+        // Min total picks 1 + 1 + 10 = 12, min max picks 5 + 5 + 5 = 15
+        let cost = vec![
+            vec![1., 5., 20.],
+            vec![20., 1., 5.],
+            vec![5., 20., 10.],
+        ];
+
+        let total_assignment = min_total(&cost);
+        assert_eq!(longest(&cost, &total_assignment), 10.);
+
+        let assignment = min_max(&cost);
+        assert!(is_permutation(&assignment));
+        assert_eq!(longest(&cost, &assignment), 5.);
+        assert_eq!(assignment, vec![1, 2, 0]);
+    }
+
+    #[test]
+    fn test_min_total_matches_brute_force() {
+        let cost = (0..5)
+            .map(|i| (0..5).map(|j| ((i * 7 + j * 13) % 11) as f32).collect())
+            .collect::<Vec<Vec<f32>>>();
+
+        let mut best = std::f32::INFINITY;
+        let mut perm = (0..5).collect::<Vec<usize>>();
+        permutations(&mut perm, 0, &mut |p| best = best.min(total(&cost, p)));
+
+        let assignment = min_total(&cost);
+        assert!(is_permutation(&assignment));
+        assert_eq!(total(&cost, &assignment), best);
+    }
+
+    fn permutations(perm: &mut Vec<usize>, k: usize, f: &mut dyn FnMut(&[usize])) {
+        if k == perm.len() {
+            f(perm);
+            return;
+        }
+
+        for i in k..perm.len() {
+            perm.swap(k, i);
+            permutations(perm, k + 1, f);
+            perm.swap(k, i);
+        }
+    }
+
+    #[test]
+    fn test_strict() {
+        assert_eq!(AssignmentMode::Strict.solve(&[vec![1.]]), None);
+    }
+
+    #[test]
+    fn test_next_cycles_all_modes() {
+        let mode = AssignmentMode::Strict;
+        assert_eq!(mode.next(), AssignmentMode::MinTotal);
+        assert_eq!(mode.next().next(), AssignmentMode::MinMax);
+        assert_eq!(mode.next().next().next(), mode);
+    }
+}
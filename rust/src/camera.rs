@@ -1,43 +1,322 @@
-use gdnative::api::{Area, Camera as GodotCamera, MeshInstance, PhysicsServer};
-use gdnative::{Dictionary, Variant, VariantArray, Vector2, Vector3, Ptr};
+use gdnative::api::{Area, Camera as GodotCamera, PhysicsServer};
+use gdnative::{Dictionary, Ptr, Rect2, Variant, VariantArray, Vector2, Vector3};
 use legion::prelude::*;
+use serde::{Deserialize, Serialize};
 use legion::systems::schedule::Builder;
 
 use crate::gameworld::{ClickIndicator, Delta};
-use crate::input::{Keyboard, Keys, MouseButton, MousePos, RMB};
+use crate::movement::Pos;
+use crate::player::Selected;
+use crate::tilemap::{Chunks, TileMap};
+use crate::gesture::{GestureKind, Gestures};
+use crate::input::{Keyboard, Keys, Layer, MousePos, RMB};
+use crate::settings::Settings;
 
 pub const RAY_LENGTH: f32 = 1000.;
 const CAMERA_SPEED: f32 = 80.;
+const ROTATE_SPEED: f32 = 2.;
+// Radians per pixel when orbiting with the middle mouse button
+const ORBIT_SENSITIVITY: f32 = 0.005;
+// Each step of the mouse wheel changes the distance by this fraction
+const ZOOM_STEP: f32 = 0.1;
+const MIN_DISTANCE: f32 = 10.;
+const MAX_DISTANCE: f32 = 120.;
+const MIN_PITCH: f32 = 0.35;
+const MAX_PITCH: f32 = 1.45;
+// Higher is snappier
+const SMOOTHING: f32 = 10.;
+// How far past the edge of the map the focus can go
+const BOUNDS_MARGIN: f32 = 4.;
+/// One for each of F1 to F4, the F-keys after those are taken
+pub const BOOKMARK_COUNT: usize = 4;
 
 // -----------------------------------------------------------------------------
 //     - Resources -
 // -----------------------------------------------------------------------------
+/// The box selection on screen, shown by `SelectionRect`
+pub struct SelectionBox(pub Option<Rect2>);
+
+/// Centre the camera on this point
+pub struct CameraFocus(pub Option<Vector3>);
+
+/// The area the camera focus is kept inside.
+/// Since this applies to the focus point and not the camera itself,
+/// zooming and rotating the camera doesn't change what's reachable.
+pub struct CameraBounds {
+    rect: Option<Rect2>,
+    dirty: bool,
+}
+
+impl CameraBounds {
+    pub fn new() -> Self {
+        Self {
+            rect: None,
+            dirty: true,
+        }
+    }
+
+    /// Recalculate the bounds from the tile map on the next frame
+    pub fn invalidate(&mut self) {
+        self.dirty = true;
+    }
+}
+
+/// Keep the point inside the rect (on the x / z plane)
+pub fn clamp_focus(focus: Vector3, bounds: Rect2) -> Vector3 {
+    let min = bounds.min();
+    let max = bounds.max();
+    Vector3::new(
+        focus.x.max(min.x).min(max.x),
+        focus.y,
+        focus.z.max(min.y).min(max.y),
+    )
+}
+
+/// What the middle mouse button is doing
 #[derive(Debug, Clone, Copy)]
-pub enum Drag {
-    Empty,
-    Start(Vector3),
+enum MiddleDrag {
+    Released,
+    /// Last mouse position
+    Orbit(Vector2),
+    /// The point on the ground that stays under the mouse
+    Grab(Vector3),
 }
 
-pub struct SelectionBox(pub Ptr<MeshInstance>);
+/// Move the camera to the selected units
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SelectionFocus {
+    Off,
+    /// Fly to the selection, then stop
+    Once,
+    /// Keep the selection in the middle of the screen until the camera is panned
+    Follow,
+}
 
-unsafe impl Send for SelectionBox {}
-unsafe impl Sync for SelectionBox {}
+impl SelectionFocus {
+    pub fn toggle_follow(&mut self) {
+        *self = match self {
+            Self::Follow => Self::Off,
+            _ => Self::Follow,
+        };
+    }
 
-pub struct UnitSelectionArea(pub Ptr<Area>);
+    pub fn focus(&mut self) {
+        if *self != Self::Follow {
+            *self = Self::Once;
+        }
+    }
+}
 
-unsafe impl Send for UnitSelectionArea {}
-unsafe impl Sync for UnitSelectionArea {}
+pub enum BookmarkCommand {
+    /// Ctrl + F-key
+    Store(usize),
+    /// F-key
+    Recall(usize),
+}
+
+pub struct CameraBookmarks {
+    bookmarks: Vec<Option<RigState>>,
+    commands: Vec<BookmarkCommand>,
+}
 
-impl Drag {
-    pub fn set_start(&mut self, pos: Vector3) {
-        *self = Self::Start(pos);
+impl CameraBookmarks {
+    pub fn new() -> Self {
+        Self {
+            bookmarks: vec![None; BOOKMARK_COUNT],
+            commands: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, command: BookmarkCommand) {
+        self.commands.push(command);
+    }
+
+    pub fn to_save_data(&self) -> Vec<Option<RigState>> {
+        self.bookmarks.clone()
+    }
+
+    pub fn restore(&mut self, saved: &[Option<RigState>]) {
+        for (bookmark, saved) in self.bookmarks.iter_mut().zip(saved) {
+            *bookmark = *saved;
+        }
     }
+}
 
-    pub fn clear(&mut self) {
-        *self = Self::Empty;
+/// Camera input that isn't held in `Keyboard`
+pub struct CameraInput {
+    /// Mouse wheel steps since the last frame, positive zooms in
+    pub zoom: f32,
+    /// Middle mouse button held, and whether shift was held when it was pressed
+    pub middle: Option<bool>,
+    /// Analog pan, from the gamepad
+    pub pan: Vector2,
+    drag: MiddleDrag,
+}
+
+impl CameraInput {
+    pub fn new() -> Self {
+        Self {
+            zoom: 0.,
+            middle: None,
+            pan: Vector2::zero(),
+            drag: MiddleDrag::Released,
+        }
     }
 }
 
+/// Where the camera is looking and from how far away
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RigState {
+    /// The point on the ground the camera orbits
+    pub focus: Vector3,
+    pub yaw: f32,
+    pub pitch: f32,
+    pub distance: f32,
+}
+
+impl RigState {
+    fn offset(&self) -> Vector3 {
+        let (sin_yaw, cos_yaw) = self.yaw.sin_cos();
+        let (sin_pitch, cos_pitch) = self.pitch.sin_cos();
+        Vector3::new(sin_yaw * cos_pitch, sin_pitch, cos_yaw * cos_pitch) * self.distance
+    }
+
+    pub fn position(&self) -> Vector3 {
+        self.focus + self.offset()
+    }
+
+    /// Forward and right along the ground, relative to the yaw
+    pub fn ground_axes(&self) -> (Vector3, Vector3) {
+        let (sin_yaw, cos_yaw) = self.yaw.sin_cos();
+        let forward = Vector3::new(-sin_yaw, 0., -cos_yaw);
+        let right = Vector3::new(cos_yaw, 0., -sin_yaw);
+        (forward, right)
+    }
+
+    fn clamped(mut self) -> Self {
+        self.pitch = self.pitch.max(MIN_PITCH).min(MAX_PITCH);
+        self.distance = self.distance.max(MIN_DISTANCE).min(MAX_DISTANCE);
+        self
+    }
+
+    fn lerp(&self, to: &Self, t: f32) -> Self {
+        Self {
+            focus: self.focus + (to.focus - self.focus) * t,
+            yaw: self.yaw + (to.yaw - self.yaw) * t,
+            pitch: self.pitch + (to.pitch - self.pitch) * t,
+            distance: self.distance + (to.distance - self.distance) * t,
+        }
+    }
+}
+
+/// Input moves the target, and the camera eases toward it
+pub struct CameraRig {
+    pub current: RigState,
+    pub target: RigState,
+    // Orthographic size per unit of distance, so zoom works for both projections
+    ortho_scale: f32,
+    // Moved by the player since this was last checked
+    panned: bool,
+}
+
+impl CameraRig {
+    pub fn new(state: RigState, ortho_scale: f32) -> Self {
+        let state = state.clamped();
+        Self {
+            current: state,
+            target: state,
+            ortho_scale,
+            panned: false,
+        }
+    }
+
+    /// Pick up wherever the camera was placed in the scene
+    pub fn from_camera(camera: &Camera) -> Option<Self> {
+        let focus = camera.ground_focus()?;
+        let godot_camera = unsafe { camera.0.assume_safe() };
+        let offset = godot_camera.translation() - focus;
+        let distance = offset.length();
+        if distance < std::f32::EPSILON {
+            return None;
+        }
+
+        let state = RigState {
+            focus,
+            yaw: offset.x.atan2(offset.z),
+            pitch: (offset.y / distance).asin(),
+            distance,
+        };
+
+        let ortho_scale = godot_camera.size() as f32 / distance;
+        Some(Self::new(state, ortho_scale))
+    }
+
+    /// `x` is right and `y` is forward, relative to where the camera is facing
+    pub fn pan(&mut self, dir: Vector2) {
+        if dir == Vector2::zero() {
+            return;
+        }
+
+        let (forward, right) = self.target.ground_axes();
+        self.target.focus += right * dir.x + forward * dir.y;
+        self.panned = true;
+    }
+
+    pub fn take_panned(&mut self) -> bool {
+        std::mem::replace(&mut self.panned, false)
+    }
+
+    /// Fly to a stored camera position
+    pub fn fly_to(&mut self, state: RigState) {
+        self.target = state.clamped();
+    }
+
+    pub fn zoom(&mut self, steps: f32) {
+        self.target.distance *= (1. - ZOOM_STEP).powf(steps);
+        self.target = self.target.clamped();
+    }
+
+    pub fn orbit(&mut self, yaw: f32, pitch: f32) {
+        self.target.yaw += yaw;
+        self.target.pitch += pitch;
+        self.target = self.target.clamped();
+    }
+
+    pub fn focus_on(&mut self, point: Vector3) {
+        self.target.focus = Vector3::new(point.x, 0., point.z);
+    }
+
+    /// Only the target is clamped, so the camera eases back
+    /// instead of stopping dead at the edge
+    pub fn clamp_to(&mut self, bounds: Rect2) {
+        self.target.focus = clamp_focus(self.target.focus, bounds);
+    }
+
+    /// Ease the camera toward the target
+    pub fn update(&mut self, delta: f32) {
+        let t = 1. - (-SMOOTHING * delta).exp();
+        self.current = self.current.lerp(&self.target, t);
+    }
+
+    pub fn apply(&self, camera: &Camera) {
+        let camera = unsafe { camera.0.assume_safe() };
+        camera.look_at_from_position(
+            self.current.position(),
+            self.current.focus,
+            Vector3::new(0., 1., 0.),
+        );
+
+        if camera.projection() == GodotCamera::PROJECTION_ORTHOGONAL {
+            camera.set_size((self.current.distance * self.ortho_scale) as f64);
+        }
+    }
+}
+
+pub struct UnitSelectionArea(pub Ptr<Area>);
+
+unsafe impl Send for UnitSelectionArea {}
+unsafe impl Sync for UnitSelectionArea {}
+
 pub struct Camera(pub Ptr<GodotCamera>);
 
 unsafe impl Send for Camera {}
@@ -60,6 +339,57 @@ impl Camera {
         Some(pos)
     }
 
+    /// The instance id of whatever is under the mouse
+    pub fn collider_id(&self, mouse_pos: Vector2, ray_length: f32, col_mask: i64) -> Option<i64> {
+        let dict = self.object_from_camera(mouse_pos, ray_length, col_mask);
+        dict.get(&"collider_id".into()).try_to_i64()
+    }
+
+    pub fn viewport_size(&self) -> Option<Vector2> {
+        let camera = unsafe { self.0.assume_safe_during(self) };
+        let viewport = unsafe { camera.get_viewport()?.assume_safe() };
+        Some(viewport.size())
+    }
+
+    /// Where `pos` is on the screen, `None` if it's behind the camera
+    pub fn screen_position(&self, pos: Vector3) -> Option<Vector2> {
+        let camera = unsafe { self.0.assume_safe_during(self) };
+        if camera.is_position_behind(pos) {
+            return None;
+        }
+
+        Some(camera.unproject_position(pos))
+    }
+
+    pub fn is_on_screen(&self, pos: Vector3) -> bool {
+        let camera = unsafe { self.0.assume_safe_during(self) };
+        let viewport = match camera.get_viewport() {
+            Some(v) => unsafe { v.assume_safe() },
+            None => return false,
+        };
+
+        match self.screen_position(pos) {
+            Some(screen_pos) => viewport.get_visible_rect().contains(screen_pos.to_point()),
+            None => false,
+        }
+    }
+
+    /// The point on the ground (y = 0) in the middle of the screen
+    pub fn ground_focus(&self) -> Option<Vector3> {
+        let camera = unsafe { self.0.assume_safe_during(self) };
+        let viewport = unsafe { camera.get_viewport()?.assume_safe() };
+        let centre = viewport.size() / 2.;
+
+        let from = camera.project_ray_origin(centre);
+        let normal = camera.project_ray_normal(centre);
+        if normal.y.abs() < std::f32::EPSILON {
+            return None;
+        }
+
+        let t = -from.y / normal.y;
+        Some(from + normal * t)
+    }
+
     pub fn object_from_camera(
         &self,
         mouse_pos: Vector2,
@@ -98,43 +428,203 @@ impl Camera {
 fn move_camera() -> Box<dyn Runnable> {
     SystemBuilder::new("move camera")
         .read_resource::<Keyboard>()
+        .read_resource::<MousePos>()
+        .read_resource::<Delta>()
+        .read_resource::<Settings>()
+        .read_resource::<CameraBounds>()
+        .write_resource::<CameraInput>()
+        .write_resource::<CameraRig>()
         .write_resource::<Camera>()
         .write_resource::<UnitSelectionArea>()
-        .read_resource::<Delta>()
         .build_thread_local(|_, _, res, _| {
-            let (keyboard, camera, unit_sel_area, delta) = res;
+            let (keyboard, mouse_pos, delta, settings, bounds, input, rig, camera, unit_sel_area) =
+                res;
+            let settings = &settings.camera;
+            let keys = keyboard.keys();
+            let mouse_pos = mouse_pos.global();
+
+            // Pan
+            let mut dir = Vector2::zero();
+
+            if keys & Keys::LEFT == Keys::LEFT {
+                dir.x -= 1.0;
+            }
+
+            if keys & Keys::RIGHT == Keys::RIGHT {
+                dir.x += 1.0;
+            }
+
+            if keys & Keys::UP == Keys::UP {
+                dir.y += 1.0;
+            }
+
+            if keys & Keys::DOWN == Keys::DOWN {
+                dir.y -= 1.0;
+            }
+
+            rig.pan((dir + input.pan) * CAMERA_SPEED * delta.0);
+
+            // Edge scroll
+            if let (true, Some(size)) = (settings.edge_scroll, camera.viewport_size()) {
+                let margin = settings.edge_margin;
+                let mut dir = Vector2::zero();
+
+                if mouse_pos.x <= margin {
+                    dir.x -= 1.0;
+                } else if mouse_pos.x >= size.x - margin {
+                    dir.x += 1.0;
+                }
+
+                if mouse_pos.y <= margin {
+                    dir.y += 1.0;
+                } else if mouse_pos.y >= size.y - margin {
+                    dir.y -= 1.0;
+                }
+
+                rig.pan(dir * settings.edge_speed * delta.0);
+            }
+
+            // Rotate
+            let mut yaw = 0.;
+
+            if keys & Keys::ROTATE_LEFT == Keys::ROTATE_LEFT {
+                yaw -= ROTATE_SPEED * delta.0;
+            }
+
+            if keys & Keys::ROTATE_RIGHT == Keys::ROTATE_RIGHT {
+                yaw += ROTATE_SPEED * delta.0;
+            }
+
+            let mut pitch = 0.;
+
+            // Middle mouse button
+            let ground = match input.middle {
+                Some(_) => camera.pos_from_camera(mouse_pos, RAY_LENGTH, 2),
+                None => None,
+            };
+            input.drag = match (input.middle, input.drag) {
+                (None, _) => MiddleDrag::Released,
+                (Some(shift), MiddleDrag::Released) => match (settings.grab_pan && !shift, ground) {
+                    (true, Some(ground)) => MiddleDrag::Grab(ground),
+                    (true, None) => MiddleDrag::Released,
+                    (false, _) => MiddleDrag::Orbit(mouse_pos),
+                },
+                (Some(_), MiddleDrag::Orbit(last)) => {
+                    let moved = mouse_pos - last;
+                    yaw -= moved.x * ORBIT_SENSITIVITY;
+                    pitch += moved.y * ORBIT_SENSITIVITY;
+                    MiddleDrag::Orbit(mouse_pos)
+                }
+                (Some(_), MiddleDrag::Grab(anchor)) => {
+                    // Move the camera right away rather than easing,
+                    // otherwise the ground slides under the mouse
+                    if let Some(ground) = ground {
+                        let mut offset = anchor - ground;
+                        offset.y = 0.;
+                        rig.current.focus += offset;
+                        rig.target.focus += offset;
+                        rig.panned = true;
+                    }
+                    MiddleDrag::Grab(anchor)
+                }
+            };
+
+            rig.orbit(yaw, pitch);
+
+            // Zoom
+            rig.zoom(input.zoom);
+            input.zoom = 0.;
+
+            if let Some(rect) = bounds.rect {
+                rig.clamp_to(rect.inflate(BOUNDS_MARGIN, BOUNDS_MARGIN));
+            }
+
+            rig.update(delta.0);
+            rig.apply(camera);
+
             let unit_sel_area = unsafe { unit_sel_area.0.assume_safe() };
-            let camera = unsafe { camera.0.assume_safe() };
+            unit_sel_area.set_translation(rig.current.focus);
+        })
+}
 
-            if keyboard.keys() == Keys::EMPTY {
-                return;
+fn focus_selection() -> Box<dyn Runnable> {
+    SystemBuilder::new("focus selection")
+        .write_resource::<SelectionFocus>()
+        .write_resource::<CameraRig>()
+        .with_query(<Read<Pos>>::query().filter(tag::<Selected>()))
+        .build_thread_local(|_, world, (selection_focus, rig), selected| {
+            if rig.take_panned() && **selection_focus == SelectionFocus::Follow {
+                **selection_focus = SelectionFocus::Off;
             }
 
-            let mut translation = Vector3::zero();
+            if **selection_focus == SelectionFocus::Off {
+                return;
+            }
 
-            if keyboard.keys() & Keys::LEFT == Keys::LEFT {
-                translation.x += -1.0;
+            let positions = selected.iter(world).map(|pos| pos.0).collect::<Vec<_>>();
+            if positions.is_empty() {
+                return;
             }
 
-            if keyboard.keys() & Keys::RIGHT == Keys::RIGHT {
-                translation.x += 1.0;
+            let sum = positions.iter().fold(Vector3::zero(), |acc, p| acc + *p);
+            rig.focus_on(sum / positions.len() as f32);
+
+            if **selection_focus == SelectionFocus::Once {
+                **selection_focus = SelectionFocus::Off;
             }
+        })
+}
 
-            if keyboard.keys() & Keys::UP == Keys::UP {
-                translation.z += -1.0;
+fn camera_bookmarks() -> Box<dyn Runnable> {
+    SystemBuilder::new("camera bookmarks")
+        .write_resource::<CameraBookmarks>()
+        .write_resource::<CameraRig>()
+        .build_thread_local(|_, _, (bookmarks, rig), _| {
+            let commands = bookmarks.commands.drain(..).collect::<Vec<_>>();
+            // Bindings loaded from an older file can point past the last bookmark
+            for command in commands {
+                match command {
+                    BookmarkCommand::Store(index) => {
+                        if let Some(bookmark) = bookmarks.bookmarks.get_mut(index) {
+                            *bookmark = Some(rig.target);
+                        }
+                    }
+                    BookmarkCommand::Recall(index) => {
+                        if let Some(Some(state)) = bookmarks.bookmarks.get(index) {
+                            rig.fly_to(*state);
+                        }
+                    }
+                }
             }
+        })
+}
 
-            if keyboard.keys() & Keys::DOWN == Keys::DOWN {
-                translation.z += 1.0;
+fn update_camera_bounds() -> Box<dyn Runnable> {
+    SystemBuilder::new("update camera bounds")
+        .read_resource::<TileMap>()
+        .read_resource::<Chunks>()
+        .write_resource::<CameraBounds>()
+        .build_thread_local(|_, _, (tilemap, chunks, bounds), _| {
+            if !bounds.dirty {
+                return;
             }
 
-            unsafe {
-                let current_translation = camera.translation();
-                translation *= CAMERA_SPEED * delta.0;
-                camera.set_translation(current_translation + translation);
-                let mut camera_x_z = camera.translation();
-                camera_x_z.y = 0.;
-                unit_sel_area.set_translation(camera_x_z);
+            // While streaming it's the whole map, not just the chunks that are loaded
+            bounds.rect = match chunks.is_streaming() {
+                true => Some(chunks.bounds(tilemap.cell_size())),
+                false => tilemap.used_bounds(),
+            };
+            bounds.dirty = false;
+        })
+}
+
+fn focus_camera() -> Box<dyn Runnable> {
+    SystemBuilder::new("focus camera")
+        .write_resource::<CameraFocus>()
+        .write_resource::<CameraRig>()
+        .build_thread_local(|_, _, (camera_focus, rig), _| {
+            if let Some(target) = camera_focus.0.take() {
+                rig.focus_on(target);
             }
         })
 }
@@ -143,16 +633,24 @@ fn set_click_indicator() -> Box<dyn Runnable> {
     SystemBuilder::new("set click indicator")
         .read_resource::<Camera>()
         .write_resource::<ClickIndicator>()
-        .read_resource::<MouseButton>()
-        .read_resource::<MousePos>()
+        .read_resource::<Gestures>()
         .build_thread_local(|_cmd, _world, resources, _query| {
-            let (camera, click_indicator, mouse_btn, mouse_pos) = resources;
+            let (camera, click_indicator, gestures) = resources;
             let click_indicator = unsafe { click_indicator.0.assume_safe() };
-            if !mouse_btn.button_pressed(RMB) {
-                return;
-            }
+            let mouse_pos = gestures
+                .iter(Layer::World)
+                .filter(|g| g.button == RMB)
+                .find_map(|g| match g.kind {
+                    GestureKind::Click | GestureKind::DoubleClick => Some(g.pos),
+                    GestureKind::DragStart => Some(g.start),
+                    _ => None,
+                });
+            let mouse_pos = match mouse_pos {
+                Some(p) => p,
+                None => return,
+            };
 
-            let dest_pos = match camera.pos_from_camera(mouse_pos.global(), RAY_LENGTH, 2) {
+            let dest_pos = match camera.pos_from_camera(mouse_pos, RAY_LENGTH, 2) {
                 Some(p) => p,
                 None => return,
             };
@@ -163,6 +661,80 @@ fn set_click_indicator() -> Box<dyn Runnable> {
 
 pub fn camera_systems(builder: Builder) -> Builder {
     builder
+        .add_thread_local(update_camera_bounds())
+        .add_thread_local(focus_camera())
+        .add_thread_local(focus_selection())
+        .add_thread_local(camera_bookmarks())
         .add_thread_local(move_camera())
         .add_thread_local(set_click_indicator())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use gdnative::{Point2, Size2};
+
+    fn test_rig() -> CameraRig {
+        let state = RigState {
+            focus: Vector3::zero(),
+            yaw: 0.,
+            pitch: 1.,
+            distance: 50.,
+        };
+        CameraRig::new(state, 1.)
+    }
+
+    #[test]
+    fn pan_follows_yaw() {
+        let mut rig = test_rig();
+        rig.pan(Vector2::new(0., 1.));
+        assert!((rig.target.focus - Vector3::new(0., 0., -1.)).length() < 1e-5);
+
+        // Quarter turn: forward is now -x
+        let mut rig = test_rig();
+        rig.orbit(std::f32::consts::FRAC_PI_2, 0.);
+        rig.pan(Vector2::new(0., 1.));
+        assert!((rig.target.focus - Vector3::new(-1., 0., 0.)).length() < 1e-5);
+    }
+
+    #[test]
+    fn zoom_and_pitch_are_clamped() {
+        let mut rig = test_rig();
+        rig.zoom(100.);
+        assert_eq!(rig.target.distance, MIN_DISTANCE);
+        rig.zoom(-100.);
+        assert_eq!(rig.target.distance, MAX_DISTANCE);
+
+        rig.orbit(0., 10.);
+        assert_eq!(rig.target.pitch, MAX_PITCH);
+        rig.orbit(0., -10.);
+        assert_eq!(rig.target.pitch, MIN_PITCH);
+    }
+
+    #[test]
+    fn focus_is_clamped_to_bounds() {
+        let bounds = Rect2::new(Point2::new(-10., 0.), Size2::new(20., 30.));
+
+        let inside = Vector3::new(5., 0., 5.);
+        assert_eq!(clamp_focus(inside, bounds), inside);
+
+        let outside = Vector3::new(-50., 0., 100.);
+        assert_eq!(clamp_focus(outside, bounds), Vector3::new(-10., 0., 30.));
+    }
+
+    #[test]
+    fn update_eases_toward_target() {
+        let mut rig = test_rig();
+        rig.focus_on(Vector3::new(10., 5., 0.));
+        assert_eq!(rig.target.focus, Vector3::new(10., 0., 0.));
+
+        rig.update(1. / 60.);
+        let x = rig.current.focus.x;
+        assert!(x > 0. && x < 10.);
+
+        for _ in 0..600 {
+            rig.update(1. / 60.);
+        }
+        assert!((rig.current.focus.x - 10.).abs() < 1e-3);
+    }
+}
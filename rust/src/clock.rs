@@ -0,0 +1,171 @@
+pub const TICKS_PER_SECOND: u32 = 60;
+/// The simulation always steps by this, whatever the frame rate
+pub const TICK_DELTA: f32 = 1. / TICKS_PER_SECOND as f32;
+
+pub const MIN_TIME_SCALE: f32 = 0.25;
+pub const MAX_TIME_SCALE: f32 = 4.;
+
+// Give up on catching up after a long frame rather than falling further behind
+const MAX_TICKS_PER_FRAME: u32 = 5;
+// Frame times don't add up to whole ticks exactly
+const EPSILON: f64 = 1e-9;
+
+/// Ticks since the world was created
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tick(pub u64);
+
+/// Turns frame time into a whole number of ticks, the rest is carried over
+pub struct Clock {
+    accumulator: f64,
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Self { accumulator: 0. }
+    }
+
+    /// How many ticks to run for a frame that took `delta` seconds
+    pub fn advance(&mut self, delta: f64) -> u32 {
+        let step = 1. / TICKS_PER_SECOND as f64;
+        self.accumulator += delta;
+
+        let mut ticks = 0;
+        while self.accumulator + EPSILON >= step {
+            self.accumulator -= step;
+            ticks += 1;
+        }
+
+        if ticks > MAX_TICKS_PER_FRAME {
+            self.accumulator = 0.;
+            return MAX_TICKS_PER_FRAME;
+        }
+
+        ticks
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Resources -
+// -----------------------------------------------------------------------------
+/// Pause and speed of the simulation.
+/// Orders given while paused are carried out once it's running again.
+pub struct GameTime {
+    paused: bool,
+    scale: f32,
+    // Single ticks to run while paused
+    steps: u32,
+}
+
+impl GameTime {
+    pub fn new() -> Self {
+        Self {
+            paused: false,
+            scale: 1.,
+            steps: 0,
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+        self.steps = 0;
+    }
+
+    pub fn scale(&self) -> f32 {
+        self.scale
+    }
+
+    pub fn set_scale(&mut self, scale: f32) {
+        self.scale = scale.max(MIN_TIME_SCALE).min(MAX_TIME_SCALE);
+    }
+
+    pub fn faster(&mut self) {
+        self.set_scale(self.scale * 2.);
+    }
+
+    pub fn slower(&mut self) {
+        self.set_scale(self.scale / 2.);
+    }
+
+    /// Run a single tick, pausing first if it's running
+    pub fn step(&mut self) {
+        self.paused = true;
+        self.steps += 1;
+    }
+
+    /// How many ticks to run for a frame that took `delta` seconds
+    pub fn ticks(&mut self, clock: &mut Clock, delta: f64) -> u32 {
+        match self.paused {
+            true => std::mem::replace(&mut self.steps, 0),
+            false => clock.advance(delta * self.scale as f64),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const STEP: f64 = 1. / TICKS_PER_SECOND as f64;
+
+    #[test]
+    fn one_tick_per_step() {
+        let mut clock = Clock::new();
+        let ticks = (0..600).map(|_| clock.advance(STEP)).collect::<Vec<_>>();
+        assert!(ticks.iter().all(|t| *t == 1));
+    }
+
+    #[test]
+    fn short_frames_add_up() {
+        let mut clock = Clock::new();
+        assert_eq!(clock.advance(STEP * 0.6), 0);
+        assert_eq!(clock.advance(STEP * 0.6), 1);
+        assert_eq!(clock.advance(STEP * 0.8), 1);
+        assert_eq!(clock.advance(STEP * 2.5), 2);
+    }
+
+    #[test]
+    fn long_frames_are_capped() {
+        let mut clock = Clock::new();
+        assert_eq!(clock.advance(1.), MAX_TICKS_PER_FRAME);
+        assert_eq!(clock.advance(STEP), 1);
+    }
+
+    #[test]
+    fn scaled_time() {
+        let mut clock = Clock::new();
+        let mut time = GameTime::new();
+
+        time.faster();
+        assert_eq!(time.ticks(&mut clock, STEP), 2);
+
+        time.set_scale(0.25);
+        let ticks = (0..8).map(|_| time.ticks(&mut clock, STEP)).sum::<u32>();
+        assert_eq!(ticks, 2);
+
+        time.slower();
+        assert_eq!(time.scale(), MIN_TIME_SCALE);
+        time.set_scale(10.);
+        assert_eq!(time.scale(), MAX_TIME_SCALE);
+    }
+
+    #[test]
+    fn paused_time_steps_one_tick_at_a_time() {
+        let mut clock = Clock::new();
+        let mut time = GameTime::new();
+
+        time.toggle_pause();
+        assert_eq!(time.ticks(&mut clock, STEP), 0);
+
+        time.step();
+        time.step();
+        assert_eq!(time.ticks(&mut clock, STEP), 2);
+        assert_eq!(time.ticks(&mut clock, STEP), 0);
+
+        time.toggle_pause();
+        assert_eq!(time.ticks(&mut clock, STEP), 1);
+    }
+}
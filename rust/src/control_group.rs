@@ -0,0 +1,327 @@
+use std::time::{Duration, Instant};
+
+use gdnative::Vector3;
+use legion::prelude::*;
+use legion::systems::schedule::Builder;
+
+use crate::camera::CameraFocus;
+use crate::movement::Pos;
+use crate::player::{PlayerId, Selected};
+
+const GROUP_COUNT: usize = 9;
+const DOUBLE_PRESS: Duration = Duration::from_millis(400);
+
+pub enum ControlGroupCommand {
+    /// Ctrl + number
+    Assign(usize),
+    /// Shift + number
+    Add(usize),
+    /// Number
+    Recall(usize),
+    /// Recall the next (or previous) group that has units in it
+    Cycle(i32),
+}
+
+/// The next group in `step` direction that isn't empty, wrapping around.
+/// Starts from the first (or last) group if none was recalled yet.
+fn next_group<T>(groups: &[Vec<T>], current: Option<usize>, step: i32) -> Option<usize> {
+    let count = groups.len() as i32;
+    let start = match current {
+        Some(index) => index as i32,
+        None if step > 0 => -1,
+        None => count,
+    };
+
+    (1..=count)
+        .map(|offset| (start + offset * step.signum()).rem_euclid(count) as usize)
+        .find(|index| groups[*index].len() > 0)
+}
+
+// -----------------------------------------------------------------------------
+//     - Resources -
+// -----------------------------------------------------------------------------
+pub struct ControlGroups {
+    groups: Vec<Vec<Entity>>,
+    commands: Vec<ControlGroupCommand>,
+    last_recall: Option<(usize, Instant)>,
+}
+
+impl ControlGroups {
+    pub fn new() -> Self {
+        Self {
+            groups: vec![Vec::new(); GROUP_COUNT],
+            commands: Vec::new(),
+            last_recall: None,
+        }
+    }
+
+    pub fn push(&mut self, command: ControlGroupCommand) {
+        self.commands.push(command);
+    }
+
+    /// The groups as player ids, for the save file
+    pub fn to_save_data(&self, world: &World) -> Vec<Vec<PlayerId>> {
+        self.groups
+            .iter()
+            .map(|group| {
+                group
+                    .iter()
+                    .filter_map(|ent| world.get_tag::<PlayerId>(*ent).copied())
+                    .collect()
+            })
+            .collect()
+    }
+
+    pub fn restore(&mut self, world: &World, saved: &[Vec<PlayerId>]) {
+        let units = <(Read<Pos>, Tagged<PlayerId>)>::query()
+            .iter_entities(world)
+            .map(|(ent, (_, player_id))| (*player_id, ent))
+            .collect::<Vec<_>>();
+
+        for (group, saved) in self.groups.iter_mut().zip(saved) {
+            *group = saved
+                .iter()
+                .filter_map(|id| units.iter().find(|(player_id, _)| player_id == id))
+                .map(|(_, ent)| *ent)
+                .collect();
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Systems -
+// -----------------------------------------------------------------------------
+fn control_groups() -> Box<dyn Runnable> {
+    SystemBuilder::new("control groups")
+        .write_resource::<ControlGroups>()
+        .write_resource::<CameraFocus>()
+        .with_query(<Read<Pos>>::query().filter(tag::<PlayerId>()))
+        .with_query(<Read<Pos>>::query().filter(tag::<Selected>()))
+        .build_thread_local(|cmd, world, (control_groups, camera_focus), (units, selected)| {
+            // Dead units leave their groups
+            let alive = units
+                .iter_entities(world)
+                .map(|(ent, pos)| (ent, pos.0))
+                .collect::<Vec<_>>();
+            for group in control_groups.groups.iter_mut() {
+                group.retain(|ent| alive.iter().any(|(e, _)| e == ent));
+            }
+
+            let selected = selected
+                .iter_entities(world)
+                .map(|(ent, _)| ent)
+                .collect::<Vec<_>>();
+
+            let commands = control_groups.commands.drain(..).collect::<Vec<_>>();
+            for command in commands {
+                let command = match command {
+                    ControlGroupCommand::Cycle(step) => {
+                        let current = control_groups.last_recall.map(|(index, _)| index);
+                        match next_group(&control_groups.groups, current, step) {
+                            Some(index) => ControlGroupCommand::Recall(index),
+                            None => continue,
+                        }
+                    }
+                    command => command,
+                };
+
+                match command {
+                    ControlGroupCommand::Assign(index) => {
+                        control_groups.groups[index] = selected.clone();
+                    }
+                    ControlGroupCommand::Add(index) => {
+                        let group = &mut control_groups.groups[index];
+                        for ent in &selected {
+                            if !group.contains(ent) {
+                                group.push(*ent);
+                            }
+                        }
+                    }
+                    ControlGroupCommand::Recall(index) => {
+                        let group = &control_groups.groups[index];
+                        if group.len() == 0 {
+                            continue;
+                        }
+
+                        for (ent, _) in &alive {
+                            match group.contains(ent) {
+                                true => cmd.add_tag(*ent, Selected),
+                                false => cmd.remove_tag::<Selected>(*ent),
+                            }
+                        }
+
+                        // Pressing it twice centres the camera on the group
+                        let now = Instant::now();
+                        if let Some((last, at)) = control_groups.last_recall {
+                            if last == index && now - at < DOUBLE_PRESS {
+                                let positions = alive
+                                    .iter()
+                                    .filter(|(ent, _)| group.contains(ent))
+                                    .map(|(_, pos)| *pos)
+                                    .collect::<Vec<_>>();
+                                let sum = positions.iter().fold(Vector3::zero(), |acc, p| acc + *p);
+                                camera_focus.0 = Some(sum / positions.len() as f32);
+                            }
+                        }
+                        control_groups.last_recall = Some((index, now));
+                    }
+                    ControlGroupCommand::Cycle(_) => {}
+                }
+            }
+        })
+}
+
+pub fn control_group_systems(builder: Builder) -> Builder {
+    builder.add_thread_local(control_groups())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cycle_skips_empty_groups() {
+        let groups = vec![vec![1], vec![], vec![2], vec![]];
+
+        assert_eq!(next_group(&groups, None, 1), Some(0));
+        assert_eq!(next_group(&groups, None, -1), Some(2));
+        assert_eq!(next_group(&groups, Some(0), 1), Some(2));
+        assert_eq!(next_group(&groups, Some(2), 1), Some(0));
+        assert_eq!(next_group(&groups, Some(0), -1), Some(2));
+
+        let empty: Vec<Vec<u32>> = vec![vec![]; 3];
+        assert_eq!(next_group(&empty, None, 1), None);
+    }
+
+    // Four units in a row, the first two selected
+    fn setup(world: &mut World) -> (Resources, Vec<Entity>) {
+        let units = (0..4)
+            .map(|i| {
+                let pos = Pos(Vector3::new(i as f32 * 2., 0., 0.));
+                world.insert((PlayerId::new(i),), vec![(pos,)])[0]
+            })
+            .collect::<Vec<_>>();
+        assert!(world.add_tag(units[0], Selected).is_ok());
+        assert!(world.add_tag(units[1], Selected).is_ok());
+
+        let mut resources = Resources::default();
+        resources.insert(ControlGroups::new());
+        resources.insert(CameraFocus(None));
+        (resources, units)
+    }
+
+    fn run(world: &mut World, resources: &mut Resources, commands: Vec<ControlGroupCommand>) {
+        resources.get_mut::<ControlGroups>().map(|mut groups| {
+            for command in commands {
+                groups.push(command);
+            }
+        });
+        let mut schedule = control_group_systems(Schedule::builder()).build();
+        schedule.execute(world, resources);
+    }
+
+    fn ids(world: &World, units: &[Entity]) -> Vec<PlayerId> {
+        let mut ids = units
+            .iter()
+            .filter_map(|ent| world.get_tag::<PlayerId>(*ent).copied())
+            .collect::<Vec<_>>();
+        ids.sort();
+        ids
+    }
+
+    fn group(world: &World, resources: &Resources, index: usize) -> Vec<PlayerId> {
+        ids(world, &resources.get::<ControlGroups>().unwrap().groups[index])
+    }
+
+    fn selected(world: &World, units: &[Entity]) -> Vec<PlayerId> {
+        let selected = units
+            .iter()
+            .filter(|ent| world.get_tag::<Selected>(**ent).is_some())
+            .copied()
+            .collect::<Vec<_>>();
+        ids(world, &selected)
+    }
+
+    #[test]
+    fn test_assign_add_recall() {
+        let id = PlayerId::new;
+        let mut world = Universe::new().create_world();
+        let (mut resources, units) = setup(&mut world);
+
+        run(&mut world, &mut resources, vec![ControlGroupCommand::Assign(0)]);
+        assert_eq!(group(&world, &resources, 0), vec![id(0), id(1)]);
+
+        // Adding doesn't add a unit twice
+        assert!(world.add_tag(units[2], Selected).is_ok());
+        run(&mut world, &mut resources, vec![ControlGroupCommand::Add(0)]);
+        assert_eq!(group(&world, &resources, 0), vec![id(0), id(1), id(2)]);
+
+        // Assigning replaces the group
+        assert!(world.remove_tag::<Selected>(units[0]).is_ok());
+        assert!(world.remove_tag::<Selected>(units[1]).is_ok());
+        run(&mut world, &mut resources, vec![ControlGroupCommand::Assign(1)]);
+        assert_eq!(group(&world, &resources, 1), vec![id(2)]);
+
+        // Recalling selects the group and nothing else
+        assert!(world.add_tag(units[3], Selected).is_ok());
+        run(&mut world, &mut resources, vec![ControlGroupCommand::Recall(0)]);
+        assert_eq!(selected(&world, &units), vec![id(0), id(1), id(2)]);
+        assert_eq!(resources.get::<CameraFocus>().unwrap().0, None);
+
+        // An empty group leaves the selection alone
+        run(&mut world, &mut resources, vec![ControlGroupCommand::Recall(5)]);
+        assert_eq!(selected(&world, &units), vec![id(0), id(1), id(2)]);
+    }
+
+    #[test]
+    fn test_double_recall_focuses_camera() {
+        let mut world = Universe::new().create_world();
+        let (mut resources, units) = setup(&mut world);
+        run(&mut world, &mut resources, vec![ControlGroupCommand::Assign(0)]);
+
+        let recall = vec![ControlGroupCommand::Recall(0), ControlGroupCommand::Recall(0)];
+        run(&mut world, &mut resources, recall);
+
+        // Half way between the two units
+        let focus = resources.get::<CameraFocus>().unwrap().0;
+        assert_eq!(focus, Some(Vector3::new(1., 0., 0.)));
+        assert_eq!(selected(&world, &units), vec![PlayerId::new(0), PlayerId::new(1)]);
+    }
+
+    #[test]
+    fn test_dead_units_leave_groups() {
+        let mut world = Universe::new().create_world();
+        let (mut resources, units) = setup(&mut world);
+        run(&mut world, &mut resources, vec![ControlGroupCommand::Assign(0)]);
+
+        world.delete(units[0]);
+        run(&mut world, &mut resources, vec![]);
+        let group = resources.get::<ControlGroups>().unwrap().groups[0].clone();
+        assert_eq!(group, vec![units[1]]);
+    }
+
+    #[test]
+    fn test_save_and_restore() {
+        let mut world = Universe::new().create_world();
+        let (mut resources, _) = setup(&mut world);
+        run(&mut world, &mut resources, vec![ControlGroupCommand::Assign(2)]);
+
+        let saved = resources.get::<ControlGroups>().unwrap().to_save_data(&world);
+        assert_eq!(saved[2].len(), 2);
+        assert!(saved[2].contains(&PlayerId::new(0)));
+        assert!(saved[2].contains(&PlayerId::new(1)));
+
+        // Loaded into a world where the entities differ and unit 0 is gone
+        let mut loaded = Universe::new().create_world();
+        let units = (1..4)
+            .rev()
+            .map(|i| loaded.insert((PlayerId::new(i),), vec![(Pos(Vector3::zero()),)])[0])
+            .collect::<Vec<_>>();
+
+        let mut groups = ControlGroups::new();
+        groups.restore(&loaded, &saved);
+        assert_eq!(groups.groups[2], vec![units[2]]);
+        assert_eq!(ids(&loaded, &groups.groups[2]), vec![PlayerId::new(1)]);
+        assert!(groups.groups[0].is_empty());
+    }
+}
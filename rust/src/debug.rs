@@ -22,7 +22,9 @@ pub struct DebugDraw {
 #[methods]
 impl DebugDraw {
     pub fn _init(_: &Node2D) -> Self {
-        Self { lines: Vec::new() }
+        Self {
+            lines: Vec::new(),
+        }
     }
 
     pub fn set_lines(&mut self, mut lines: Vec<Line>) {
@@ -0,0 +1,508 @@
+use std::collections::VecDeque;
+
+use gdnative::api::GridMap;
+use gdnative::Vector3;
+
+use crate::procgen::{Biome, Rng};
+use crate::tilemap::NavGrid;
+
+// Give up placing a room after this many tries per room
+const ROOM_ATTEMPTS: u32 = 8;
+// Cave cells with at least this many walls around them become walls
+const CAVE_WALLS: usize = 5;
+
+/// A cell of the layout, `x` and `y` become `x` and `z` on the GridMap
+pub type Cell = (i32, i32);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tile {
+    Wall,
+    Floor,
+}
+
+impl Tile {
+    fn biome(&self) -> Biome {
+        match self {
+            Tile::Wall => Biome::Rock,
+            Tile::Floor => Biome::Ground,
+        }
+    }
+}
+
+/// Tiles in rows, everything outside is wall
+#[derive(Debug, Clone, PartialEq)]
+pub struct Grid {
+    width: i32,
+    height: i32,
+    tiles: Vec<Tile>,
+}
+
+impl Grid {
+    pub fn new(width: i32, height: i32) -> Self {
+        Self {
+            width,
+            height,
+            tiles: vec![Tile::Wall; (width * height) as usize],
+        }
+    }
+
+    pub fn width(&self) -> i32 {
+        self.width
+    }
+
+    pub fn height(&self) -> i32 {
+        self.height
+    }
+
+    fn index(&self, (x, y): Cell) -> Option<usize> {
+        match x >= 0 && y >= 0 && x < self.width && y < self.height {
+            true => Some((y * self.width + x) as usize),
+            false => None,
+        }
+    }
+
+    pub fn get(&self, cell: Cell) -> Tile {
+        self.index(cell).map(|i| self.tiles[i]).unwrap_or(Tile::Wall)
+    }
+
+    pub fn set(&mut self, cell: Cell, tile: Tile) {
+        if let Some(i) = self.index(cell) {
+            self.tiles[i] = tile;
+        }
+    }
+
+    pub fn cells(&self) -> impl Iterator<Item = (Cell, Tile)> + '_ {
+        let width = self.width;
+        self.tiles
+            .iter()
+            .enumerate()
+            .map(move |(i, tile)| ((i as i32 % width, i as i32 / width), *tile))
+    }
+
+    pub fn floor(&self) -> impl Iterator<Item = Cell> + '_ {
+        self.cells()
+            .filter(|(_, tile)| *tile == Tile::Floor)
+            .map(|(cell, _)| cell)
+    }
+
+    fn walls_around(&self, (x, y): Cell) -> usize {
+        let mut walls = 0;
+        for dy in -1..=1 {
+            for dx in -1..=1 {
+                if self.get((x + dx, y + dy)) == Tile::Wall {
+                    walls += 1;
+                }
+            }
+        }
+        walls
+    }
+
+    /// Steps from `start` to every floor cell it can reach, `None` where it can't
+    pub fn distances(&self, start: Cell) -> Vec<Option<u32>> {
+        let mut distances = vec![None; self.tiles.len()];
+        let start_index = match self.index(start) {
+            Some(i) if self.tiles[i] == Tile::Floor => i,
+            _ => return distances,
+        };
+
+        distances[start_index] = Some(0);
+        let mut queue = VecDeque::new();
+        queue.push_back((start, 0));
+
+        while let Some(((x, y), distance)) = queue.pop_front() {
+            for &next in &[(x + 1, y), (x - 1, y), (x, y + 1), (x, y - 1)] {
+                let i = match self.index(next) {
+                    Some(i) => i,
+                    None => continue,
+                };
+                if self.tiles[i] == Tile::Floor && distances[i].is_none() {
+                    distances[i] = Some(distance + 1);
+                    queue.push_back((next, distance + 1));
+                }
+            }
+        }
+
+        distances
+    }
+
+    /// Groups of floor cells that can reach each other, biggest first
+    pub fn regions(&self) -> Vec<Vec<Cell>> {
+        let mut seen = vec![false; self.tiles.len()];
+        let mut regions = Vec::new();
+
+        for cell in self.floor().collect::<Vec<_>>() {
+            if seen[self.index(cell).unwrap()] {
+                continue;
+            }
+
+            let distances = self.distances(cell);
+            let region = self
+                .floor()
+                .filter(|c| distances[self.index(*c).unwrap()].is_some())
+                .collect::<Vec<_>>();
+            for c in &region {
+                seen[self.index(*c).unwrap()] = true;
+            }
+            regions.push(region);
+        }
+
+        regions.sort_by_key(|region| std::cmp::Reverse(region.len()));
+        regions
+    }
+
+    /// Every floor cell can be walked to from every other
+    pub fn is_connected(&self) -> bool {
+        self.regions().len() <= 1
+    }
+
+    /// Dig an L shaped corridor
+    fn carve_corridor(&mut self, from: Cell, to: Cell, horizontal_first: bool) {
+        let corner = match horizontal_first {
+            true => (to.0, from.1),
+            false => (from.0, to.1),
+        };
+
+        for &(a, b) in &[(from, corner), (corner, to)] {
+            let (min_x, max_x) = (a.0.min(b.0), a.0.max(b.0));
+            let (min_y, max_y) = (a.1.min(b.1), a.1.max(b.1));
+            for y in min_y..=max_y {
+                for x in min_x..=max_x {
+                    self.set((x, y), Tile::Floor);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Room {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl Room {
+    pub fn centre(&self) -> Cell {
+        (self.x + self.width / 2, self.y + self.height / 2)
+    }
+
+    /// Rooms need at least `gap` walls between them
+    fn overlaps(&self, other: &Room, gap: i32) -> bool {
+        self.x - gap < other.x + other.width
+            && other.x - gap < self.x + self.width
+            && self.y - gap < other.y + other.height
+            && other.y - gap < self.y + self.height
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpawnKind {
+    PlayerSquad,
+    EnemyGroup,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpawnPoint {
+    pub kind: SpawnKind,
+    pub cell: Cell,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DungeonConfig {
+    pub width: i32,
+    pub height: i32,
+    pub max_rooms: u32,
+    pub min_room: i32,
+    pub max_room: i32,
+    /// Chance of a cave cell starting out as wall, in percent
+    pub cave_fill: u32,
+    pub cave_steps: u32,
+    pub enemy_groups: usize,
+    /// Enemy groups are at least this many cells apart
+    pub spawn_spacing: i32,
+}
+
+impl Default for DungeonConfig {
+    fn default() -> Self {
+        Self {
+            width: 64,
+            height: 64,
+            max_rooms: 12,
+            min_room: 4,
+            max_room: 10,
+            cave_fill: 45,
+            cave_steps: 4,
+            enemy_groups: 4,
+            spawn_spacing: 8,
+        }
+    }
+}
+
+/// A generated interior, every floor cell is reachable
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layout {
+    pub grid: Grid,
+    /// Empty for caves
+    pub rooms: Vec<Room>,
+    pub spawns: Vec<SpawnPoint>,
+}
+
+impl Layout {
+    pub fn spawns(&self, kind: SpawnKind) -> impl Iterator<Item = Cell> + '_ {
+        self.spawns
+            .iter()
+            .filter(move |spawn| spawn.kind == kind)
+            .map(|spawn| spawn.cell)
+    }
+
+    /// Replace the cells of the GridMap and the navigation grid,
+    /// with the top left corner of the layout at `origin`
+    pub fn write(&self, gridmap: &GridMap, nav: &mut NavGrid, origin: Cell) {
+        gridmap.clear();
+        nav.clear();
+        for ((x, y), tile) in self.grid.cells() {
+            let (x, z) = (origin.0 + x, origin.1 + y);
+            gridmap.set_cell_item(x as i64, 0, z as i64, tile.biome().item(), 0);
+            nav.set_walkable(x, z, tile == Tile::Floor);
+        }
+    }
+
+    /// The middle of a cell, on the ground
+    pub fn world_pos(cell: Cell, origin: Cell, cell_size: Vector3) -> Vector3 {
+        Vector3::new(
+            (origin.0 + cell.0) as f32 * cell_size.x + cell_size.x / 2.,
+            0.,
+            (origin.1 + cell.1) as f32 * cell_size.z + cell_size.z / 2.,
+        )
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Resources -
+// -----------------------------------------------------------------------------
+/// Where the squads of the current dungeon start
+pub struct SpawnPoints(pub Vec<(SpawnKind, Vector3)>);
+
+/// Dig corridors from every smaller region to the biggest one
+fn connect_regions(grid: &mut Grid, rng: &mut Rng) {
+    let regions = grid.regions();
+    let main = match regions.first() {
+        Some(region) => region[0],
+        None => return,
+    };
+
+    for region in regions.iter().skip(1) {
+        let horizontal_first = rng.chance(50);
+        grid.carve_corridor(region[0], main, horizontal_first);
+    }
+}
+
+/// The player squad goes at `start`, the enemy groups at the candidates
+/// furthest from it, spread out
+fn place_spawns(grid: &Grid, start: Cell, candidates: &[Cell], config: &DungeonConfig) -> Vec<SpawnPoint> {
+    let distances = grid.distances(start);
+    let mut candidates = candidates
+        .iter()
+        .filter_map(|cell| grid.index(*cell).and_then(|i| distances[i]).map(|d| (*cell, d)))
+        .filter(|(_, d)| *d > 0)
+        .collect::<Vec<_>>();
+    candidates.sort_by_key(|(cell, d)| (std::cmp::Reverse(*d), cell.1, cell.0));
+
+    let mut spawns = vec![SpawnPoint {
+        kind: SpawnKind::PlayerSquad,
+        cell: start,
+    }];
+
+    for (cell, _) in candidates {
+        if spawns.len() > config.enemy_groups {
+            break;
+        }
+
+        let too_close = spawns.iter().any(|spawn| {
+            (spawn.cell.0 - cell.0).abs().max((spawn.cell.1 - cell.1).abs()) < config.spawn_spacing
+        });
+        if !too_close {
+            spawns.push(SpawnPoint {
+                kind: SpawnKind::EnemyGroup,
+                cell,
+            });
+        }
+    }
+
+    spawns
+}
+
+// -----------------------------------------------------------------------------
+//     - Generators -
+// -----------------------------------------------------------------------------
+/// Rooms placed at random, each joined to the one placed before it
+pub fn rooms_and_corridors(seed: u64, config: &DungeonConfig) -> Layout {
+    let mut rng = Rng::new(seed);
+    let mut grid = Grid::new(config.width, config.height);
+    let mut rooms: Vec<Room> = Vec::new();
+
+    for _ in 0..config.max_rooms * ROOM_ATTEMPTS {
+        if rooms.len() as u32 == config.max_rooms {
+            break;
+        }
+
+        let width = rng.range(config.min_room, config.max_room + 1);
+        let height = rng.range(config.min_room, config.max_room + 1);
+        // Keep a wall around the edge
+        let room = Room {
+            x: rng.range(1, config.width - width),
+            y: rng.range(1, config.height - height),
+            width,
+            height,
+        };
+
+        if room.x + room.width >= config.width || room.y + room.height >= config.height {
+            continue;
+        }
+        if rooms.iter().any(|other| room.overlaps(other, 1)) {
+            continue;
+        }
+
+        for y in room.y..room.y + room.height {
+            for x in room.x..room.x + room.width {
+                grid.set((x, y), Tile::Floor);
+            }
+        }
+
+        if let Some(previous) = rooms.last() {
+            let horizontal_first = rng.chance(50);
+            grid.carve_corridor(previous.centre(), room.centre(), horizontal_first);
+        }
+
+        rooms.push(room);
+    }
+
+    let start = match rooms.first() {
+        Some(room) => room.centre(),
+        None => {
+            return Layout {
+                grid,
+                rooms,
+                spawns: Vec::new(),
+            }
+        }
+    };
+
+    let centres = rooms.iter().skip(1).map(Room::centre).collect::<Vec<_>>();
+    let spawns = place_spawns(&grid, start, &centres, config);
+    Layout { grid, rooms, spawns }
+}
+
+/// Random noise smoothed by a cellular automaton
+pub fn caves(seed: u64, config: &DungeonConfig) -> Layout {
+    let mut rng = Rng::new(seed);
+    let mut grid = Grid::new(config.width, config.height);
+
+    for y in 1..config.height - 1 {
+        for x in 1..config.width - 1 {
+            if !rng.chance(config.cave_fill) {
+                grid.set((x, y), Tile::Floor);
+            }
+        }
+    }
+
+    for _ in 0..config.cave_steps {
+        let mut next = Grid::new(config.width, config.height);
+        for y in 1..config.height - 1 {
+            for x in 1..config.width - 1 {
+                if grid.walls_around((x, y)) < CAVE_WALLS {
+                    next.set((x, y), Tile::Floor);
+                }
+            }
+        }
+        grid = next;
+    }
+
+    connect_regions(&mut grid, &mut rng);
+
+    // Start as close to the middle as there's floor
+    let middle = (config.width / 2, config.height / 2);
+    let start = grid
+        .floor()
+        .min_by_key(|cell| (cell.0 - middle.0).abs() + (cell.1 - middle.1).abs());
+
+    let spawns = match start {
+        Some(start) => {
+            let floor = grid.floor().collect::<Vec<_>>();
+            place_spawns(&grid, start, &floor, config)
+        }
+        None => Vec::new(),
+    };
+
+    Layout {
+        grid,
+        rooms: Vec::new(),
+        spawns,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn every_room_is_reachable() {
+        let config = DungeonConfig::default();
+        for seed in 0..20 {
+            let layout = rooms_and_corridors(seed, &config);
+            assert!(layout.rooms.len() > 1);
+            assert!(layout.grid.is_connected());
+
+            let distances = layout.grid.distances(layout.rooms[0].centre());
+            for room in &layout.rooms {
+                let i = layout.grid.index(room.centre()).unwrap();
+                assert!(distances[i].is_some());
+            }
+        }
+    }
+
+    #[test]
+    fn caves_are_connected() {
+        let config = DungeonConfig::default();
+        for seed in 0..20 {
+            let layout = caves(seed, &config);
+            assert!(layout.grid.floor().count() > 0);
+            assert!(layout.grid.is_connected());
+        }
+    }
+
+    #[test]
+    fn layouts_are_deterministic() {
+        let config = DungeonConfig::default();
+        assert_eq!(rooms_and_corridors(3, &config), rooms_and_corridors(3, &config));
+        assert_eq!(caves(3, &config), caves(3, &config));
+        assert_ne!(caves(3, &config).grid, caves(4, &config).grid);
+    }
+
+    #[test]
+    fn spawns_are_on_reachable_floor() {
+        let config = DungeonConfig::default();
+        for layout in vec![rooms_and_corridors(1, &config), caves(1, &config)] {
+            assert_eq!(layout.spawns(SpawnKind::PlayerSquad).count(), 1);
+            let enemies = layout.spawns(SpawnKind::EnemyGroup).collect::<Vec<_>>();
+            assert!(!enemies.is_empty() && enemies.len() <= config.enemy_groups);
+
+            for spawn in &layout.spawns {
+                assert_eq!(layout.grid.get(spawn.cell), Tile::Floor);
+            }
+        }
+    }
+
+    #[test]
+    fn regions_are_split_by_walls() {
+        let mut grid = Grid::new(5, 3);
+        grid.set((1, 1), Tile::Floor);
+        grid.set((3, 1), Tile::Floor);
+        grid.set((3, 2), Tile::Floor);
+        assert_eq!(grid.regions(), vec![vec![(3, 1), (3, 2)], vec![(1, 1)]]);
+
+        grid.carve_corridor((1, 1), (3, 1), true);
+        assert!(grid.is_connected());
+    }
+}
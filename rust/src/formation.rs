@@ -1,14 +1,22 @@
+use std::collections::HashMap;
+
 use bitter::Bitter;
 use gdextras::node_ext::NodeExt;
 use gdnative::api::{Control, TextureRect};
-use gdnative::{Color, Ptr, Vector2};
+use gdnative::{Color, Ptr, Rect2, Vector2};
 use legion::prelude::*;
 use legion::systems::schedule::Builder;
 
-use crate::input::{MouseButton, LMB};
+use crate::gesture::{GestureKind, Gestures, UiRects};
+use crate::input::{Layer, LMB};
+use crate::player::Selected;
+use crate::squad::{ActiveSquad, SquadId};
+use crate::unit::Health;
 
 const TILE_SIZE: f32 = 16.;
 const FORMATION_WIDTH: usize = 4; // WIDTH x WIDTH
+const HIGHLIGHT_COLOR: Color = Color { r: 1.6, g: 1.6, b: 1.6, a: 1. };
+const DIMMED_COLOR: Color = Color { r: 1., g: 1., b: 1., a: 0.4 };
 
 // Formation related functions
 pub fn index_to_x_y(index: usize) -> (usize, usize) {
@@ -18,11 +26,6 @@ pub fn index_to_x_y(index: usize) -> (usize, usize) {
     (x, y)
 }
 
-fn pos_to_index(pos: Vector2) -> usize {
-    let index = pos.y * FORMATION_WIDTH as f32 + pos.x;
-    index as usize
-}
-
 // fn index_to_col(index: usize) -> usize {
 //     index / FORMATION_WIDTH
 // }
@@ -33,17 +36,113 @@ fn pos_to_index(pos: Vector2) -> usize {
 //     row
 // }
 
-// fn row_to_index(row: usize) -> Vec<usize> {
-//     let start = row * FORMATION_WIDTH;
-//     let end = row * FORMATION_WIDTH + FORMATION_WIDTH;
-//     (start..end).collect()
-// }
+fn row_to_index(row: usize) -> Vec<usize> {
+    let start = row * FORMATION_WIDTH;
+    let end = row * FORMATION_WIDTH + FORMATION_WIDTH;
+    (start..end).collect()
+}
 
-// fn col_to_index(col: usize) -> Vec<usize> {
-//     (col..FORMATION_WIDTH * FORMATION_WIDTH)
-//         .step_by(FORMATION_WIDTH)
-//         .collect()
-// }
+fn col_to_index(col: usize) -> Vec<usize> {
+    (col..FORMATION_WIDTH * FORMATION_WIDTH)
+        .step_by(FORMATION_WIDTH)
+        .collect()
+}
+
+/// If the index is a slot in the formation grid (and fits in the occupancy mask)
+pub fn is_slot(index: u16) -> bool {
+    (0..FORMATION_WIDTH)
+        .flat_map(row_to_index)
+        .any(|slot| slot == index as usize && slot < 16)
+}
+
+/// Bitmask of the occupied slots
+pub fn occupancy<T>(slots: &[(T, u16)]) -> u16 {
+    slots.iter().fold(0, |acc, (_, index)| acc | 1 << index)
+}
+
+/// The first free slot, front row first
+pub fn first_free(occupied: u16) -> Option<u16> {
+    (0..FORMATION_WIDTH)
+        .flat_map(row_to_index)
+        .map(|index| index as u16)
+        .find(|index| occupied & 1 << index == 0)
+}
+
+/// Close the gaps left in `vacated` by promoting the units behind them
+/// (in the same column) forward. Gaps that were already there are left alone.
+pub fn reflow<T>(slots: &mut [(T, u16)], vacated: u16) {
+    let mut gaps = (0..(FORMATION_WIDTH * FORMATION_WIDTH) as u16)
+        .filter(|index| vacated & 1 << index != 0)
+        .collect::<Vec<_>>();
+
+    while gaps.len() > 0 {
+        // Always fill the front most gap first
+        gaps.sort();
+        let gap = gaps.remove(0);
+        let (col, _) = index_to_x_y(gap as usize);
+
+        let behind = col_to_index(col)
+            .into_iter()
+            .map(|index| index as u16)
+            .filter(|index| *index > gap)
+            .find_map(|index| slots.iter().position(|(_, i)| *i == index));
+
+        if let Some(unit) = behind {
+            gaps.push(slots[unit].1);
+            slots[unit].1 = gap;
+        }
+    }
+}
+
+/// Move the `selected` units by `delta` (columns, rows), the units in the way
+/// swap into the slots that were left.
+/// Returns the new layout, or `None` if a unit would end up outside the grid.
+pub fn move_units<T: Copy + PartialEq>(
+    slots: &[(T, u16)],
+    selected: &[T],
+    delta: (i32, i32),
+) -> Option<Vec<(T, u16)>> {
+    let width = FORMATION_WIDTH as i32;
+    let mut layout = slots.to_vec();
+
+    let mut origins = Vec::new();
+    let mut targets = Vec::new();
+    for (unit, index) in layout.iter_mut() {
+        if !selected.contains(unit) {
+            continue;
+        }
+
+        let (x, y) = index_to_x_y(*index as usize);
+        let (x, y) = (x as i32 + delta.0, y as i32 + delta.1);
+        if x < 0 || y < 0 || x >= width || y >= width {
+            return None;
+        }
+
+        origins.push(*index);
+        *index = (y * width + x) as u16;
+        targets.push(*index);
+    }
+
+    // The slots that were left, front first
+    let mut free = origins
+        .into_iter()
+        .filter(|index| !targets.contains(index))
+        .collect::<Vec<_>>();
+    free.sort();
+    let mut free = free.into_iter();
+
+    let mut displaced = layout
+        .iter_mut()
+        .filter(|(unit, index)| !selected.contains(unit) && targets.contains(index))
+        .collect::<Vec<_>>();
+    displaced.sort_by_key(|(_, index)| *index);
+
+    for (_, index) in displaced {
+        *index = free.next()?;
+    }
+
+    Some(layout)
+}
 
 // *  Needs to work regardless of number of units
 //
@@ -52,9 +151,6 @@ fn pos_to_index(pos: Vector2) -> usize {
 // *  Each unit has a formation order
 // *  Selected units are ordered by formation order
 
-// TODO:
-// [ ] Snapping
-// [ ] Updating the FormationPosition
 
 fn pos_to_coords(pos: Vector2) -> Vector2 {
     (pos / TILE_SIZE).floor()
@@ -69,23 +165,40 @@ fn snap_to_pos(pos: Vector2) -> Vector2 {
     coords_to_pos(coords)
 }
 
+pub fn index_to_pos(index: u16) -> Vector2 {
+    let (x, y) = index_to_x_y(index as usize);
+    coords_to_pos(Vector2::new(x as f32, y as f32))
+}
+
+/// Occupied slots of a squad formation, one bit per slot
+#[derive(Debug, Clone, Copy)]
 pub struct Formation(pub u16);
 
 impl Formation {
     pub fn new() -> Self {
         Self(0)
     }
+
+    pub fn set_occupied(&mut self, indices: &[u16]) {
+        self.0 = 0;
+        for index in indices {
+            self.0.set_bit(*index, true);
+        }
+    }
 }
 
 // -----------------------------------------------------------------------------
 //     - Tags -
 // -----------------------------------------------------------------------------
 
+/// Selected in the formation UI
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct FormationUnitSelected;
 
+/// Add this to a unit to take it out of its squad and formation.
+/// Units that die get this, their icon is freed and the units behind move up.
 #[derive(Debug, Clone, Copy, PartialEq)]
-pub struct FormationUnitMoved;
+pub struct LeaveFormation;
 
 // -----------------------------------------------------------------------------
 //     - Resources -
@@ -96,6 +209,22 @@ impl FormationUI {
     pub fn new(inner: Ptr<TextureRect>) -> Self {
         Self(inner)
     }
+
+    pub fn set_color(&self, color: Color) {
+        let ui = unsafe { self.0.assume_safe() };
+        ui.set_self_modulate(color);
+    }
+
+    fn global_rect(&self) -> Rect2 {
+        let ui = unsafe { self.0.assume_safe() };
+        ui.get_global_rect()
+    }
+
+    /// A screen position relative to the formation grid
+    fn to_local(&self, pos: Vector2) -> Vector2 {
+        let ui = unsafe { self.0.assume_safe() };
+        pos - ui.get_global_position()
+    }
 }
 
 unsafe impl Send for FormationUI {}
@@ -107,16 +236,117 @@ impl FormationUnit {
     pub fn new(inner: Ptr<TextureRect>) -> Self {
         Self(inner)
     }
+
+    pub fn set_index(&mut self, index: u16) {
+        let unit = unsafe { self.0.assume_safe() };
+        unit.set_position(index_to_pos(index), false);
+    }
+
+    pub fn set_visible(&mut self, visible: bool) {
+        let unit = unsafe { self.0.assume_safe() };
+        unit.set_visible(visible);
+    }
+
+    /// Highlighted when selected in the formation UI,
+    /// dimmed when the unit isn't selected in the world
+    pub fn set_highlight(&mut self, highlight: bool, dimmed: bool) {
+        let unit = unsafe { self.0.assume_safe() };
+        match (highlight, dimmed) {
+            (true, _) => unit.set_self_modulate(HIGHLIGHT_COLOR),
+            (false, true) => unit.set_self_modulate(DIMMED_COLOR),
+            (false, false) => unit.set_self_modulate(Color::rgb(1., 1., 1.)),
+        }
+    }
+
+    pub fn free(&mut self) {
+        let unit = unsafe { self.0.assume_safe() };
+        unit.queue_free();
+    }
+
+    // Move the node between "Pending" and "Moving" so the dragged
+    // units are drawn on top
+    fn reparent(&mut self, formation_ui: &FormationUI, from: &str, to: &str) {
+        let unit = unsafe { self.0.assume_safe() };
+        let formation_ui = unsafe { formation_ui.0.assume_safe() };
+        let mut from = formation_ui.get_and_cast::<Control>(from);
+        let mut to = formation_ui.get_and_cast::<Control>(to);
+        from.remove_child(Some(unit.to_node()));
+        to.add_child(Some(unit.to_node()), false);
+        unit.set_owner(Some(to.to_node()));
+    }
 }
 
 unsafe impl Send for FormationUnit {}
 unsafe impl Sync for FormationUnit {}
 
+/// The units being dragged in the formation UI, with their original index
+pub enum FormationDrag {
+    Empty,
+    Start { units: Vec<(Entity, u16)> },
+}
+
+impl FormationDrag {
+    fn is_dragging(&self, ent: Entity) -> bool {
+        match self {
+            Self::Empty => false,
+            Self::Start { units } => units.iter().any(|(e, _)| *e == ent),
+        }
+    }
+
+    fn take(&mut self) -> Self {
+        std::mem::replace(self, Self::Empty)
+    }
+}
+
+pub enum FormationCommand {
+    Nudge(i32, i32),
+    Undo,
+    Redo,
+}
+
+/// Commands from the keyboard, applied by the formation editor
+pub struct FormationCommands(pub Vec<FormationCommand>);
+
+impl FormationCommands {
+    pub fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    pub fn push(&mut self, command: FormationCommand) {
+        self.0.push(command);
+    }
+}
+
+struct LayoutEdit {
+    squad: Entity,
+    before: Vec<(Entity, u16)>,
+    after: Vec<(Entity, u16)>,
+}
+
+pub struct FormationHistory {
+    undo: Vec<LayoutEdit>,
+    redo: Vec<LayoutEdit>,
+}
+
+impl FormationHistory {
+    pub fn new() -> Self {
+        Self {
+            undo: Vec::new(),
+            redo: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, edit: LayoutEdit) {
+        self.undo.push(edit);
+        self.redo.clear();
+    }
+}
+
 // -----------------------------------------------------------------------------
 //     - Components -
 // -----------------------------------------------------------------------------
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct FormationPos(pub u16);
 
 impl FormationPos {
@@ -125,153 +355,478 @@ impl FormationPos {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-struct StartDrag(Vector2);
+/// Set the layout of a squad.
+/// The formation bits are updated together with the positions so the
+/// membership system doesn't mistake the edit for units leaving.
+fn apply_layout(cmd: &mut CommandBuffer, squad: Entity, layout: &[(Entity, u16)]) {
+    for (ent, index) in layout {
+        cmd.add_component(*ent, FormationPos(*index));
+    }
+    cmd.add_component(squad, Formation(occupancy(layout)));
+}
+
+// A layout in history is only valid as long as the squad has the same units
+fn same_units(a: &[(Entity, u16)], b: &[(Entity, u16)]) -> bool {
+    a.len() == b.len() && a.iter().all(|(ent, _)| b.iter().any(|(other, _)| ent == other))
+}
 
 // -----------------------------------------------------------------------------
 //     - Systems -
 // -----------------------------------------------------------------------------
+fn formation_ui_rect() -> Box<dyn Runnable> {
+    SystemBuilder::new("formation ui rect")
+        .read_resource::<FormationUI>()
+        .write_resource::<UiRects>()
+        .build_thread_local(|_, _, (formation_ui, ui_rects), _| {
+            ui_rects.set("formation", Some(formation_ui.global_rect()));
+        })
+}
+
 fn select_formation_unit() -> Box<dyn Runnable> {
     SystemBuilder::new("select formation unit")
-        .write_resource::<MouseButton>()
+        .read_resource::<Gestures>()
         .read_resource::<FormationUI>()
-        .with_query(<Write<FormationUnit>>::query())
-        .build_thread_local(|cmd, world, (mouse_btn, formation_ui), units| unsafe {
-            if !mouse_btn.button_pressed(LMB) {
-                return;
-            }
+        .read_resource::<ActiveSquad>()
+        .write_resource::<FormationDrag>()
+        .with_query(<(Read<SquadId>, Read<FormationPos>, Write<FormationUnit>)>::query())
+        .with_query(<Read<FormationUnit>>::query().filter(tag::<FormationUnitSelected>()))
+        .build_thread_local(|cmd, world, resources, (units, selected)| {
+            let (gestures, formation_ui, active_squad, drag) = resources;
+
+            let mut selected = selected
+                .iter_entities(world)
+                .map(|(ent, _)| ent)
+                .collect::<Vec<_>>();
 
-            let formation_ui = unsafe { formation_ui.0.assume_safe() };
+            for gesture in gestures.iter(Layer::Ui).filter(|g| g.button == LMB) {
+                let pick_up = match gesture.kind {
+                    GestureKind::Click | GestureKind::DoubleClick => false,
+                    GestureKind::DragStart => true,
+                    _ => continue,
+                };
+
+                // The UI layer is shared with other menus
+                if !formation_ui.global_rect().contains(gesture.start.to_point()) {
+                    continue;
+                }
 
-            let mouse_pos = formation_ui.get_local_mouse_position();
+                let mouse_pos = formation_ui.to_local(gesture.start);
+                let clicked = units
+                    .iter_entities_mut(world)
+                    .filter(|(_, (squad_id, _, _))| Some(squad_id.0) == active_squad.0)
+                    .find(|(_, (_, _, unit))| {
+                        let unit = unsafe { unit.0.assume_safe() };
+                        unit.get_rect().contains(mouse_pos.to_point())
+                    })
+                    .map(|(ent, _)| ent);
 
-            let mut rect = formation_ui.get_rect();
-            rect.origin = Vector2::zero().to_point();
-            if !rect.contains(mouse_pos.to_point()) {
-                return;
+                // Clicking next to the units clears the selection
+                let clicked = match (clicked, pick_up) {
+                    (Some(ent), _) => ent,
+                    (None, false) => {
+                        for ent in selected.drain(..) {
+                            cmd.remove_tag::<FormationUnitSelected>(ent);
+                        }
+                        continue;
+                    }
+                    (None, true) => continue,
+                };
+
+                // Shift toggles, a plain click selects only the clicked unit.
+                // Dragging a selected unit takes the whole selection with it.
+                let was_selected = selected.contains(&clicked);
+                if gesture.shift {
+                    if was_selected && !pick_up {
+                        cmd.remove_tag::<FormationUnitSelected>(clicked);
+                        selected.retain(|ent| *ent != clicked);
+                        continue;
+                    }
+                    if !was_selected {
+                        cmd.add_tag(clicked, FormationUnitSelected);
+                        selected.push(clicked);
+                    }
+                } else if !(pick_up && was_selected) {
+                    for ent in selected.drain(..).filter(|ent| *ent != clicked) {
+                        cmd.remove_tag::<FormationUnitSelected>(ent);
+                    }
+                    if !was_selected {
+                        cmd.add_tag(clicked, FormationUnitSelected);
+                    }
+                    selected.push(clicked);
+                }
+
+                if !pick_up {
+                    continue;
+                }
+
+                let mut dragged = Vec::new();
+                for (ent, (squad_id, formation_pos, mut unit)) in units.iter_entities_mut(world) {
+                    if selected.contains(&ent) && Some(squad_id.0) == active_squad.0 {
+                        dragged.push((ent, formation_pos.0));
+                        unit.reparent(formation_ui, "Pending", "Moving");
+                    }
+                }
+
+                **drag = FormationDrag::Start { units: dragged };
             }
-            mouse_btn.consume();
+        })
+}
 
-            for (ent, mut unit) in units.iter_entities_mut(world) {
-                let unit = unsafe { unit.0.assume_safe() };
-                let rect = unit.get_rect();
-
-                if rect.contains(mouse_pos.to_point()) {
-                    cmd.add_component(ent, StartDrag(mouse_pos));
-                    cmd.add_tag(ent, FormationUnitSelected);
-
-                    let mut pending = formation_ui.get_and_cast::<Control>("Pending");
-                    let mut moving = formation_ui.get_and_cast::<Control>("Moving");
-                    pending.remove_child(Some(unit.to_node()));
-                    moving.add_child(Some(unit.to_node()), false);
-                    unit.set_owner(Some(moving.to_node()));
+fn drag_formation_unit() -> Box<dyn Runnable> {
+    SystemBuilder::new("drag formation unit")
+        .read_resource::<Gestures>()
+        .read_resource::<FormationDrag>()
+        .with_query(<Write<FormationUnit>>::query())
+        .build_thread_local(|_, world, (gestures, drag), units| {
+            let dragged = match &**drag {
+                FormationDrag::Start { units } => units,
+                FormationDrag::Empty => return,
+            };
+
+            let offset = match gestures
+                .iter(Layer::Ui)
+                .filter(|g| g.is(GestureKind::DragMove, LMB))
+                .last()
+            {
+                Some(gesture) => gesture.pos - gesture.start,
+                None => return,
+            };
+
+            for (ent, unit) in units.iter_entities_mut(world) {
+                if let Some((_, index)) = dragged.iter().find(|(e, _)| *e == ent) {
+                    let unit = unsafe { unit.0.assume_safe() };
+                    unit.set_position(index_to_pos(*index) + offset, false);
                 }
             }
         })
 }
 
-fn deselect_formation_unit() -> Box<dyn Runnable> {
-    SystemBuilder::new("deselect formation unit")
-        .read_resource::<MouseButton>()
+fn drop_formation_unit() -> Box<dyn Runnable> {
+    SystemBuilder::new("drop formation unit")
+        .read_resource::<Gestures>()
         .read_resource::<FormationUI>()
-        .with_query(<Write<FormationUnit>>::query())
-        .with_query(
-            <(Write<FormationUnit>, Read<StartDrag>)>::query()
-                .filter(tag::<FormationUnitSelected>()),
-        )
-        .build_thread_local(
-            |cmd, world, (mouse_btn, formation_ui), (deselected_units, units)| {
-                if !mouse_btn.button_released(LMB) {
+        .read_resource::<ActiveSquad>()
+        .write_resource::<FormationDrag>()
+        .write_resource::<FormationHistory>()
+        .with_query(<(Read<SquadId>, Read<FormationPos>, Write<FormationUnit>)>::query())
+        .build_thread_local(|cmd, world, resources, units| {
+            let (gestures, formation_ui, active_squad, drag, history) = resources;
+
+            let offset = match gestures
+                .iter(Layer::Ui)
+                .find(|g| g.is(GestureKind::DragEnd, LMB))
+            {
+                Some(gesture) => gesture.pos - gesture.start,
+                None => return,
+            };
+
+            let dragged = match drag.take() {
+                FormationDrag::Start { units } => units,
+                FormationDrag::Empty => return,
+            };
+
+            let squad = match active_squad.0 {
+                Some(s) => s,
+                None => return,
+            };
+
+            // Snap the centre of the first icon to the cell it was dropped on
+            let origin = match dragged.first() {
+                Some((_, index)) => index_to_pos(*index),
+                None => return,
+            };
+            let half_tile = Vector2::new(TILE_SIZE, TILE_SIZE) / 2.;
+            let dropped = snap_to_pos(origin + offset + half_tile);
+            let delta = pos_to_coords(dropped) - pos_to_coords(origin);
+            let delta = (delta.x as i32, delta.y as i32);
+
+            let mut slots = Vec::new();
+            for (ent, (squad_id, formation_pos, mut unit)) in units.iter_entities_mut(world) {
+                if squad_id.0 != squad {
+                    continue;
+                }
+
+                if let Some((_, index)) = dragged.iter().find(|(e, _)| *e == ent) {
+                    unit.reparent(formation_ui, "Moving", "Pending");
+                    // Back where it was until the new layout is applied
+                    unit.set_index(*index);
+                }
+
+                slots.push((ent, formation_pos.0));
+            }
+
+            if delta == (0, 0) {
+                return;
+            }
+
+            let dragged = dragged.iter().map(|(ent, _)| *ent).collect::<Vec<_>>();
+
+            // Dropped outside the grid
+            let layout = match move_units(&slots, &dragged, delta) {
+                Some(l) => l,
+                None => return,
+            };
+
+            apply_layout(cmd, squad, &layout);
+            history.push(LayoutEdit {
+                squad,
+                before: slots,
+                after: layout,
+            });
+        })
+}
+
+fn formation_commands() -> Box<dyn Runnable> {
+    SystemBuilder::new("formation commands")
+        .read_resource::<ActiveSquad>()
+        .write_resource::<FormationCommands>()
+        .write_resource::<FormationHistory>()
+        .read_resource::<FormationDrag>()
+        .with_query(<(Read<SquadId>, Read<FormationPos>)>::query())
+        .with_query(<Read<FormationUnit>>::query().filter(tag::<FormationUnitSelected>()))
+        .build_thread_local(|cmd, world, resources, (units, selected)| {
+            let (active_squad, commands, history, drag) = resources;
+
+            // Leave the layout alone while it's being dragged
+            if let FormationDrag::Start { .. } = **drag {
+                return;
+            }
+
+            let squad = match active_squad.0 {
+                Some(s) => s,
+                None => {
+                    commands.0.clear();
                     return;
                 }
+            };
 
-                let start_pos = match units.iter_mut(world).next() {
-                    Some((_, s)) => snap_to_pos(s.0),
-                    None => return,
-                };
+            let slots = units
+                .iter_entities(world)
+                .filter(|(_, (squad_id, _))| squad_id.0 == squad)
+                .map(|(ent, (_, formation_pos))| (ent, formation_pos.0))
+                .collect::<Vec<_>>();
+
+            let selected = selected
+                .iter_entities(world)
+                .map(|(ent, _)| ent)
+                .collect::<Vec<_>>();
 
-                let formation_ui = unsafe { formation_ui.0.assume_safe() };
+            // One command per frame, the next one needs the updated layout
+            let command = match commands.0.len() {
+                0 => return,
+                _ => commands.0.remove(0),
+            };
 
-                let mouse_pos = formation_ui.get_local_mouse_position();
-                let mouse_pos = snap_to_pos(mouse_pos);
+            match command {
+                FormationCommand::Nudge(x, y) => {
+                    if selected.len() == 0 {
+                        return;
+                    }
 
-                // Check if there is already a unit at the final destination
-                for mut unit in deselected_units.iter_mut(world) {
-                    let unit = unsafe { unit.0.assume_safe() };
-                    if unit.get_rect().contains(mouse_pos.to_point()) {
-                        unit.set_position(start_pos, false);
+                    if let Some(layout) = move_units(&slots, &selected, (x, y)) {
+                        apply_layout(cmd, squad, &layout);
+                        history.push(LayoutEdit {
+                            squad,
+                            before: slots,
+                            after: layout,
+                        });
                     }
                 }
+                FormationCommand::Undo => {
+                    let edit = match history.undo.pop() {
+                        Some(e) => e,
+                        None => return,
+                    };
 
-                for (ent, (mut unit, _)) in units.iter_entities_mut(world) {
-                    cmd.remove_tag::<FormationUnitSelected>(ent);
-                    cmd.add_tag(ent, FormationUnitMoved);
+                    if edit.squad == squad && same_units(&edit.before, &slots) {
+                        apply_layout(cmd, squad, &edit.before);
+                        history.redo.push(edit);
+                    } else {
+                        // The squad changed since, this can't be undone
+                        history.undo.clear();
+                    }
+                }
+                FormationCommand::Redo => {
+                    let edit = match history.redo.pop() {
+                        Some(e) => e,
+                        None => return,
+                    };
 
-                    let unit = unsafe { unit.0.assume_safe() };
-                    unit.set_position(mouse_pos, false);
-
-                    // Reparent the node to "Pending" again
-                    let mut pending = formation_ui.get_and_cast::<Control>("Pending");
-                    let mut moving = formation_ui.get_and_cast::<Control>("Moving");
-                    moving.remove_child(Some(unit.to_node()));
-                    pending.add_child(Some(unit.to_node()), false);
-                    unit.set_owner(Some(pending.to_node()));
+                    if edit.squad == squad && same_units(&edit.after, &slots) {
+                        apply_layout(cmd, squad, &edit.after);
+                        history.undo.push(edit);
+                    } else {
+                        history.redo.clear();
+                    }
                 }
-            },
-        )
+            }
+        })
 }
 
-fn drag_formation_unit() -> Box<dyn Runnable> {
-    SystemBuilder::new("drag formation unit")
-        .read_resource::<FormationUI>()
-        .with_query(<Write<FormationUnit>>::query().filter(tag::<FormationUnitSelected>()))
-        .build_thread_local(|_, world, formation_ui, units| {
-            let formation_ui = unsafe { formation_ui.0.assume_safe() };
-            for mut unit in units.iter_mut(world) {
-                let unit = unsafe { unit.0.assume_safe() };
-                let rect = unit.get_rect();
-                let mouse_pos = formation_ui.get_local_mouse_position() - rect.size.to_vector() / 2.;
-                unit.set_position(mouse_pos, false);
+/// Keep the icons in line with the formation positions
+fn place_formation_units() -> Box<dyn Runnable> {
+    SystemBuilder::new("place formation units")
+        .read_resource::<FormationDrag>()
+        .with_query(<(Read<FormationPos>, Write<FormationUnit>)>::query())
+        .with_query(<Read<FormationUnit>>::query().filter(tag::<FormationUnitSelected>()))
+        .with_query(<Read<FormationUnit>>::query().filter(tag::<Selected>()))
+        .build_thread_local(|_, world, drag, (units, selected, in_world)| {
+            let selected = selected
+                .iter_entities(world)
+                .map(|(ent, _)| ent)
+                .collect::<Vec<_>>();
+            let in_world = in_world
+                .iter_entities(world)
+                .map(|(ent, _)| ent)
+                .collect::<Vec<_>>();
+
+            for (ent, (formation_pos, mut unit)) in units.iter_entities_mut(world) {
+                unit.set_highlight(selected.contains(&ent), !in_world.contains(&ent));
+
+                if !drag.is_dragging(ent) {
+                    unit.set_index(formation_pos.0);
+                }
+            }
+        })
+}
+
+/// Dead units leave their squad
+fn leave_formation_on_death() -> Box<dyn Runnable> {
+    SystemBuilder::new("leave formation on death")
+        .with_query(<(Read<Health>, Read<SquadId>)>::query().filter(!tag::<LeaveFormation>()))
+        .build_thread_local(|cmd, world, _, units| {
+            for (ent, (health, _)) in units.iter_entities(world) {
+                if health.current <= 0. {
+                    cmd.add_tag(ent, LeaveFormation);
+                }
             }
         })
 }
 
-fn done_moving() -> Box<dyn Runnable> {
-    SystemBuilder::new("done moving")
-        .write_resource::<Formation>()
-        .with_query(<Read<FormationUnit>>::query())
-        .with_query(<Read<FormationUnit>>::query().filter(tag::<FormationUnitMoved>()))
-        .build_thread_local(|cmd, world, formation, (units, done_moving_unit)| {
-            let entities = done_moving_unit
-                .iter_entities_mut(world)
+/// Units that are no longer selected in the world can't be edited in the
+/// formation UI
+fn deselect_formation_units() -> Box<dyn Runnable> {
+    SystemBuilder::new("deselect formation units")
+        .with_query(<Read<FormationUnit>>::query().filter(tag::<FormationUnitSelected>()))
+        .with_query(<Read<FormationUnit>>::query().filter(tag::<Selected>()))
+        .build_thread_local(|cmd, world, _, (formation_selected, selected)| {
+            let selected = selected
+                .iter_entities(world)
                 .map(|(ent, _)| ent)
                 .collect::<Vec<_>>();
 
-            if entities.len() == 0 {
-                return;
+            for (ent, _) in formation_selected.iter_entities(world) {
+                if !selected.contains(&ent) {
+                    cmd.remove_tag::<FormationUnitSelected>(ent);
+                }
             }
+        })
+}
 
-            for ent in &entities {
-                cmd.remove_tag::<FormationUnitMoved>(*ent);
+/// Units that left their squad lose their icon
+fn free_formation_units() -> Box<dyn Runnable> {
+    SystemBuilder::new("free formation units")
+        .with_query(<Write<FormationUnit>>::query().filter(!component::<SquadId>()))
+        .build_thread_local(|cmd, world, _, units| {
+            for (ent, mut unit) in units.iter_entities_mut(world) {
+                unit.free();
+                cmd.remove_component::<FormationUnit>(ent);
             }
+        })
+}
 
-            // Set the bits containing units
-            for (ent, unit) in units.iter_entities_mut(world) {
-                let unit = unsafe { unit.0.assume_safe() };
-                let unit_pos = unsafe { unit.position() } / 16.;
-                let index = pos_to_index(unit_pos) as u16;
-                formation.0.set_bit(index, true);
-                cmd.add_component(ent, FormationPos(index));
+/// Keeps the formation positions and the squad's `Formation` in sync as
+/// units join and leave. The icons follow in `place_formation_units`.
+/// Squads without units are deleted here, and only here.
+pub fn formation_membership() -> Box<dyn Runnable> {
+    SystemBuilder::new("formation membership")
+        .with_query(<Read<SquadId>>::query().filter(tag::<LeaveFormation>()))
+        .with_query(<Read<SquadId>>::query().filter(!component::<FormationPos>()))
+        .with_query(
+            <(Read<SquadId>, Write<FormationPos>)>::query().filter(!tag::<LeaveFormation>()),
+        )
+        .with_query(<Write<Formation>>::query())
+        .build_thread_local(|cmd, world, _, (leaving, joining, members, formations)| {
+            for (ent, _) in leaving.iter_entities(world) {
+                cmd.remove_component::<FormationPos>(ent);
+                cmd.remove_component::<SquadId>(ent);
+                cmd.remove_tag::<LeaveFormation>(ent);
+            }
+
+            let mut squads = HashMap::new();
+            for (ent, (squad_id, formation_pos)) in members.iter_entities_mut(world) {
+                squads
+                    .entry(squad_id.0)
+                    .or_insert_with(Vec::new)
+                    .push((ent, formation_pos.0));
+            }
+
+            let mut occupied = HashMap::new();
+            for (squad, formation) in formations.iter_entities_mut(world) {
+                let slots = squads.entry(squad).or_insert_with(Vec::new);
+
+                // Units that left (or were deleted) leave gaps behind
+                let vacated = formation.0 & !occupancy(slots);
+                if vacated != 0 {
+                    reflow(slots, vacated);
+                }
+                occupied.insert(squad, occupancy(slots));
+            }
+
+            for (ent, (squad_id, mut formation_pos)) in members.iter_entities_mut(world) {
+                let index = squads
+                    .get(&squad_id.0)
+                    .and_then(|slots| slots.iter().find(|(e, _)| *e == ent))
+                    .map(|(_, index)| *index);
+
+                match index {
+                    Some(index) if formation_pos.0 != index => formation_pos.0 = index,
+                    _ => {}
+                }
+            }
+
+            // New units take the first free slot in their squad
+            for (ent, squad_id) in joining.iter_entities(world) {
+                let squad_occupied = match occupied.get_mut(&squad_id.0) {
+                    Some(o) => o,
+                    None => continue,
+                };
+
+                match first_free(*squad_occupied) {
+                    Some(index) => {
+                        *squad_occupied |= 1 << index;
+                        cmd.add_component(ent, FormationPos(index));
+                    }
+                    // No room: the unit stays out of the squad and the selection
+                    None => {
+                        cmd.remove_component::<SquadId>(ent);
+                        cmd.remove_tag::<Selected>(ent);
+                    }
+                }
+            }
+
+            // Empty squads are disbanded
+            for (squad, mut formation) in formations.iter_entities_mut(world) {
+                match occupied.get(&squad) {
+                    Some(0) | None => cmd.delete(squad),
+                    Some(o) => formation.0 = *o,
+                }
             }
         })
 }
 
 pub fn formation_systems(builder: Builder) -> Builder {
     builder
+        .add_thread_local(leave_formation_on_death())
+        .add_thread_local(formation_membership())
+        .add_thread_local(free_formation_units())
+        .add_thread_local(deselect_formation_units())
+        .add_thread_local(formation_ui_rect())
         .add_thread_local(select_formation_unit())
+        .add_thread_local(place_formation_units())
         .add_thread_local(drag_formation_unit())
-        .add_thread_local(deselect_formation_unit())
-        .add_thread_local(done_moving())
+        .add_thread_local(drop_formation_unit())
+        .add_thread_local(formation_commands())
 }
 
 #[cfg(test)]
@@ -302,4 +857,185 @@ mod test {
         assert_eq!(row_to_index(first_row), vec![0, 1, 2, 3]);
         assert_eq!(row_to_index(second_row), vec![4, 5, 6, 7]);
     }
+
+    fn assert_invariants(slots: &[(char, u16)], formation: &Formation) {
+        for (i, (_, index)) in slots.iter().enumerate() {
+            assert!(slots[i + 1..].iter().all(|(_, other)| other != index));
+        }
+        assert_eq!(occupancy(slots), formation.0);
+        assert_eq!(formation.0.count_ones() as usize, slots.len());
+    }
+
+    #[test]
+    fn test_first_free() {
+        assert_eq!(first_free(0), Some(0));
+        assert_eq!(first_free(0b1011), Some(2));
+        assert_eq!(first_free(0b1111), Some(4));
+        assert_eq!(first_free(u16::MAX), None);
+    }
+
+    #[test]
+    fn test_reflow_promotes_rear_units() {
+        // Column 1: a (row 0), b (row 1), c (row 3)
+        let mut slots = vec![('a', 1), ('b', 5), ('c', 13), ('d', 2)];
+        slots.remove(0);
+
+        reflow(&mut slots, 1 << 1);
+
+        assert_eq!(slots, vec![('b', 1), ('c', 5), ('d', 2)]);
+    }
+
+    #[test]
+    fn test_reflow_keeps_existing_gaps() {
+        // A wedge: the empty slots next to the front unit are on purpose
+        let mut slots = vec![('a', 1), ('b', 4), ('c', 6), ('d', 3)];
+        slots.retain(|(c, _)| *c != 'd');
+
+        reflow(&mut slots, 1 << 3);
+
+        assert_eq!(slots, vec![('a', 1), ('b', 4), ('c', 6)]);
+    }
+
+    #[test]
+    fn test_move_units_swaps() {
+        let slots = vec![('a', 0), ('b', 1), ('c', 4)];
+
+        // a -> 1, b takes a's place
+        let layout = move_units(&slots, &['a'], (1, 0)).unwrap();
+        assert_eq!(layout, vec![('a', 1), ('b', 0), ('c', 4)]);
+
+        // a and b one row down, c swaps into the front row
+        let layout = move_units(&slots, &['a', 'b'], (0, 1)).unwrap();
+        assert_eq!(layout, vec![('a', 4), ('b', 5), ('c', 0)]);
+    }
+
+    #[test]
+    fn test_move_units_outside_grid() {
+        let slots = vec![('a', 0), ('b', 3)];
+
+        assert_eq!(move_units(&slots, &['a'], (-1, 0)), None);
+        assert_eq!(move_units(&slots, &['a', 'b'], (1, 0)), None);
+        assert_eq!(move_units(&slots, &['b'], (0, 4)), None);
+    }
+
+    #[test]
+    fn test_membership_invariants() {
+        let mut formation = Formation::new();
+        let mut slots: Vec<(char, u16)> = Vec::new();
+
+        // Join
+        for unit in "abcdef".chars() {
+            let index = first_free(occupancy(&slots)).unwrap();
+            slots.push((unit, index));
+            formation.0 = occupancy(&slots);
+            assert_invariants(&slots, &formation);
+        }
+        assert_eq!(slots.last(), Some(&('f', 5)));
+
+        // Leave
+        for unit in "bea".chars() {
+            slots.retain(|(c, _)| *c != unit);
+            let vacated = formation.0 & !occupancy(&slots);
+            reflow(&mut slots, vacated);
+            formation.0 = occupancy(&slots);
+            assert_invariants(&slots, &formation);
+        }
+
+        // The rear units moved up into the front row
+        assert_eq!(slots, vec![('c', 2), ('d', 3), ('f', 1)]);
+
+        // Join again, into the gap
+        let index = first_free(formation.0).unwrap();
+        slots.push(('g', index));
+        formation.0 = occupancy(&slots);
+        assert_invariants(&slots, &formation);
+        assert_eq!(index, 0);
+    }
+
+    fn run_membership(world: &mut World) {
+        let mut schedule = Schedule::builder()
+            .add_thread_local(leave_formation_on_death())
+            .add_thread_local(formation_membership())
+            .build();
+        schedule.execute(world, &mut Resources::default());
+    }
+
+    fn squad_slots(world: &World, squad: Entity) -> Vec<(Entity, u16)> {
+        let mut slots = <(Read<SquadId>, Read<FormationPos>)>::query()
+            .iter_entities(world)
+            .filter(|(_, (squad_id, _))| squad_id.0 == squad)
+            .map(|(ent, (_, formation_pos))| (ent, formation_pos.0))
+            .collect::<Vec<_>>();
+        slots.sort_by_key(|(_, index)| *index);
+        slots
+    }
+
+    fn kill(world: &mut World, unit: Entity) {
+        world.get_component_mut::<Health>(unit).unwrap().current = 0.;
+    }
+
+    #[test]
+    fn test_membership_system() {
+        let universe = Universe::new();
+        let mut world = universe.create_world();
+        let squad = world.insert((), vec![(Formation::new(),)])[0];
+        let units = world
+            .insert((), (0..5).map(|_| (SquadId(squad), Health::new(10.))))
+            .to_vec();
+
+        // Joining units fill the front row first
+        run_membership(&mut world);
+        let slots = squad_slots(&world, squad);
+        let indices = slots.iter().map(|(_, index)| *index).collect::<Vec<_>>();
+        assert_eq!(indices, vec![0, 1, 2, 3, 4]);
+        assert_eq!(world.get_component::<Formation>(squad).unwrap().0, occupancy(&slots));
+
+        // The unit behind a dead one moves up
+        let front = slots[0].0;
+        let behind = slots[4].0;
+        kill(&mut world, front);
+        run_membership(&mut world);
+        run_membership(&mut world);
+
+        let slots = squad_slots(&world, squad);
+        assert_eq!(slots.len(), 4);
+        assert!(slots.contains(&(behind, 0)));
+        assert_eq!(world.get_component::<Formation>(squad).unwrap().0, occupancy(&slots));
+        assert!(world.get_component::<SquadId>(front).is_none());
+        assert!(world.get_component::<FormationPos>(front).is_none());
+
+        // The squad is disbanded once everyone is gone
+        for unit in &units {
+            kill(&mut world, *unit);
+        }
+        run_membership(&mut world);
+        run_membership(&mut world);
+        assert!(world.get_component::<Formation>(squad).is_none());
+    }
+
+    #[test]
+    fn test_full_formation_refuses_joins() {
+        let universe = Universe::new();
+        let mut world = universe.create_world();
+        let squad = world.insert((), vec![(Formation::new(),)])[0];
+        let units = world
+            .insert(
+                (Selected,),
+                (0..17).map(|_| (SquadId(squad), Health::new(10.))),
+            )
+            .to_vec();
+
+        run_membership(&mut world);
+
+        assert_eq!(squad_slots(&world, squad).len(), 16);
+        assert_eq!(world.get_component::<Formation>(squad).unwrap().0, u16::MAX);
+
+        let left_out = units
+            .iter()
+            .filter(|unit| world.get_component::<SquadId>(**unit).is_none())
+            .collect::<Vec<_>>();
+        assert_eq!(left_out.len(), 1);
+        assert!(world.get_tag::<Selected>(*left_out[0]).is_none());
+        assert!(world.get_component::<FormationPos>(*left_out[0]).is_none());
+    }
 }
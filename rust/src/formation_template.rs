@@ -0,0 +1,358 @@
+use std::fs::File;
+use std::io::{Error, ErrorKind, Result};
+use std::path::PathBuf;
+
+use gdnative::api::OS;
+use legion::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::formation::{first_free, is_slot, occupancy, Formation, FormationPos, FormationUnit};
+use crate::squad::SquadId;
+use crate::unit::Role;
+
+const TEMPLATE_FILE: &str = "formation_templates.json";
+
+// Templates live in the user data dir, not next to the save games
+fn file_path() -> PathBuf {
+    let os = OS::godot_singleton();
+    let mut path = PathBuf::from(os.get_user_data_dir().to_string());
+    path.push(TEMPLATE_FILE);
+    path
+}
+
+// -----------------------------------------------------------------------------
+//     - Template -
+// -----------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct TemplateSlot {
+    pub index: u16,
+    pub role: Option<Role>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FormationTemplate {
+    pub name: String,
+    pub slots: Vec<TemplateSlot>,
+}
+
+impl FormationTemplate {
+    /// Create a template from `(role, formation index)` pairs.
+    /// Slots are stored in formation order.
+    pub fn new(name: String, units: &[(Role, u16)]) -> Self {
+        let mut slots = units
+            .iter()
+            .map(|(role, index)| TemplateSlot {
+                index: *index,
+                role: Some(*role),
+            })
+            .collect::<Vec<_>>();
+        slots.sort_by_key(|slot| slot.index);
+
+        Self { name, slots }
+    }
+
+    /// A single line of text that can be pasted to someone else
+    pub fn to_blob(&self) -> Result<String> {
+        serde_json::to_string(self).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+
+    /// Read a pasted template, the slots have to be in the formation and
+    /// can't be used twice
+    pub fn from_blob(blob: &str) -> Result<Self> {
+        let template: Self =
+            serde_json::from_str(blob.trim()).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+        let mut used = 0u16;
+        for slot in &template.slots {
+            if !is_slot(slot.index) {
+                let msg = format!("{} is not a formation slot", slot.index);
+                return Err(Error::new(ErrorKind::InvalidData, msg));
+            }
+            if used & 1 << slot.index != 0 {
+                let msg = format!("slot {} is used twice", slot.index);
+                return Err(Error::new(ErrorKind::InvalidData, msg));
+            }
+            used |= 1 << slot.index;
+        }
+
+        Ok(template)
+    }
+
+    /// Map units onto the template slots.
+    /// Units are given as `(key, role, current formation index)`.
+    ///
+    /// Slots with a role are filled first by the first unit (in current
+    /// formation order) with a matching role, the remaining slots are then
+    /// filled by the remaining units in order.
+    /// Units that don't fit in the template are not part of the result.
+    pub fn assign<T: Copy>(&self, units: &[(T, Role, u16)]) -> Vec<(T, u16)> {
+        let mut units = units.to_vec();
+        units.sort_by_key(|(_, _, index)| *index);
+
+        let mut taken = vec![false; units.len()];
+        let mut filled = vec![false; self.slots.len()];
+        let mut assigned = Vec::with_capacity(units.len());
+
+        // By role
+        for (slot_index, slot) in self.slots.iter().enumerate() {
+            let role = match slot.role {
+                Some(r) => r,
+                None => continue,
+            };
+
+            let unit_index = units
+                .iter()
+                .enumerate()
+                .position(|(i, (_, unit_role, _))| !taken[i] && *unit_role == role);
+
+            if let Some(i) = unit_index {
+                taken[i] = true;
+                filled[slot_index] = true;
+                assigned.push((units[i].0, slot.index));
+            }
+        }
+
+        // By order
+        let mut remaining = units
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !taken[*i])
+            .map(|(_, (key, _, _))| *key);
+
+        for (slot_index, slot) in self.slots.iter().enumerate() {
+            if filled[slot_index] {
+                continue;
+            }
+
+            match remaining.next() {
+                Some(key) => assigned.push((key, slot.index)),
+                None => break,
+            }
+        }
+
+        assigned
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Resources -
+// -----------------------------------------------------------------------------
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct FormationTemplates {
+    templates: Vec<FormationTemplate>,
+}
+
+impl FormationTemplates {
+    pub fn load() -> Self {
+        let file = match File::open(file_path()) {
+            Ok(f) => f,
+            Err(_) => return Self::default(),
+        };
+
+        match serde_json::from_reader(&file) {
+            Ok(templates) => templates,
+            Err(e) => {
+                eprintln!("Could not read formation templates: {:?}", e);
+                Self::default()
+            }
+        }
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let mut file = File::create(file_path())?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        Ok(())
+    }
+
+    /// Insert the template, replacing any template with the same name
+    pub fn insert(&mut self, template: FormationTemplate) {
+        self.remove(&template.name);
+        self.templates.push(template);
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.templates.retain(|t| t.name != name);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&FormationTemplate> {
+        self.templates.iter().find(|t| t.name == name)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.templates.iter().map(|t| t.name.as_str())
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - World -
+// -----------------------------------------------------------------------------
+
+/// Capture the current formation layout of a squad
+pub fn template_from_world(world: &World, squad: Entity, name: String) -> FormationTemplate {
+    let units = <(Read<Role>, Read<FormationPos>, Read<SquadId>)>::query()
+        .iter(world)
+        .filter(|(_, _, squad_id)| squad_id.0 == squad)
+        .map(|(role, formation_pos, _)| (*role, formation_pos.0))
+        .collect::<Vec<_>>();
+
+    FormationTemplate::new(name, &units)
+}
+
+/// Apply a template to the units of a squad and update its formation
+pub fn apply_template(world: &mut World, squad: Entity, template: &FormationTemplate) {
+    let units = <(Read<Role>, Read<FormationPos>, Read<SquadId>)>::query()
+        .iter_entities(world)
+        .filter(|(_, (_, _, squad_id))| squad_id.0 == squad)
+        .map(|(ent, (role, formation_pos, _))| (ent, *role, formation_pos.0))
+        .collect::<Vec<_>>();
+
+    let assigned = template.assign(&units);
+
+    // Units that didn't fit in the template keep their index, unless it's taken
+    let mut occupied = assigned.iter().fold(0u16, |acc, (_, index)| acc | 1 << index);
+    let mut slots = Vec::with_capacity(units.len());
+    for (ent, _, index) in &units {
+        let index = match assigned.iter().find(|(e, _)| e == ent) {
+            Some((_, index)) => *index,
+            None if occupied & 1 << index == 0 => *index,
+            None => match first_free(occupied) {
+                Some(index) => index,
+                None => continue,
+            },
+        };
+        occupied |= 1 << index;
+        slots.push((*ent, index));
+    }
+
+    for (ent, (mut formation_pos, mut formation_unit)) in
+        <(Write<FormationPos>, Write<FormationUnit>)>::query().iter_entities_mut(world)
+    {
+        if let Some((_, index)) = slots.iter().find(|(e, _)| *e == ent) {
+            formation_pos.0 = *index;
+            formation_unit.set_index(*index);
+        }
+    }
+
+    if let Some(mut formation) = world.get_component_mut::<Formation>(squad) {
+        formation.0 = occupancy(&slots);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn template(slots: &[(u16, Option<Role>)]) -> FormationTemplate {
+        FormationTemplate {
+            name: "test".to_string(),
+            slots: slots
+                .iter()
+                .map(|(index, role)| TemplateSlot {
+                    index: *index,
+                    role: *role,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn assign_by_role() {
+        let template = template(&[
+            (0, Some(Role::Medic)),
+            (1, Some(Role::Infantry)),
+            (2, Some(Role::Support)),
+        ]);
+        let units = [
+            (10, Role::Infantry, 0),
+            (11, Role::Support, 1),
+            (12, Role::Medic, 2),
+        ];
+
+        let mut assigned = template.assign(&units);
+        assigned.sort();
+
+        assert_eq!(assigned, vec![(10, 1), (11, 2), (12, 0)]);
+    }
+
+    #[test]
+    fn assign_remaining_by_order() {
+        let template = template(&[(0, None), (3, Some(Role::Medic)), (5, None)]);
+        // Given out of formation order, the medic takes its slot first
+        let units = [
+            (12, Role::Infantry, 4),
+            (10, Role::Medic, 2),
+            (11, Role::Infantry, 1),
+        ];
+
+        let mut assigned = template.assign(&units);
+        assigned.sort();
+
+        assert_eq!(assigned, vec![(10, 3), (11, 0), (12, 5)]);
+    }
+
+    #[test]
+    fn assign_missing_role_falls_back_to_order() {
+        let template = template(&[(0, Some(Role::Medic)), (1, Some(Role::Medic))]);
+        let units = [(10, Role::Infantry, 0), (11, Role::Medic, 1)];
+
+        let mut assigned = template.assign(&units);
+        assigned.sort();
+
+        assert_eq!(assigned, vec![(10, 1), (11, 0)]);
+    }
+
+    #[test]
+    fn assign_more_units_than_slots() {
+        let template = template(&[(2, None)]);
+        let units = [(10, Role::Infantry, 0), (11, Role::Infantry, 1)];
+
+        assert_eq!(template.assign(&units), vec![(10, 2)]);
+    }
+
+    #[test]
+    fn blob_round_trip() {
+        let template = FormationTemplate::new(
+            "wedge".to_string(),
+            &[(Role::Support, 4), (Role::Infantry, 0), (Role::Medic, 2)],
+        );
+
+        let blob = template.to_blob().unwrap();
+        assert!(!blob.contains('\n'));
+
+        let copy = FormationTemplate::from_blob(&format!("  {}\n", blob)).unwrap();
+        assert_eq!(copy.name, "wedge");
+        let slots = copy
+            .slots
+            .iter()
+            .map(|s| (s.index, s.role))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            slots,
+            vec![
+                (0, Some(Role::Infantry)),
+                (2, Some(Role::Medic)),
+                (4, Some(Role::Support))
+            ]
+        );
+    }
+
+    #[test]
+    fn bad_blob() {
+        assert!(FormationTemplate::from_blob("not a template").is_err());
+    }
+
+    #[test]
+    fn blob_slots_are_checked() {
+        let blob = |indices: &[u16]| {
+            let units = indices.iter().map(|i| (Role::Infantry, *i)).collect::<Vec<_>>();
+            FormationTemplate::new("test".to_string(), &units).to_blob().unwrap()
+        };
+
+        assert!(FormationTemplate::from_blob(&blob(&[0, 5, 15])).is_ok());
+
+        let err = FormationTemplate::from_blob(&blob(&[0, 16])).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(FormationTemplate::from_blob(&blob(&[200])).is_err());
+        assert!(FormationTemplate::from_blob(&blob(&[3, 3])).is_err());
+    }
+}
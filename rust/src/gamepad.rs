@@ -0,0 +1,185 @@
+use gdnative::Vector2;
+use legion::prelude::*;
+use legion::systems::schedule::Builder;
+
+use crate::camera::{Camera, CameraInput};
+use crate::gameworld::Delta;
+use crate::input::{MouseEvent, MousePos, LMB, RMB};
+use crate::settings::{GamepadSettings, Settings};
+
+pub const LEFT_X: i64 = 0;
+pub const LEFT_Y: i64 = 1;
+pub const RIGHT_X: i64 = 2;
+pub const RIGHT_Y: i64 = 3;
+
+pub const BUMPER_LEFT: i64 = 4;
+pub const BUMPER_RIGHT: i64 = 5;
+pub const TRIGGER_LEFT: i64 = 6;
+pub const TRIGGER_RIGHT: i64 = 7;
+
+/// Scale the stick so it goes from zero at the edge of the deadzone
+/// to one when it's all the way out
+pub fn apply_deadzone(stick: Vector2, deadzone: f32) -> Vector2 {
+    let length = stick.length();
+    if length <= deadzone {
+        return Vector2::zero();
+    }
+
+    let scaled = ((length - deadzone) / (1. - deadzone)).min(1.);
+    stick / length * scaled
+}
+
+/// Move the cursor by the stick and keep it on screen
+pub fn step_cursor(
+    cursor: Vector2,
+    stick: Vector2,
+    config: &GamepadSettings,
+    delta: f32,
+    screen: Vector2,
+) -> Vector2 {
+    let cursor = cursor + apply_deadzone(stick, config.deadzone) * config.cursor_speed * delta;
+    Vector2::new(cursor.x.max(0.).min(screen.x), cursor.y.max(0.).min(screen.y))
+}
+
+// -----------------------------------------------------------------------------
+//     - Resources -
+// -----------------------------------------------------------------------------
+/// The sticks and the virtual cursor.
+/// Touching the gamepad switches to it, moving the mouse switches back.
+pub struct Gamepad {
+    cursor: Option<Vector2>,
+    left: Vector2,
+    right: Vector2,
+}
+
+impl Gamepad {
+    pub fn new() -> Self {
+        Self {
+            cursor: None,
+            left: Vector2::zero(),
+            right: Vector2::zero(),
+        }
+    }
+
+    /// The virtual cursor, if the gamepad is in use
+    pub fn cursor(&self) -> Option<Vector2> {
+        self.cursor
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.cursor.is_some()
+    }
+
+    /// The cursor starts where the mouse was
+    pub fn activate(&mut self, mouse_pos: Vector2) {
+        if self.cursor.is_none() {
+            self.cursor = Some(mouse_pos);
+        }
+    }
+
+    pub fn deactivate(&mut self) {
+        *self = Self::new();
+    }
+
+    pub fn set_axis(&mut self, axis: i64, value: f32) {
+        match axis {
+            LEFT_X => self.left.x = value,
+            LEFT_Y => self.left.y = value,
+            RIGHT_X => self.right.x = value,
+            RIGHT_Y => self.right.y = value,
+            _ => {}
+        }
+    }
+
+    /// The triggers click like the mouse buttons, at the cursor
+    pub fn trigger_event(&self, button: i64, pressed: bool) -> Option<MouseEvent> {
+        let button = match button {
+            TRIGGER_LEFT => LMB,
+            TRIGGER_RIGHT => RMB,
+            _ => return None,
+        };
+
+        self.cursor.map(|cursor| MouseEvent::new(button, pressed, cursor))
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Systems -
+// -----------------------------------------------------------------------------
+fn gamepad_input() -> Box<dyn Runnable> {
+    SystemBuilder::new("gamepad input")
+        .read_resource::<Delta>()
+        .read_resource::<Settings>()
+        .read_resource::<Camera>()
+        .write_resource::<Gamepad>()
+        .write_resource::<MousePos>()
+        .write_resource::<CameraInput>()
+        .build_thread_local(|_, _, resources, _| {
+            let (delta, settings, camera, gamepad, mouse_pos, camera_input) = resources;
+            let config = &settings.gamepad;
+
+            let (cursor, size) = match (gamepad.cursor, camera.viewport_size()) {
+                (Some(cursor), Some(size)) => (cursor, size),
+                _ => {
+                    camera_input.pan = Vector2::zero();
+                    return;
+                }
+            };
+
+            // Up on the stick is negative
+            let pan = apply_deadzone(gamepad.left, config.deadzone);
+            camera_input.pan = Vector2::new(pan.x, -pan.y);
+
+            let cursor = step_cursor(cursor, gamepad.right, config, delta.0, size);
+            gamepad.cursor = Some(cursor);
+            mouse_pos.set_global(cursor);
+        })
+}
+
+pub fn gamepad_systems(builder: Builder) -> Builder {
+    builder.add_thread_local(gamepad_input())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn deadzone_is_rescaled() {
+        assert_eq!(apply_deadzone(Vector2::new(0.4, 0.), 0.5), Vector2::zero());
+        assert_eq!(apply_deadzone(Vector2::new(0.75, 0.), 0.5), Vector2::new(0.5, 0.));
+        assert_eq!(apply_deadzone(Vector2::new(0., -1.), 0.5), Vector2::new(0., -1.));
+    }
+
+    #[test]
+    fn cursor_stays_on_screen() {
+        let config = GamepadSettings {
+            deadzone: 0.,
+            cursor_speed: 100.,
+        };
+        let screen = Vector2::new(640., 480.);
+
+        let cursor = step_cursor(Vector2::new(10., 10.), Vector2::new(1., 0.), &config, 0.5, screen);
+        assert_eq!(cursor, Vector2::new(60., 10.));
+
+        let cursor = step_cursor(cursor, Vector2::new(-1., -1.), &config, 1., screen);
+        assert_eq!(cursor, Vector2::zero());
+    }
+
+    #[test]
+    fn triggers_need_the_cursor() {
+        let mut gamepad = Gamepad::new();
+        assert_eq!(gamepad.trigger_event(TRIGGER_LEFT, true), None);
+
+        gamepad.activate(Vector2::new(5., 5.));
+        gamepad.activate(Vector2::zero());
+        assert_eq!(
+            gamepad.trigger_event(TRIGGER_RIGHT, true),
+            Some(MouseEvent::new(RMB, true, Vector2::new(5., 5.)))
+        );
+        assert_eq!(gamepad.trigger_event(BUMPER_LEFT, true), None);
+
+        gamepad.deactivate();
+        assert!(!gamepad.is_active());
+    }
+}
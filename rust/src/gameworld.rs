@@ -1,32 +1,71 @@
-use gdextras::input::InputEventExt;
 use gdextras::node_ext::NodeExt;
 use gdnative::api::{
-    AnimationTree as GDAnimationTree, Area, Camera as GodotCamera, CanvasLayer, Control, GridMap,
-    InputEvent, InputEventKey, InputEventMouse, InputEventMouseButton, Label, MeshInstance, Node2D,
-    Performance, Spatial,
+    AnimationTree as GDAnimationTree, Area, Camera as GodotCamera, CanvasLayer, Control, Engine,
+    GridMap, InputEvent, InputEventJoypadButton, InputEventJoypadMotion, InputEventKey,
+    InputEventMouse, InputEventMouseButton, Label, MeshInstance, Node2D, Performance, Spatial,
 };
-use gdnative::{methods, Color, NativeClass, Ptr, Variant, Vector2, Vector3, GodotObject};
+use gdnative::{methods, Color, GodotObject, GodotString, NativeClass, Ptr, Variant, Vector2, Vector3};
 use lazy_static::lazy_static;
 use legion::prelude::*;
 use std::sync::Mutex;
 
+use crate::action_map::{Action, ActionMap, Binding, RebindError};
+use crate::assignment::AssignmentMode;
 use crate::animation::{animation_systems, Animation, AnimationTree};
-use crate::camera::{camera_systems, Camera, Drag, SelectionBox, UnitSelectionArea};
+use crate::clock::{Clock, GameTime, Tick, TICKS_PER_SECOND};
+use crate::camera::{
+    camera_systems, BookmarkCommand, Camera, CameraBookmarks, CameraBounds, CameraFocus, CameraInput,
+    CameraRig, SelectionBox, SelectionFocus, UnitSelectionArea,
+};
+use crate::control_group::{control_group_systems, ControlGroupCommand, ControlGroups};
 use crate::contextmenu::ContextMenuNode;
 use crate::debug::DebugDraw;
+use crate::dungeon::{self, DungeonConfig, Layout, SpawnKind, SpawnPoints};
 use crate::enemy::{enemy_systems, DetectionRange, Enemy};
-use crate::formation::{formation_systems, Formation, FormationPos, FormationUI, FormationUnit};
-use crate::input::{Keyboard, Keys, MouseButton, MousePos};
-use crate::movement::{movement_systems, Acceleration, Forces, MaxSpeed, Pos, Velocity};
-use crate::player::{player_systems, PlayerId};
+use crate::formation::{
+    formation_systems, FormationCommand, FormationCommands, FormationDrag, FormationHistory,
+    FormationPos, FormationUI, FormationUnit,
+};
+use crate::formation_template::{
+    apply_template, template_from_world, FormationTemplate, FormationTemplates,
+};
+use crate::gamepad::{gamepad_systems, Gamepad, BUMPER_LEFT, BUMPER_RIGHT};
+use crate::gesture::{gesture_systems, Gestures, UiRects};
+use crate::group::{group_systems, FormationMember};
+use crate::input::{Keyboard, Keys, MouseEvent, MouseInput, MousePos, MMB, WHEEL_DOWN, WHEEL_UP};
+use crate::movement::{
+    movement_systems, Acceleration, Destination, Forces, MaxSpeed, Pos, Velocity,
+};
+use crate::orders::{order_systems, OrderTarget, Orders};
+use crate::player::{formation_slots, player_systems, select_all, MoveOrder, PlayerId};
+use crate::presentation::{
+    presentation_systems, GamepadCursor, MovePreview, OverheadBars, SelectionRect, UnitOverlay,
+};
+use crate::procgen::WorldSeed;
+use crate::radialmenu::{radial_menu_systems, RadialMenu};
+use crate::replay::{self, world_checksum, Recording, Replay};
 use crate::saveload;
+use crate::settings::Settings;
 use crate::spawner;
-use crate::tilemap::{draw_tilemap, Coords, TileMap};
-use crate::unit::Unit;
+use crate::squad::{
+    self, create_squad, merge_selected_squads, split_selection, squad_from_selection,
+    squad_systems, ActiveSquad, Squad, SquadId,
+};
+use crate::tilemap::{draw_tilemap, ChunkPos, Chunks, NavGrid, TileMap};
+use crate::unit::{Health, Role, Unit};
 use crate::safe;
 
+// Where the top left corner of a dungeon goes on the GridMap
+const DUNGEON_ORIGIN: (i32, i32) = (0, 0);
+// Units are put down a little above the floor and fall onto it
+const SPAWN_HEIGHT: f32 = 0.4;
+
 fn setup_physics_schedule() -> Schedule {
-    let builder = Schedule::builder();
+    // Only the simulation goes here, it stops while the game is paused
+    // Orders from the input go first, so they're carried out on the next tick
+    let builder = order_systems(Schedule::builder());
+    let builder = enemy_systems(builder);
+    let builder = group_systems(builder);
     let builder = movement_systems(builder);
     let builder = animation_systems(builder);
     builder.build()
@@ -34,10 +73,17 @@ fn setup_physics_schedule() -> Schedule {
 
 fn setup_schedule() -> Schedule {
     let builder = Schedule::builder().add_thread_local(draw_tilemap());
-    let builder = enemy_systems(builder);
+    // The gamepad feeds the mouse input, so it goes first
+    let builder = gamepad_systems(builder);
+    let builder = gesture_systems(builder);
+    let builder = control_group_systems(builder);
+    let builder = squad_systems(builder);
+    // The formation editor is UI, it gets the mouse before the world does
+    let builder = formation_systems(builder);
     let builder = camera_systems(builder);
     let builder = player_systems(builder);
-    let builder = formation_systems(builder);
+    let builder = radial_menu_systems(builder);
+    let builder = presentation_systems(builder);
     builder.build()
 }
 
@@ -56,9 +102,49 @@ where
     let _ = WORLD.try_lock().map(|mut world| f(&mut world));
 }
 
+/// Reload the scene with an empty world
+fn restart(owner: &Spatial) {
+    with_world(|world| *world = Universe::new().create_world());
+    if let Some(tree) = owner.get_tree() {
+        let _ = unsafe { tree.assume_safe() }.reload_current_scene();
+    }
+}
+
+/// Move the player's units to `spawn` in their formation, dropping their orders.
+/// A unit whose slot is in a wall goes on the spawn point itself.
+fn place_player_units(world: &mut World, nav: &NavGrid, spawn: Vector3) {
+    let units = <(Read<FormationPos>, Read<SquadId>)>::query()
+        .filter(tag::<PlayerId>())
+        .iter_entities(world)
+        .map(|(ent, (formation_pos, squad_id))| (ent, (squad_id.0, formation_pos.0)))
+        .collect::<Vec<_>>();
+
+    let indices = units.iter().map(|(_, index)| *index).collect::<Vec<_>>();
+    let slots = formation_slots(&indices, spawn, Vector2::zero(), None);
+
+    for ((ent, _), slot) in units.into_iter().zip(slots) {
+        let (x, z) = nav.cell(slot);
+        let pos = match nav.is_walkable(x, z) {
+            true => slot,
+            false => spawn,
+        };
+        let pos = Vector3::new(pos.x, SPAWN_HEIGHT, pos.z);
+
+        world.get_component::<Unit>(ent).map(|unit| unit.set_translation(pos));
+        world.get_component_mut::<Pos>(ent).map(|mut p| p.0 = pos);
+        world.get_component_mut::<Velocity>(ent).map(|mut v| v.0 = Vector3::zero());
+        world.get_component_mut::<Animation>(ent).map(|mut a| *a = Animation::Idle);
+        let _ = world.remove_component::<Destination>(ent);
+        let _ = world.remove_component::<FormationMember>(ent);
+        let _ = world.remove_component::<OrderTarget>(ent);
+    }
+}
+
 // -----------------------------------------------------------------------------
 //     - Resources -
 // -----------------------------------------------------------------------------
+/// Time since the last rendered frame, for the camera and other things that
+/// aren't part of the simulation
 pub struct Delta(pub f32);
 pub struct ClickIndicator(pub Ptr<MeshInstance>);
 
@@ -97,6 +183,7 @@ pub struct GameWorld {
     resources: Resources,
     physics: Schedule,
     process: Schedule,
+    clock: Clock,
 }
 
 #[methods]
@@ -106,12 +193,38 @@ impl GameWorld {
         let process = setup_schedule();
         let mut resources = Resources::default();
         resources.insert(Delta(0.));
-        resources.insert(MouseButton::Empty);
+        resources.insert(Tick(0));
+        resources.insert(GameTime::new());
+        resources.insert(MouseInput::new());
         resources.insert(MousePos::zero());
-        resources.insert(Coords::new());
+        resources.insert(Chunks::new(ChunkPos::new(-2, -2), ChunkPos::new(5, 5)));
+        resources.insert(NavGrid::new());
+        resources.insert(SpawnPoints(Vec::new()));
         resources.insert(Keyboard::new());
-        resources.insert(Drag::Empty);
-        resources.insert(Formation::new());
+        resources.insert(Gestures::new());
+        resources.insert(Gamepad::new());
+        resources.insert(UiRects::new());
+        resources.insert(SelectionBox(None));
+        resources.insert(ActiveSquad(None));
+        resources.insert(FormationDrag::Empty);
+        resources.insert(FormationHistory::new());
+        resources.insert(FormationCommands::new());
+        resources.insert(ControlGroups::new());
+        resources.insert(CameraFocus(None));
+        resources.insert(CameraBounds::new());
+        resources.insert(CameraBookmarks::new());
+        resources.insert(SelectionFocus::Off);
+        resources.insert(CameraInput::new());
+        let settings = Settings::load();
+        resources.insert(settings.orders.assignment);
+        resources.insert(settings);
+        // A new world every game, loading a save or a replay brings the old one back
+        resources.insert(WorldSeed::from_time());
+        resources.insert(Replay::new());
+        resources.insert(FormationTemplates::load());
+        resources.insert(MoveOrder::new());
+        resources.insert(Orders::new());
+        resources.insert(OverheadBars::Selected);
         resources.insert(DebugLines::new());
         resources.insert(ClickedState { clicked: false });
 
@@ -119,30 +232,59 @@ impl GameWorld {
             resources,
             physics,
             process,
+            clock: Clock::new(),
         }
     }
 
     #[export]
     pub fn _ready(&mut self, owner: &Spatial) {
+        // Recordings and replays start with a scene reload, in the same world
+        if let Some(replay) = replay::take_pending() {
+            if let Some(seed) = replay.seed() {
+                self.resources.insert(WorldSeed(seed));
+            }
+            self.resources.insert(replay);
+        }
+
+        // `move_and_slide` steps by the physics frame, keep it in line with the ticks
+        Engine::godot_singleton().set_iterations_per_second(TICKS_PER_SECOND as i64);
+
+        let action_map = ActionMap::load();
+        action_map.register();
+        self.resources.insert(action_map);
+
         let click_indicator = owner.get_and_cast::<MeshInstance>("ClickIndicator");
         self.resources
             .insert(ClickIndicator(click_indicator.claim()));
 
+        let move_preview = owner.get_and_cast::<Spatial>("MovePreview");
+        self.resources.insert(MovePreview::new(move_preview.claim()));
+
+        let selection_rect = owner.get_and_cast::<Control>("UI/SelectionRect");
+        self.resources.insert(SelectionRect(selection_rect.claim()));
+
+        let gamepad_cursor = owner.get_and_cast::<Control>("UI/GamepadCursor");
+        self.resources.insert(GamepadCursor(gamepad_cursor.claim()));
+
         // Tilemap
         let gridmap = owner.get_and_cast::<GridMap>("GridMap");
+        // The terrain is streamed in around the camera
+        gridmap.clear();
+        self.resources
+            .get_mut::<NavGrid>()
+            .map(|mut nav| nav.set_cell_size(gridmap.cell_size()));
         self.resources.insert(TileMap(gridmap.claim()));
 
         // Camera
-        let camera = owner.get_and_cast::<GodotCamera>("Camera");
-        self.resources.insert(Camera(camera.claim()));
+        let camera = Camera(owner.get_and_cast::<GodotCamera>("Camera").claim());
+        let rig = CameraRig::from_camera(&camera).expect("the camera should face the ground");
+        self.resources.insert(camera);
+        self.resources.insert(rig);
 
         // Unit selection area (detect mouse selection)
         let unit_selection_area = owner.get_and_cast::<Area>("UnitSelectionArea");
         self.resources.insert(UnitSelectionArea(unit_selection_area.claim()));
 
-        // Draw selection node
-        let selection_box = owner.get_and_cast::<MeshInstance>("SelectionBox");
-        self.resources.insert(SelectionBox(selection_box.claim()));
 
         // Formation UI
         let formation_ui = spawner::spawn_formation_ui();
@@ -151,6 +293,11 @@ impl GameWorld {
         ui.add_child(Some(formation_ui.to_node()), false);
         self.resources.insert(FormationUI::new(formation_ui.claim()));
 
+        // Radial menu
+        let radial_menu = spawner::spawn_radial_menu();
+        ui.add_child(Some(unsafe { radial_menu.assume_safe() }.to_node()), false);
+        self.resources.insert(RadialMenu::new(radial_menu));
+
         let colors = [
             Color::rgb(1., 0., 0.),
             Color::rgb(0., 1., 0.),
@@ -158,6 +305,12 @@ impl GameWorld {
             Color::rgb(1., 1., 0.),
         ];
 
+        let roles = [Role::Infantry, Role::Infantry, Role::Support, Role::Medic];
+
+        let mut squad = None;
+        with_world(|world| squad = Some(create_squad(world, "Alpha".to_string())));
+        let squad = squad.expect("the world is locked");
+
         // Player unit
         for i in 0..4 {
             let color_index = i;
@@ -189,6 +342,9 @@ impl GameWorld {
             unit.set_translation(Vector3::new(x, y, z));
             unit.add_child(Some(context_menu.to_node()), false);
 
+            let overlay = spawner::spawn_unit_overlay();
+            unit.add_child(Some(unsafe { overlay.assume_safe() }.to_node()), false);
+
             let pos = unsafe { unit.translation() };
 
             let anim_tree = unit.get_and_cast::<GDAnimationTree>("AnimationTree");
@@ -215,9 +371,13 @@ impl GameWorld {
                         Acceleration(Vector3::zero()),
                         formation_unit,
                         formation_pos,
+                        roles[i],
+                        SquadId(squad),
                         AnimationTree::new(anim_tree.claim()),
                         Animation::Idle,
                         ContextMenuNode(context_menu.claim()),
+                        UnitOverlay::new(overlay),
+                        Health::new(100.),
                     )),
                 );
             });
@@ -264,72 +424,291 @@ impl GameWorld {
             .try_to_object::<InputEvent>()
             .expect("I expect this to be an input event");
 
-        if event.action_pressed("ui_cancel") {
-            owner
-                .get_tree()
-                .map(|tree| unsafe { tree.assume_safe() }.quit(0));
-        }
+        // Keyboard
+        if let Some(key_event) = event.clone().cast::<InputEventKey>() {
+            let actions = match self.resources.get::<ActionMap>() {
+                Some(_) if key_event.is_echo() => Vec::new(),
+                Some(action_map) if key_event.is_pressed() => {
+                    action_map.pressed(Binding::from_event(&key_event))
+                }
+                Some(action_map) => action_map.released(key_event.scancode()),
+                None => Vec::new(),
+            };
 
-        if event.action_pressed("save") {
-            if let Err(e) = saveload::save(0) {
-                eprintln!("{:?}", e);
+            for action in actions {
+                self.handle_action(owner, action, key_event.is_pressed());
             }
         }
 
         // Mouse button
         if let Some(btn_event) = event.clone().cast::<InputEventMouseButton>() {
-            self.resources.get_mut::<MouseButton>().map(|mut btn| {
-                *btn = MouseButton::from_event(btn_event);
-            });
+            match btn_event.button_index() {
+                WHEEL_UP | WHEEL_DOWN if btn_event.is_pressed() => {
+                    let steps = match btn_event.button_index() {
+                        WHEEL_UP => 1.,
+                        _ => -1.,
+                    };
+                    self.resources.get_mut::<CameraInput>().map(|mut input| input.zoom += steps);
+                }
+                WHEEL_UP | WHEEL_DOWN => {}
+                MMB => {
+                    let middle = match btn_event.is_pressed() {
+                        true => Some(btn_event.shift()),
+                        false => None,
+                    };
+                    self.resources.get_mut::<CameraInput>().map(|mut input| input.middle = middle);
+                }
+                _ => {
+                    self.resources
+                        .get_mut::<MouseInput>()
+                        .map(|mut input| input.push(MouseEvent::from_event(btn_event)));
+                }
+            }
+        }
+
+        // Gamepad
+        if let Some(motion_event) = event.clone().cast::<InputEventJoypadMotion>() {
+            let value = motion_event.axis_value() as f32;
+            let deadzone = self.resources.get::<Settings>().map(|s| s.gamepad.deadzone);
+            // Ignore drift when the stick is at rest
+            if value.abs() > deadzone.unwrap_or(0.) {
+                self.activate_gamepad();
+            }
+            self.resources
+                .get_mut::<Gamepad>()
+                .map(|mut gamepad| gamepad.set_axis(motion_event.axis(), value));
+        }
+
+        if let Some(btn_event) = event.clone().cast::<InputEventJoypadButton>() {
+            self.activate_gamepad();
+            self.gamepad_button(btn_event.button_index(), btn_event.is_pressed());
         }
 
         // Mouse pos
         if let Some(mouse_event) = event.clone().cast::<InputEventMouse>() {
+            self.resources.get_mut::<Gamepad>().map(|mut gamepad| gamepad.deactivate());
             self.resources.get_mut::<MousePos>().map(|mut pos| {
                 pos.set_global(mouse_event.global_position());
             });
         }
+    }
 
-        // Keyboard
-        if let Some(_) = event.clone().cast::<InputEventKey>() {
-            self.resources.get_mut::<Keyboard>().map(|mut key| {
-                if event.is_action_pressed("Left".into(), false) {
-                    key.update(Keys::LEFT, true);
-                } else if event.is_action_released("Left".into()) {
-                    key.update(Keys::LEFT, false);
-                }
+    fn activate_gamepad(&mut self) {
+        let mouse_pos = self.resources.get::<MousePos>().map(|pos| pos.global());
+        self.resources
+            .get_mut::<Gamepad>()
+            .map(|mut gamepad| gamepad.activate(mouse_pos.unwrap_or(Vector2::zero())));
+    }
 
-                if event.is_action_pressed("Right".into(), false) {
-                    key.update(Keys::RIGHT, true);
-                } else if event.is_action_released("Right".into()) {
-                    key.update(Keys::RIGHT, false);
-                }
+    /// Triggers go through the mouse input, bumpers cycle the control groups
+    fn gamepad_button(&mut self, button: i64, pressed: bool) {
+        let event = self
+            .resources
+            .get::<Gamepad>()
+            .and_then(|gamepad| gamepad.trigger_event(button, pressed));
+        if let Some(event) = event {
+            self.resources.get_mut::<MouseInput>().map(|mut input| input.push(event));
+            return;
+        }
+
+        if !pressed {
+            return;
+        }
+
+        let step = match button {
+            BUMPER_LEFT => -1,
+            BUMPER_RIGHT => 1,
+            _ => return,
+        };
+        self.resources
+            .get_mut::<ControlGroups>()
+            .map(|mut groups| groups.push(ControlGroupCommand::Cycle(step)));
+    }
+
+    fn handle_action(&mut self, owner: &Spatial, action: Action, pressed: bool) {
+        // Held actions
+        let key = match action {
+            Action::CameraLeft => Some(Keys::LEFT),
+            Action::CameraRight => Some(Keys::RIGHT),
+            Action::CameraForward => Some(Keys::UP),
+            Action::CameraBack => Some(Keys::DOWN),
+            Action::CameraRotateLeft => Some(Keys::ROTATE_LEFT),
+            Action::CameraRotateRight => Some(Keys::ROTATE_RIGHT),
+            _ => None,
+        };
+
+        if let Some(key) = key {
+            self.resources.get_mut::<Keyboard>().map(|mut keyboard| keyboard.update(key, pressed));
+            return;
+        }
+
+        if !pressed {
+            return;
+        }
 
-                if event.is_action_pressed("Up".into(), false) {
-                    key.update(Keys::UP, true);
-                } else if event.is_action_released("Up".into()) {
-                    key.update(Keys::UP, false);
+        match action {
+            Action::Quit => {
+                owner
+                    .get_tree()
+                    .map(|tree| unsafe { tree.assume_safe() }.quit(0));
+            }
+            Action::Save => {
+                let seed = self.resources.get::<WorldSeed>().map(|seed| *seed).unwrap_or(WorldSeed(0));
+                let control_groups = self.resources.get::<ControlGroups>();
+                let bookmarks = self.resources.get::<CameraBookmarks>();
+                if let (Some(control_groups), Some(bookmarks)) = (control_groups, bookmarks) {
+                    if let Err(e) = saveload::save(0, seed, &control_groups, &bookmarks) {
+                        eprintln!("{:?}", e);
+                    }
+                }
+            }
+            Action::Load => {
+                let save_data = match saveload::load(0) {
+                    Ok(s) => s,
+                    Err(e) => {
+                        eprintln!("{:?}", e);
+                        return;
+                    }
+                };
+
+                let seed = WorldSeed(save_data.seed);
+                let reseeded = self.resources.get_mut::<WorldSeed>().map(|mut current| {
+                    let changed = *current != seed;
+                    *current = seed;
+                    changed
+                });
+                if reseeded == Some(true) {
+                    self.resources.get_mut::<Chunks>().map(|mut chunks| chunks.redraw());
+                    self.resources.get_mut::<CameraBounds>().map(|mut bounds| bounds.invalidate());
                 }
 
-                if event.is_action_pressed("Down".into(), false) {
-                    key.update(Keys::DOWN, true);
-                } else if event.is_action_released("Down".into()) {
-                    key.update(Keys::DOWN, false);
+                self.resources.get_mut::<ControlGroups>().map(|mut control_groups| {
+                    with_world(|world| control_groups.restore(world, &save_data.control_groups));
+                });
+                with_world(|world| squad::restore(world, &save_data.squads));
+                self.resources
+                    .get_mut::<CameraBookmarks>()
+                    .map(|mut bookmarks| bookmarks.restore(&save_data.camera_bookmarks));
+            }
+            Action::CameraFollow => {
+                self.resources.get_mut::<SelectionFocus>().map(|mut focus| focus.toggle_follow());
+            }
+            Action::CameraFocus => {
+                self.resources.get_mut::<SelectionFocus>().map(|mut focus| focus.focus());
+            }
+            Action::BookmarkStore(index) | Action::BookmarkRecall(index) => {
+                let index = index as usize;
+                let command = match action {
+                    Action::BookmarkStore(_) => BookmarkCommand::Store(index),
+                    _ => BookmarkCommand::Recall(index),
+                };
+                self.resources
+                    .get_mut::<CameraBookmarks>()
+                    .map(|mut bookmarks| bookmarks.push(command));
+            }
+            Action::SelectAll => with_world(select_all),
+            Action::ControlGroupAssign(index)
+            | Action::ControlGroupAdd(index)
+            | Action::ControlGroupRecall(index) => {
+                let index = index as usize;
+                let command = match action {
+                    Action::ControlGroupAssign(_) => ControlGroupCommand::Assign(index),
+                    Action::ControlGroupAdd(_) => ControlGroupCommand::Add(index),
+                    _ => ControlGroupCommand::Recall(index),
+                };
+                self.resources.get_mut::<ControlGroups>().map(|mut groups| groups.push(command));
+            }
+            Action::March => {
+                let active_squad = self.resources.get::<ActiveSquad>().and_then(|a| a.0);
+                with_world(|world| {
+                    let squad =
+                        active_squad.and_then(|squad| world.get_component_mut::<Squad>(squad));
+                    if let Some(mut squad) = squad {
+                        squad.march = !squad.march;
+                    }
+                });
+            }
+            Action::SquadCreate => with_world(|world| {
+                squad_from_selection(world);
+            }),
+            Action::SquadSplit => with_world(|world| {
+                split_selection(world);
+            }),
+            Action::SquadMerge => with_world(|world| {
+                merge_selected_squads(world);
+            }),
+            Action::FormationUndo
+            | Action::FormationRedo
+            | Action::FormationLeft
+            | Action::FormationRight
+            | Action::FormationUp
+            | Action::FormationDown => {
+                let command = match action {
+                    Action::FormationUndo => FormationCommand::Undo,
+                    Action::FormationRedo => FormationCommand::Redo,
+                    Action::FormationLeft => FormationCommand::Nudge(-1, 0),
+                    Action::FormationRight => FormationCommand::Nudge(1, 0),
+                    Action::FormationUp => FormationCommand::Nudge(0, -1),
+                    _ => FormationCommand::Nudge(0, 1),
+                };
+                self.resources
+                    .get_mut::<FormationCommands>()
+                    .map(|mut commands| commands.push(command));
+            }
+            Action::TimePause
+            | Action::TimeStep
+            | Action::TimeFaster
+            | Action::TimeSlower => {
+                self.resources.get_mut::<GameTime>().map(|mut time| match action {
+                    Action::TimePause => time.toggle_pause(),
+                    Action::TimeStep => time.step(),
+                    Action::TimeFaster => time.faster(),
+                    _ => time.slower(),
+                });
+            }
+            Action::OverheadBars => {
+                self.resources.get_mut::<OverheadBars>().map(|mut bars| *bars = bars.next());
+            }
+            Action::AssignmentMode => {
+                let mode = self.resources.get_mut::<AssignmentMode>().map(|mut mode| {
+                    *mode = mode.next();
+                    *mode
+                });
+                if let Some(mode) = mode {
+                    self.update_settings(|settings| settings.orders.assignment = mode);
                 }
-            });
+            }
+            // Held actions are handled above
+            Action::CameraLeft
+            | Action::CameraRight
+            | Action::CameraForward
+            | Action::CameraBack
+            | Action::CameraRotateLeft
+            | Action::CameraRotateRight => {}
         }
     }
 
     #[export]
-    pub fn _process(&mut self, owner: &Spatial, _: f64) {
+    pub fn _process(&mut self, owner: &Spatial, delta: f64) {
+        self.resources
+            .get_mut::<Delta>()
+            .map(|mut d| d.0 = delta as f32);
         with_world(|world| {
             self.process.execute(world, &mut self.resources);
         });
+        self.resources.get_mut::<MouseInput>().map(|mut input| input.end_frame());
 
         // Debug label
         let label = owner.get_and_cast::<Label>("UI/Panel/DebugLabel");
         let perf = Performance::godot_singleton();
-        let fps = format!("fps: {}", perf.get_monitor(Performance::TIME_FPS));
+        let mut fps = format!("fps: {}", perf.get_monitor(Performance::TIME_FPS));
+        self.resources.get::<GameTime>().map(|time| match time.is_paused() {
+            true => fps.push_str("\npaused"),
+            false => fps.push_str(&format!("\nspeed: {}x", time.scale())),
+        });
+        self.resources
+            .get::<AssignmentMode>()
+            .map(|mode| fps.push_str(&format!("\nslots: {:?}", *mode)));
         label.set_text(fps.into());
 
         self.resources.get_mut::<DebugLines>().map(|mut lines| {
@@ -343,15 +722,252 @@ impl GameWorld {
     }
 
     #[export]
-    pub fn _physics_process(&mut self, _: &Spatial, delta: f64) {
+    pub fn _physics_process(&mut self, _owner: &Spatial, delta: f64) {
+        // The simulation steps in fixed ticks, however long the frame was
+        let clock = &mut self.clock;
+        let ticks = self
+            .resources
+            .get_mut::<GameTime>()
+            .map(|mut time| time.ticks(clock, delta))
+            .unwrap_or(0);
+        for _ in 0..ticks {
+            with_world(|world| {
+                self.physics.execute(world, &mut self.resources);
+            });
+            self.resources.get_mut::<Tick>().map(|mut tick| tick.0 += 1);
+            self.end_tick();
+        }
+    }
+
+    fn end_tick(&mut self) {
+        // Replay
+        let recording = self.resources.get::<Replay>().map(|replay| !replay.is_idle());
+        if recording.unwrap_or(false) {
+            let mut checksum = 0;
+            with_world(|world| checksum = world_checksum(world));
+            self.resources.get_mut::<Replay>().map(|mut replay| replay.end_tick(checksum));
+        }
+    }
+
+    // -------------------------------------------------------------------------
+    //     - Formation templates -
+    // -------------------------------------------------------------------------
+
+    #[export]
+    pub fn save_formation_template(&mut self, _owner: &Spatial, name: GodotString) {
+        let squad = match self.resources.get::<ActiveSquad>().and_then(|a| a.0) {
+            Some(s) => s,
+            None => return,
+        };
+
+        let mut template = None;
+        with_world(|world| template = Some(template_from_world(world, squad, name.to_string())));
+
+        let template = match template {
+            Some(t) => t,
+            None => return,
+        };
+
+        self.resources.get_mut::<FormationTemplates>().map(|mut templates| {
+            templates.insert(template);
+            if let Err(e) = templates.save() {
+                eprintln!("{:?}", e);
+            }
+        });
+    }
+
+    #[export]
+    pub fn recall_formation_template(&mut self, _owner: &Spatial, name: GodotString) {
+        let template = match self.resources.get::<FormationTemplates>() {
+            Some(templates) => templates.get(&name.to_string()).cloned(),
+            None => None,
+        };
+
+        let template = match template {
+            Some(t) => t,
+            None => return,
+        };
+
+        if let Some(squad) = self.resources.get::<ActiveSquad>().and_then(|a| a.0) {
+            with_world(|world| apply_template(world, squad, &template));
+        }
+    }
+
+    #[export]
+    pub fn export_formation_template(&mut self, _owner: &Spatial, name: GodotString) -> GodotString {
         self.resources
-            .get_mut::<Delta>()
-            .map(|mut d| d.0 = delta as f32);
-        with_world(|world| {
-            self.physics.execute(world, &mut self.resources);
+            .get::<FormationTemplates>()
+            .and_then(|templates| templates.get(&name.to_string()).map(|t| t.to_blob()))
+            .and_then(|blob| match blob {
+                Ok(blob) => Some(blob),
+                Err(e) => {
+                    eprintln!("{:?}", e);
+                    None
+                }
+            })
+            .unwrap_or_default()
+            .into()
+    }
+
+    #[export]
+    pub fn import_formation_template(&mut self, _owner: &Spatial, blob: GodotString) -> bool {
+        let template = match FormationTemplate::from_blob(&blob.to_string()) {
+            Ok(t) => t,
+            Err(e) => {
+                eprintln!("{:?}", e);
+                return false;
+            }
+        };
+
+        match self.resources.get_mut::<FormationTemplates>() {
+            Some(mut templates) => {
+                templates.insert(template);
+                templates.save().is_ok()
+            }
+            None => false,
+        }
+    }
+
+    // -------------------------------------------------------------------------
+    //     - Settings -
+    // -------------------------------------------------------------------------
+
+    #[export]
+    pub fn set_edge_scroll(&mut self, _owner: &Spatial, enabled: bool) {
+        self.update_settings(|settings| settings.camera.edge_scroll = enabled);
+    }
+
+    #[export]
+    pub fn set_grab_pan(&mut self, _owner: &Spatial, enabled: bool) {
+        self.update_settings(|settings| settings.camera.grab_pan = enabled);
+    }
+
+    /// Returns the name of the action already using the binding,
+    /// or an empty string if the action was rebound
+    #[export]
+    pub fn rebind_action(
+        &mut self,
+        _owner: &Spatial,
+        name: GodotString,
+        scancode: i64,
+        control: bool,
+        shift: bool,
+        alt: bool,
+    ) -> GodotString {
+        let mut action_map = match self.resources.get_mut::<ActionMap>() {
+            Some(a) => a,
+            None => return GodotString::new(),
+        };
+
+        let action = match action_map.action_by_name(&name.to_string()) {
+            Some(a) => a,
+            None => return GodotString::new(),
+        };
+
+        let binding = Binding { scancode, control, shift, alt };
+        match action_map.rebind(action, binding) {
+            Ok(()) => {
+                action_map.register();
+                if let Err(e) = action_map.save() {
+                    eprintln!("{:?}", e);
+                }
+                GodotString::new()
+            }
+            Err(RebindError::Conflict(other)) => other.name().into(),
+        }
+    }
+
+    fn update_settings<F>(&mut self, f: F)
+    where
+        F: FnOnce(&mut Settings),
+    {
+        self.resources.get_mut::<Settings>().map(|mut settings| {
+            f(&mut settings);
+            if let Err(e) = settings.save() {
+                eprintln!("{:?}", e);
+            }
         });
     }
 
+    // -------------------------------------------------------------------------
+    //     - Dungeons -
+    // -------------------------------------------------------------------------
+
+    /// Replace the terrain with an interior, rooms and corridors or caves
+    #[export]
+    pub fn generate_dungeon(&mut self, _owner: &Spatial, caves: bool) {
+        let seed = self.resources.get::<WorldSeed>().map(|seed| seed.0).unwrap_or(0);
+        let config = DungeonConfig::default();
+        let layout = match caves {
+            true => dungeon::caves(seed, &config),
+            false => dungeon::rooms_and_corridors(seed, &config),
+        };
+
+        // The dungeon is the whole map, nothing else is streamed in
+        self.resources.get_mut::<Chunks>().map(|mut chunks| chunks.stop_streaming());
+
+        let cell_size = match self.resources.get::<TileMap>() {
+            Some(tilemap) => {
+                let mut nav = self.resources.get_mut::<NavGrid>().expect("the nav grid is a resource");
+                let gridmap = unsafe { tilemap.0.assume_safe() };
+                layout.write(&gridmap, &mut nav, DUNGEON_ORIGIN);
+                tilemap.cell_size()
+            }
+            None => return,
+        };
+        self.resources.get_mut::<CameraBounds>().map(|mut bounds| bounds.invalidate());
+
+        let spawns = layout
+            .spawns
+            .iter()
+            .map(|spawn| (spawn.kind, Layout::world_pos(spawn.cell, DUNGEON_ORIGIN, cell_size)))
+            .collect::<Vec<_>>();
+
+        let player_spawn = spawns.iter().find(|(kind, _)| *kind == SpawnKind::PlayerSquad);
+        if let Some((_, pos)) = player_spawn {
+            self.resources.get_mut::<CameraFocus>().map(|mut focus| focus.0 = Some(*pos));
+            if let Some(nav) = self.resources.get::<NavGrid>() {
+                with_world(|world| place_player_units(world, &nav, *pos));
+            }
+        }
+
+        self.resources.insert(SpawnPoints(spawns));
+    }
+
+    // -------------------------------------------------------------------------
+    //     - Replays -
+    // -------------------------------------------------------------------------
+
+    /// Restart and record everything from the first tick
+    #[export]
+    pub fn start_recording(&mut self, owner: &Spatial) {
+        let seed = self.resources.get::<WorldSeed>().map(|seed| seed.0).unwrap_or(0);
+        replay::set_pending(Replay::recording(seed));
+        restart(owner);
+    }
+
+    #[export]
+    pub fn stop_recording(&mut self, _owner: &Spatial, name: GodotString) {
+        let recording = self.resources.get_mut::<Replay>().and_then(|mut replay| replay.stop());
+        if let Some(recording) = recording {
+            if let Err(e) = recording.save(&name.to_string()) {
+                eprintln!("{:?}", e);
+            }
+        }
+    }
+
+    /// Restart and play back a recording, desyncs are printed
+    #[export]
+    pub fn play_recording(&mut self, owner: &Spatial, name: GodotString) {
+        match Recording::load(&name.to_string()) {
+            Ok(recording) => {
+                replay::set_pending(Replay::playing(recording));
+                restart(owner);
+            }
+            Err(e) => eprintln!("{:?}", e),
+        }
+    }
+
     // TODO: delete this function (it's in the name)
     pub fn delete_me(&mut self) {
         self.resources
@@ -0,0 +1,479 @@
+use std::time::Instant;
+
+use gdnative::{Rect2, Vector2};
+use legion::prelude::*;
+use legion::systems::schedule::Builder;
+
+use crate::input::{Layer, MouseEvent, MouseInput, MousePos};
+use crate::settings::{GestureSettings, Settings};
+
+// -----------------------------------------------------------------------------
+//     - Gesture -
+// -----------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GestureKind {
+    Click,
+    DoubleClick,
+    /// Held in place for a while. No click follows the release.
+    LongPress,
+    DragStart,
+    DragMove,
+    DragEnd,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Gesture {
+    pub kind: GestureKind,
+    pub button: i64,
+    /// Where the button was pressed
+    pub start: Vector2,
+    pub pos: Vector2,
+    /// Modifiers are read on release for clicks and the end of a drag,
+    /// otherwise on press
+    pub shift: bool,
+    pub control: bool,
+}
+
+impl Gesture {
+    pub fn is(&self, kind: GestureKind, button: i64) -> bool {
+        self.kind == kind && self.button == button
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Recognizer -
+// -----------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy)]
+struct Press {
+    event: MouseEvent,
+    at: Instant,
+    dragging: bool,
+    long_pressed: bool,
+    last_pos: Vector2,
+}
+
+/// Turns the raw presses and releases of one layer into gestures
+pub struct Recognizer {
+    pressed: Vec<Press>,
+    last_click: Option<(i64, Vector2, Instant)>,
+}
+
+impl Recognizer {
+    pub fn new() -> Self {
+        Self {
+            pressed: Vec::new(),
+            last_click: None,
+        }
+    }
+
+    pub fn event(
+        &mut self,
+        config: &GestureSettings,
+        event: &MouseEvent,
+        now: Instant,
+    ) -> Vec<Gesture> {
+        if event.pressed {
+            self.pressed.retain(|p| p.event.button != event.button);
+            self.pressed.push(Press {
+                event: *event,
+                at: now,
+                dragging: false,
+                long_pressed: false,
+                last_pos: event.pos,
+            });
+            return Vec::new();
+        }
+
+        let index = match self.pressed.iter().position(|p| p.event.button == event.button) {
+            Some(i) => i,
+            // Pressed before the recognizer was listening
+            None => return Vec::new(),
+        };
+
+        // Catch drags that were released before the mouse motion was seen
+        let mut gestures = self.moved(config, event.pos, now);
+        let press = self.pressed.remove(index);
+
+        let gesture = |kind| Gesture {
+            kind,
+            button: event.button,
+            start: press.event.pos,
+            pos: event.pos,
+            shift: event.shift,
+            control: event.control,
+        };
+
+        if press.dragging {
+            gestures.push(gesture(GestureKind::DragEnd));
+            return gestures;
+        }
+
+        if press.long_pressed {
+            return gestures;
+        }
+
+        let double_click = match self.last_click {
+            Some((button, pos, at)) => {
+                button == event.button
+                    && now - at <= config.double_click()
+                    && (pos - event.pos).length() <= config.double_click_distance
+            }
+            None => false,
+        };
+
+        match double_click {
+            true => {
+                self.last_click = None;
+                gestures.push(gesture(GestureKind::DoubleClick));
+            }
+            false => {
+                self.last_click = Some((event.button, event.pos, now));
+                gestures.push(gesture(GestureKind::Click));
+            }
+        }
+
+        gestures
+    }
+
+    /// Drags and long presses, call this every frame
+    pub fn moved(&mut self, config: &GestureSettings, pos: Vector2, now: Instant) -> Vec<Gesture> {
+        let mut gestures = Vec::new();
+
+        for press in self.pressed.iter_mut() {
+            let pressed = press.event;
+            let gesture = |kind| Gesture {
+                kind,
+                button: pressed.button,
+                start: pressed.pos,
+                pos,
+                shift: pressed.shift,
+                control: pressed.control,
+            };
+
+            if press.dragging {
+                if pos != press.last_pos {
+                    gestures.push(gesture(GestureKind::DragMove));
+                }
+            } else if (pos - press.event.pos).length() >= config.drag_distance {
+                press.dragging = true;
+                gestures.push(gesture(GestureKind::DragStart));
+                gestures.push(gesture(GestureKind::DragMove));
+            } else if !press.long_pressed && now - press.at >= config.long_press() {
+                press.long_pressed = true;
+                gestures.push(gesture(GestureKind::LongPress));
+            }
+
+            press.last_pos = pos;
+        }
+
+        gestures
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Resources -
+// -----------------------------------------------------------------------------
+/// Screen areas covered by the UI.
+/// Presses inside them belong to the UI layer.
+pub struct UiRects(Vec<(&'static str, Rect2)>);
+
+impl UiRects {
+    pub fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    pub fn set(&mut self, name: &'static str, rect: Option<Rect2>) {
+        self.0.retain(|(n, _)| *n != name);
+        if let Some(rect) = rect {
+            self.0.push((name, rect));
+        }
+    }
+
+    fn contains(&self, pos: Vector2) -> bool {
+        self.0.iter().any(|(_, rect)| rect.contains(pos.to_point()))
+    }
+}
+
+/// This frame's gestures for each layer
+pub struct Gestures {
+    ui: Recognizer,
+    world: Recognizer,
+    gestures: Vec<(Layer, Gesture)>,
+}
+
+impl Gestures {
+    pub fn new() -> Self {
+        Self {
+            ui: Recognizer::new(),
+            world: Recognizer::new(),
+            gestures: Vec::new(),
+        }
+    }
+
+    pub fn iter(&self, layer: Layer) -> impl Iterator<Item = &Gesture> {
+        self.gestures
+            .iter()
+            .filter(move |(l, _)| *l == layer)
+            .map(|(_, gesture)| gesture)
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Systems -
+// -----------------------------------------------------------------------------
+fn recognize_gestures() -> Box<dyn Runnable> {
+    SystemBuilder::new("recognize gestures")
+        .read_resource::<Settings>()
+        .read_resource::<MousePos>()
+        .read_resource::<UiRects>()
+        .write_resource::<MouseInput>()
+        .write_resource::<Gestures>()
+        .build_thread_local(|_, _, resources, _| {
+            let (settings, mouse_pos, ui_rects, mouse_input, gestures) = resources;
+            let config = &settings.gestures;
+            let now = Instant::now();
+            gestures.gestures.clear();
+
+            // The UI gets the presses on top of it
+            let on_ui = mouse_input
+                .events(Layer::World)
+                .filter(|(_, event)| event.pressed && ui_rects.contains(event.pos))
+                .map(|(index, _)| index)
+                .collect::<Vec<_>>();
+            for index in on_ui {
+                mouse_input.consume(index, Layer::Ui);
+            }
+
+            let Gestures { ui, world, gestures } = &mut **gestures;
+            for (layer, recognizer) in vec![(Layer::Ui, ui), (Layer::World, world)] {
+                let events = mouse_input
+                    .events(layer)
+                    .filter(|(_, event)| event.owner() == Some(layer) || layer == Layer::World)
+                    .map(|(_, event)| *event)
+                    .collect::<Vec<_>>();
+
+                for event in events {
+                    for gesture in recognizer.event(config, &event, now) {
+                        gestures.push((layer, gesture));
+                    }
+                }
+
+                for gesture in recognizer.moved(config, mouse_pos.global(), now) {
+                    gestures.push((layer, gesture));
+                }
+            }
+        })
+}
+
+pub fn gesture_systems(builder: Builder) -> Builder {
+    builder.add_thread_local(recognize_gestures())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::input::{LMB, RMB};
+    use std::time::Duration;
+
+    fn config() -> GestureSettings {
+        GestureSettings {
+            drag_distance: 4.,
+            double_click_ms: 400,
+            double_click_distance: 4.,
+            long_press_ms: 600,
+        }
+    }
+
+    fn pos(x: f32, y: f32) -> Vector2 {
+        Vector2::new(x, y)
+    }
+
+    fn ms(start: Instant, ms: u64) -> Instant {
+        start + Duration::from_millis(ms)
+    }
+
+    fn kinds(gestures: &[Gesture]) -> Vec<GestureKind> {
+        gestures.iter().map(|g| g.kind).collect()
+    }
+
+    /// Feed `(time, event)` pairs and the mouse position after each
+    fn run(stream: &[(u64, Option<MouseEvent>, Vector2)]) -> Vec<Gesture> {
+        let config = config();
+        let start = Instant::now();
+        let mut recognizer = Recognizer::new();
+        let mut gestures = Vec::new();
+
+        for (t, event, mouse_pos) in stream {
+            let now = ms(start, *t);
+            if let Some(event) = event {
+                gestures.extend(recognizer.event(&config, event, now));
+            }
+            gestures.extend(recognizer.moved(&config, *mouse_pos, now));
+        }
+
+        gestures
+    }
+
+    fn press(button: i64, p: Vector2) -> Option<MouseEvent> {
+        Some(MouseEvent::new(button, true, p))
+    }
+
+    fn release(button: i64, p: Vector2) -> Option<MouseEvent> {
+        Some(MouseEvent::new(button, false, p))
+    }
+
+    #[test]
+    fn click() {
+        let gestures = run(&[
+            (0, press(LMB, pos(10., 10.)), pos(10., 10.)),
+            (50, release(LMB, pos(11., 10.)), pos(11., 10.)),
+        ]);
+
+        assert_eq!(kinds(&gestures), vec![GestureKind::Click]);
+        assert_eq!(gestures[0].start, pos(10., 10.));
+        assert_eq!(gestures[0].pos, pos(11., 10.));
+    }
+
+    #[test]
+    fn double_click() {
+        let gestures = run(&[
+            (0, press(LMB, pos(10., 10.)), pos(10., 10.)),
+            (50, release(LMB, pos(10., 10.)), pos(10., 10.)),
+            (150, press(LMB, pos(11., 11.)), pos(11., 11.)),
+            (200, release(LMB, pos(11., 11.)), pos(11., 11.)),
+            // A third click starts over
+            (250, press(LMB, pos(11., 11.)), pos(11., 11.)),
+            (300, release(LMB, pos(11., 11.)), pos(11., 11.)),
+        ]);
+
+        assert_eq!(
+            kinds(&gestures),
+            vec![GestureKind::Click, GestureKind::DoubleClick, GestureKind::Click]
+        );
+    }
+
+    #[test]
+    fn slow_or_distant_clicks_are_not_double_clicks() {
+        let gestures = run(&[
+            (0, press(LMB, pos(10., 10.)), pos(10., 10.)),
+            (50, release(LMB, pos(10., 10.)), pos(10., 10.)),
+            (1000, press(LMB, pos(10., 10.)), pos(10., 10.)),
+            (1050, release(LMB, pos(10., 10.)), pos(10., 10.)),
+            (1100, press(LMB, pos(50., 10.)), pos(50., 10.)),
+            (1150, release(LMB, pos(50., 10.)), pos(50., 10.)),
+        ]);
+
+        assert_eq!(
+            kinds(&gestures),
+            vec![GestureKind::Click, GestureKind::Click, GestureKind::Click]
+        );
+    }
+
+    #[test]
+    fn different_buttons_are_not_double_clicks() {
+        let gestures = run(&[
+            (0, press(LMB, pos(10., 10.)), pos(10., 10.)),
+            (50, release(LMB, pos(10., 10.)), pos(10., 10.)),
+            (100, press(RMB, pos(10., 10.)), pos(10., 10.)),
+            (150, release(RMB, pos(10., 10.)), pos(10., 10.)),
+        ]);
+
+        assert_eq!(kinds(&gestures), vec![GestureKind::Click, GestureKind::Click]);
+        assert_eq!(gestures[1].button, RMB);
+    }
+
+    #[test]
+    fn drag() {
+        let gestures = run(&[
+            (0, press(LMB, pos(10., 10.)), pos(10., 10.)),
+            // Too short to be a drag
+            (16, None, pos(12., 10.)),
+            (32, None, pos(20., 10.)),
+            // Not moved, no DragMove
+            (48, None, pos(20., 10.)),
+            (64, None, pos(30., 15.)),
+            (80, release(LMB, pos(30., 15.)), pos(30., 15.)),
+        ]);
+
+        assert_eq!(
+            kinds(&gestures),
+            vec![
+                GestureKind::DragStart,
+                GestureKind::DragMove,
+                GestureKind::DragMove,
+                GestureKind::DragEnd,
+            ]
+        );
+        assert!(gestures.iter().all(|g| g.start == pos(10., 10.)));
+        assert_eq!(gestures[3].pos, pos(30., 15.));
+    }
+
+    #[test]
+    fn drag_released_in_the_same_frame() {
+        let gestures = run(&[
+            (0, press(LMB, pos(10., 10.)), pos(10., 10.)),
+            (16, release(LMB, pos(40., 10.)), pos(40., 10.)),
+        ]);
+
+        assert_eq!(
+            kinds(&gestures),
+            vec![GestureKind::DragStart, GestureKind::DragMove, GestureKind::DragEnd]
+        );
+    }
+
+    #[test]
+    fn long_press() {
+        let gestures = run(&[
+            (0, press(RMB, pos(10., 10.)), pos(10., 10.)),
+            (300, None, pos(10., 10.)),
+            (700, None, pos(11., 10.)),
+            (800, None, pos(11., 10.)),
+            (900, release(RMB, pos(11., 10.)), pos(11., 10.)),
+        ]);
+
+        assert_eq!(kinds(&gestures), vec![GestureKind::LongPress]);
+    }
+
+    #[test]
+    fn long_press_can_still_drag() {
+        let gestures = run(&[
+            (0, press(LMB, pos(10., 10.)), pos(10., 10.)),
+            (700, None, pos(10., 10.)),
+            (800, None, pos(40., 10.)),
+            (900, release(LMB, pos(40., 10.)), pos(40., 10.)),
+        ]);
+
+        assert_eq!(
+            kinds(&gestures),
+            vec![
+                GestureKind::LongPress,
+                GestureKind::DragStart,
+                GestureKind::DragMove,
+                GestureKind::DragEnd,
+            ]
+        );
+    }
+
+    #[test]
+    fn release_without_press_is_ignored() {
+        let gestures = run(&[(0, release(LMB, pos(10., 10.)), pos(10., 10.))]);
+        assert!(gestures.is_empty());
+    }
+
+    #[test]
+    fn two_buttons_at_once() {
+        let gestures = run(&[
+            (0, press(LMB, pos(10., 10.)), pos(10., 10.)),
+            (10, press(RMB, pos(10., 10.)), pos(10., 10.)),
+            (20, None, pos(30., 10.)),
+            (30, release(RMB, pos(30., 10.)), pos(30., 10.)),
+            (40, release(LMB, pos(30., 10.)), pos(30., 10.)),
+        ]);
+
+        let lmb = gestures.iter().filter(|g| g.button == LMB).map(|g| g.kind).collect::<Vec<_>>();
+        let rmb = gestures.iter().filter(|g| g.button == RMB).map(|g| g.kind).collect::<Vec<_>>();
+        let drag = vec![GestureKind::DragStart, GestureKind::DragMove, GestureKind::DragEnd];
+        assert_eq!(lmb, drag);
+        assert_eq!(rmb, drag);
+    }
+}
@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+
+use gdnative::{Vector2, Vector3};
+use legion::prelude::*;
+use legion::systems::schedule::Builder;
+
+use crate::clock::TICK_DELTA;
+use crate::movement::{to_2d, to_3d, Destination, Pos};
+use crate::tilemap::NavGrid;
+
+// Members further than this from their slot hold the group back
+const CATCH_UP_DIST: f32 = 2.;
+const ARRIVE_DIST: f32 = 0.1;
+
+// -----------------------------------------------------------------------------
+//     - Components -
+// -----------------------------------------------------------------------------
+
+/// A virtual unit that the rest of the group follows along a path.
+/// Lives on its own entity together with a `LeaderPos`.
+#[derive(Debug, Clone)]
+pub struct GroupLeader {
+    /// The last waypoint is the formation centre
+    path: Vec<Vector3>,
+    next: usize,
+    speed: f32,
+}
+
+impl GroupLeader {
+    fn arrived(&self) -> bool {
+        self.next >= self.path.len()
+    }
+
+    /// Move `step` along the path from `pos`, returns the new position
+    fn advance(&mut self, mut pos: Vector3, mut step: f32) -> Vector3 {
+        while step > 0. && !self.arrived() {
+            let waypoint = self.path[self.next];
+            let diff = to_2d(waypoint - pos);
+            let dist = diff.length();
+
+            if dist <= step.max(ARRIVE_DIST) {
+                pos = waypoint;
+                step -= dist;
+                self.next += 1;
+            } else {
+                pos += to_3d(diff.normalize() * step);
+                step = 0.;
+            }
+        }
+        pos
+    }
+}
+
+/// Where the virtual leader is.
+/// Not a `Pos`, so it never shows up as a unit.
+#[derive(Debug, Clone, Copy)]
+pub struct LeaderPos(pub Vector3);
+
+#[derive(Debug, Clone, Copy)]
+pub struct FormationMember {
+    leader: Entity,
+    offset: Vector2,
+}
+
+/// Start a march towards `centre`, along a path through the nav grid.
+/// `members` are `(entity, max speed, final slot)`, the group moves at the speed
+/// of the slowest member.
+pub fn start_march(
+    cmd: &mut CommandBuffer,
+    nav: &NavGrid,
+    start: Vector3,
+    centre: Vector3,
+    members: &[(Entity, f32, Vector3)],
+) {
+    if members.len() == 0 {
+        return;
+    }
+
+    let speed = members
+        .iter()
+        .map(|(_, speed, _)| *speed)
+        .fold(std::f32::MAX, f32::min);
+
+    // Without a path (nothing known about the ground) go straight there
+    let path = nav.find_path(start, centre).unwrap_or_else(|| vec![centre]);
+
+    let leader = GroupLeader { path, next: 0, speed };
+    let leader = cmd.insert((), vec![(leader, LeaderPos(start))])[0];
+
+    for (ent, _, slot) in members {
+        let offset = to_2d(*slot - centre);
+        cmd.add_component(*ent, FormationMember { leader, offset });
+        cmd.add_component(*ent, Destination(start + to_3d(offset)));
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Systems -
+// -----------------------------------------------------------------------------
+fn move_group_leaders() -> Box<dyn Runnable> {
+    SystemBuilder::new("move group leaders")
+        .with_query(<(Read<FormationMember>, Read<Pos>)>::query())
+        .with_query(<(Write<GroupLeader>, Write<LeaderPos>)>::query())
+        .build_thread_local(|cmd, world, _, (members, leaders)| {
+            let leader_positions = leaders
+                .iter_entities(world)
+                .map(|(ent, (_, pos))| (ent, pos.0))
+                .collect::<HashMap<_, _>>();
+
+            // How far behind is the slowest member of each group
+            let mut lag = HashMap::new();
+            for (member, pos) in members.iter(world) {
+                let leader_pos = match leader_positions.get(&member.leader) {
+                    Some(p) => *p,
+                    None => continue,
+                };
+                let slot = leader_pos + to_3d(member.offset);
+                let dist = (to_2d(slot) - to_2d(pos.0)).length();
+                let entry = lag.entry(member.leader).or_insert(0f32);
+                *entry = entry.max(dist);
+            }
+
+            for (ent, (mut leader, mut pos)) in leaders.iter_entities_mut(world) {
+                let lag = match lag.get(&ent) {
+                    Some(l) => *l,
+                    // Nobody is following any more
+                    None => {
+                        cmd.delete(ent);
+                        continue;
+                    }
+                };
+
+                let mut speed = leader.speed;
+                if lag > CATCH_UP_DIST {
+                    speed *= CATCH_UP_DIST / lag;
+                }
+
+                pos.0 = leader.advance(pos.0, speed * TICK_DELTA);
+            }
+        })
+}
+
+fn follow_group_leaders() -> Box<dyn Runnable> {
+    SystemBuilder::new("follow group leaders")
+        .with_query(<(Read<GroupLeader>, Read<LeaderPos>)>::query())
+        .with_query(<Read<FormationMember>>::query())
+        .build_thread_local(|cmd, world, _, (leaders, members)| {
+            let leaders = leaders
+                .iter_entities(world)
+                .map(|(ent, (leader, pos))| (ent, (leader.arrived(), pos.0)))
+                .collect::<HashMap<_, _>>();
+
+            for (ent, member) in members.iter_entities(world) {
+                let (arrived, leader_pos) = match leaders.get(&member.leader) {
+                    Some(l) => *l,
+                    None => {
+                        cmd.remove_component::<FormationMember>(ent);
+                        continue;
+                    }
+                };
+
+                // The slot moves with the leader along its path
+                let slot = leader_pos + to_3d(member.offset);
+                cmd.add_component(ent, Destination(slot));
+
+                // The leader has arrived, the last stretch is a normal move
+                if arrived {
+                    cmd.remove_component::<FormationMember>(ent);
+                }
+            }
+        })
+}
+
+pub fn group_systems(builder: Builder) -> Builder {
+    builder
+        .add_thread_local(move_group_leaders())
+        .add_thread_local(follow_group_leaders())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn leader(path: Vec<Vector3>) -> GroupLeader {
+        GroupLeader { path, next: 0, speed: 1. }
+    }
+
+    #[test]
+    fn leader_turns_corners() {
+        let mut leader = leader(vec![Vector3::new(2., 0., 0.), Vector3::new(2., 0., 2.)]);
+
+        let pos = leader.advance(Vector3::zero(), 3.);
+        assert_eq!(pos, Vector3::new(2., 0., 1.));
+        assert!(!leader.arrived());
+
+        let pos = leader.advance(pos, 3.);
+        assert_eq!(pos, Vector3::new(2., 0., 2.));
+        assert!(leader.arrived());
+
+        // Stays put once there
+        assert_eq!(leader.advance(pos, 3.), pos);
+    }
+
+    #[test]
+    fn leader_snaps_to_close_waypoints() {
+        let mut leader = leader(vec![Vector3::new(0.05, 0., 0.)]);
+        assert_eq!(leader.advance(Vector3::zero(), 0.01), Vector3::new(0.05, 0., 0.));
+        assert!(leader.arrived());
+    }
+}
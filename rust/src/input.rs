@@ -4,40 +4,151 @@ use bitflags::bitflags;
 
 pub const LMB: i64 = 1;
 pub const RMB: i64 = 2;
+pub const MMB: i64 = 3;
+pub const WHEEL_UP: i64 = 4;
+pub const WHEEL_DOWN: i64 = 5;
 
-pub enum MouseButton {
-    Empty,
-    Mouse { pressed: bool, button_index: i64 },
+const BUTTON_COUNT: usize = 16;
+
+/// Who gets to handle a mouse event.
+/// The UI runs first, whatever it consumes the world never sees.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layer {
+    Ui,
+    World,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MouseEvent {
+    pub button: i64,
+    pub pressed: bool,
+    pub pos: Vector2,
+    pub shift: bool,
+    pub control: bool,
+    owner: Option<Layer>,
 }
 
-impl MouseButton {
-    pub fn consume(&mut self) {
-        *self = Self::Empty;
+impl MouseEvent {
+    pub fn new(button: i64, pressed: bool, pos: Vector2) -> Self {
+        Self {
+            button,
+            pressed,
+            pos,
+            shift: false,
+            control: false,
+            owner: None,
+        }
     }
 
     pub fn from_event(ev: Ref<InputEventMouseButton>) -> Self {
-        MouseButton::Mouse {
-            pressed: ev.is_pressed(),
-            button_index: ev.button_index(),
+        Self {
+            shift: ev.shift(),
+            control: ev.control(),
+            ..Self::new(ev.button_index(), ev.is_pressed(), ev.global_position())
         }
     }
 
-    pub fn button_pressed(&self, index: i64) -> bool {
-        match self {
-            Self::Empty => false,
-            Self::Mouse { pressed, button_index } => {
-                *pressed && *button_index == index
-            }
+    /// The layer that consumed the event
+    pub fn owner(&self) -> Option<Layer> {
+        self.owner
+    }
+
+    fn visible_to(&self, layer: Layer) -> bool {
+        self.owner.map(|owner| owner == layer).unwrap_or(true)
+    }
+}
+
+/// The mouse button events of this frame, in the order they happened.
+/// Cleared at the end of every frame, so a press and release in the same
+/// frame are both seen.
+pub struct MouseInput {
+    events: Vec<MouseEvent>,
+    held: [bool; BUTTON_COUNT],
+    // A layer that consumed a press owns the button until it's released
+    captured: [Option<Layer>; BUTTON_COUNT],
+}
+
+impl MouseInput {
+    pub fn new() -> Self {
+        Self {
+            events: Vec::new(),
+            held: [false; BUTTON_COUNT],
+            captured: [None; BUTTON_COUNT],
+        }
+    }
+
+    pub fn push(&mut self, mut event: MouseEvent) {
+        let index = event.button as usize;
+        if index >= BUTTON_COUNT {
+            return;
+        }
+
+        self.held[index] = event.pressed;
+        event.owner = self.captured[index];
+        if !event.pressed {
+            self.captured[index] = None;
+        }
+
+        self.events.push(event);
+    }
+
+    pub fn end_frame(&mut self) {
+        self.events.clear();
+    }
+
+    /// This frame's events that haven't been consumed by another layer
+    pub fn events(&self, layer: Layer) -> impl Iterator<Item = (usize, &MouseEvent)> {
+        self.events
+            .iter()
+            .enumerate()
+            .filter(move |(_, event)| event.visible_to(layer))
+    }
+
+    pub fn pressed(&self, button: i64, layer: Layer) -> Option<(usize, MouseEvent)> {
+        self.events(layer)
+            .find(|(_, event)| event.button == button && event.pressed)
+            .map(|(i, event)| (i, *event))
+    }
+
+    pub fn released(&self, button: i64, layer: Layer) -> Option<(usize, MouseEvent)> {
+        self.events(layer)
+            .find(|(_, event)| event.button == button && !event.pressed)
+            .map(|(i, event)| (i, *event))
+    }
+
+    /// Held down at the end of this frame, and not owned by another layer
+    pub fn held(&self, button: i64, layer: Layer) -> bool {
+        let index = button as usize;
+        if index >= BUTTON_COUNT {
+            return false;
         }
+
+        self.held[index] && self.captured[index].map(|owner| owner == layer).unwrap_or(true)
     }
 
-    pub fn button_released(&self, index: i64) -> bool {
-        match self {
-            Self::Empty => false,
-            Self::Mouse { pressed, button_index } => {
-                !*pressed && *button_index == index
+    /// Hide the event from other layers.
+    /// Consuming a press also takes the release that goes with it.
+    pub fn consume(&mut self, index: usize, layer: Layer) {
+        let event = match self.events.get_mut(index) {
+            Some(e) => e,
+            None => return,
+        };
+        event.owner = Some(layer);
+
+        if !event.pressed {
+            return;
+        }
+
+        let button = event.button;
+        for event in self.events[index + 1..].iter_mut().filter(|e| e.button == button) {
+            event.owner = Some(layer);
+            if !event.pressed {
+                return;
             }
         }
+
+        // Not released yet
+        self.captured[button as usize] = Some(layer);
     }
 }
 
@@ -68,6 +179,8 @@ bitflags! {
         const RIGHT = 2;
         const UP = 4;
         const DOWN = 8;
+        const ROTATE_LEFT = 16;
+        const ROTATE_RIGHT = 32;
     }
 }
 
@@ -96,3 +209,75 @@ impl Keyboard {
         self.keys
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn click(input: &mut MouseInput, button: i64) {
+        input.push(MouseEvent::new(button, true, Vector2::zero()));
+        input.push(MouseEvent::new(button, false, Vector2::zero()));
+    }
+
+    #[test]
+    fn press_and_release_in_one_frame() {
+        let mut input = MouseInput::new();
+        click(&mut input, LMB);
+        click(&mut input, LMB);
+
+        assert_eq!(input.events(Layer::World).count(), 4);
+        assert!(input.pressed(LMB, Layer::World).is_some());
+        assert!(input.released(LMB, Layer::World).is_some());
+        assert!(!input.held(LMB, Layer::World));
+
+        input.end_frame();
+        assert!(input.pressed(LMB, Layer::World).is_none());
+    }
+
+    #[test]
+    fn held_across_frames() {
+        let mut input = MouseInput::new();
+        input.push(MouseEvent::new(RMB, true, Vector2::zero()));
+        input.end_frame();
+
+        assert!(input.held(RMB, Layer::World));
+        assert!(input.pressed(RMB, Layer::World).is_none());
+        assert!(!input.held(LMB, Layer::World));
+    }
+
+    #[test]
+    fn consumed_press_takes_the_release() {
+        let mut input = MouseInput::new();
+        click(&mut input, LMB);
+        click(&mut input, RMB);
+
+        let (index, _) = input.pressed(LMB, Layer::Ui).unwrap();
+        input.consume(index, Layer::Ui);
+
+        assert!(input.pressed(LMB, Layer::World).is_none());
+        assert!(input.released(LMB, Layer::World).is_none());
+        assert!(input.released(LMB, Layer::Ui).is_some());
+        assert!(input.pressed(RMB, Layer::World).is_some());
+    }
+
+    #[test]
+    fn capture_lasts_until_release() {
+        let mut input = MouseInput::new();
+        input.push(MouseEvent::new(LMB, true, Vector2::zero()));
+        let (index, _) = input.pressed(LMB, Layer::Ui).unwrap();
+        input.consume(index, Layer::Ui);
+        input.end_frame();
+
+        assert!(input.held(LMB, Layer::Ui));
+        assert!(!input.held(LMB, Layer::World));
+
+        input.push(MouseEvent::new(LMB, false, Vector2::zero()));
+        assert!(input.released(LMB, Layer::World).is_none());
+        assert!(input.released(LMB, Layer::Ui).is_some());
+        input.end_frame();
+
+        // The next click is up for grabs again
+        click(&mut input, LMB);
+        assert!(input.pressed(LMB, Layer::World).is_some());
+    }
+}
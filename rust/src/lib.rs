@@ -1,23 +1,38 @@
 use gdnative::*;
 
 // mod game;
+mod action_map;
+mod assignment;
+mod gamepad;
 mod gameworld;
+mod gesture;
 mod input;
 mod movement;
+mod orders;
 mod unit;
 mod camera;
+mod clock;
 mod spawner;
 mod tilemap;
 mod procgen;
 mod player;
+mod presentation;
+mod replay;
 mod saveload;
+mod settings;
+mod squad;
 mod enemy;
 // mod main_menu;
 mod formation;
+mod formation_template;
+mod group;
 mod animation;
 // // mod dragndrop;
 mod debug;
+mod dungeon;
 mod contextmenu;
+mod radialmenu;
+mod control_group;
 
 fn init(handle: init::InitHandle) {
     handle.add_class::<gameworld::GameWorld>();
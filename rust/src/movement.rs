@@ -7,7 +7,8 @@ use legion::systems::schedule::Builder;
 use serde::{Deserialize, Serialize};
 
 use crate::animation::Animation;
-use crate::gameworld::{Delta, DebugLines};
+use crate::clock::TICK_DELTA;
+use crate::gameworld::DebugLines;
 use crate::unit::Unit;
 
 type Transform3 = Transform3D<f32, UnknownUnit, UnknownUnit>;
@@ -89,9 +90,9 @@ fn reset_forces() -> Box<dyn Runnable> {
 
 fn apply_forces() -> Box<dyn Runnable> {
     SystemBuilder::new("apply_forces")
-        .with_query(<(Read<Unit>, Read<Forces>, Write<Acceleration>)>::query())
+        .with_query(<(Read<Forces>, Write<Acceleration>)>::query())
         .build_thread_local(|_, world, _, query| {
-            for (unit, forces, mut acc) in query.iter_mut(world) {
+            for (forces, mut acc) in query.iter_mut(world) {
                 acc.0 += to_3d(forces.separation);
                 acc.0 += to_3d(forces.seek);
             }
@@ -100,7 +101,6 @@ fn apply_forces() -> Box<dyn Runnable> {
 
 fn seek() -> Box<dyn Runnable> {
     SystemBuilder::new("apply directional velocity")
-        .read_resource::<Delta>()
         .write_resource::<DebugLines>()
         .with_query(<(
             Read<MaxSpeed>,
@@ -109,14 +109,14 @@ fn seek() -> Box<dyn Runnable> {
             Write<Forces>,
             Read<Velocity>,
         )>::query())
-        .build_thread_local(|_, world, (delta, debug_lines), query| {
+        .build_thread_local(|_, world, debug_lines, query| {
             for (max_speed, pos, dest, mut forces, velocity) in query.iter_mut(world) {
                 let mut diff = to_2d(dest.0 - pos.0);
                 let dist = diff.length();
-                let future_dist = to_2d(pos.0 + velocity.0 * delta.0 - dest.0).length();
+                let future_dist = to_2d(pos.0 + velocity.0 * TICK_DELTA - dest.0).length();
 
                 if future_dist >= dist {
-                    let force = to_2d(-velocity.0) + diff / delta.0;
+                    let force = to_2d(-velocity.0) + diff / TICK_DELTA;
                     forces.seek = force;
                 } else {
                     diff += diff.normalize() * max_speed.0;
@@ -128,26 +128,31 @@ fn seek() -> Box<dyn Runnable> {
         })
 }
 
+/// The simulation moves the units itself, so the same orders end up
+/// in the same place on every run
 fn move_units() -> Box<dyn Runnable> {
     SystemBuilder::new("move units")
         .with_query(
-            <(
-                Write<Pos>,
-                Write<Unit>,
-                Write<Velocity>,
-                Read<Acceleration>,
-                Read<MaxSpeed>,
-            )>::query()
-            .filter(component::<Destination>()),
+            <(Write<Pos>, Write<Velocity>, Read<Acceleration>, Read<MaxSpeed>)>::query()
+                .filter(component::<Destination>()),
         )
         .build_thread_local(|_, world, _, units| {
-            for (mut pos, unit, mut velocity, acc, max_speed) in units.iter_mut(world) {
-                let unit = unsafe { unit.inner.assume_safe() };
+            for (mut pos, mut velocity, acc, max_speed) in units.iter_mut(world) {
                 velocity.0 += acc.0;
                 velocity.0 = velocity.0.with_max_length(max_speed.0);
                 velocity.0.y = 0.;
-                velocity.0 = unit.move_and_slide_default(velocity.0, Vector3::new(0., 1., 0.));
-                pos.0 = unit.translation();
+                pos.0 += velocity.0 * TICK_DELTA;
+            }
+        })
+}
+
+/// Godot only shows the units where the simulation put them
+fn sync_units() -> Box<dyn Runnable> {
+    SystemBuilder::new("sync units")
+        .with_query(<(Read<Unit>, Read<Pos>)>::query())
+        .build_thread_local(|_, world, _, units| {
+            for (unit, pos) in units.iter(world) {
+                unit.set_translation(pos.0);
             }
         })
 }
@@ -227,5 +232,6 @@ pub fn movement_systems(builder: Builder) -> Builder {
         .add_thread_local(apply_forces())
         .add_thread_local(rotate_unit())
         .add_thread_local(move_units())
+        .add_thread_local(sync_units())
         .add_thread_local(done_moving())
 }
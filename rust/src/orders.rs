@@ -0,0 +1,192 @@
+use gdnative::Vector3;
+use legion::prelude::*;
+use legion::systems::schedule::Builder;
+use serde::{Deserialize, Serialize};
+
+use crate::group::start_march;
+use crate::movement::{MaxSpeed, Pos};
+use crate::player::PlayerId;
+use crate::replay::Replay;
+use crate::tilemap::NavGrid;
+
+/// An order once the input has been turned into what each unit should do.
+/// Units are referred to by their player id.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Order {
+    /// Every unit finds its own way to its slot
+    Move(Vec<(PlayerId, Vector3)>),
+    /// The units march from `start` to `centre` as a group, keeping to their slots
+    March {
+        start: Vector3,
+        centre: Vector3,
+        slots: Vec<(PlayerId, Vector3)>,
+    },
+}
+
+// -----------------------------------------------------------------------------
+//     - Components -
+// -----------------------------------------------------------------------------
+/// Where the last order sent the unit, whether it got there or not
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OrderTarget(pub Vector3);
+
+// -----------------------------------------------------------------------------
+//     - Resources -
+// -----------------------------------------------------------------------------
+/// Orders given since the last tick.
+/// The input is handled every frame, but the orders are only carried out by
+/// the next tick so they don't depend on the frame rate.
+pub struct Orders {
+    queue: Vec<Order>,
+}
+
+impl Orders {
+    pub fn new() -> Self {
+        Self { queue: Vec::new() }
+    }
+
+    pub fn push(&mut self, order: Order) {
+        self.queue.push(order);
+    }
+
+    pub fn take(&mut self) -> Vec<Order> {
+        self.queue.drain(..).collect()
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Systems -
+// -----------------------------------------------------------------------------
+fn apply_orders() -> Box<dyn Runnable> {
+    SystemBuilder::new("apply orders")
+        .read_resource::<NavGrid>()
+        .write_resource::<Orders>()
+        .write_resource::<Replay>()
+        .with_query(<(Read<Pos>, Read<MaxSpeed>, Tagged<PlayerId>)>::query())
+        .build_thread_local(|cmd, world, (nav, orders, replay), units| {
+            // Recorded with the tick, or swapped for the recorded ones
+            let orders = replay.orders(orders.take());
+            if orders.len() == 0 {
+                return;
+            }
+
+            let units = units
+                .iter_entities(world)
+                .map(|(ent, (pos, speed, player_id))| (*player_id, ent, pos.0, speed.0))
+                .collect::<Vec<_>>();
+
+            // Units that died since the order was given are left out
+            let find = |id: &PlayerId| units.iter().find(|(player_id, ..)| player_id == id);
+
+            for order in orders {
+                match order {
+                    Order::Move(slots) => {
+                        // A group of one, so it gets a path around walls too
+                        for (id, slot) in slots {
+                            if let Some((_, ent, pos, speed)) = find(&id) {
+                                cmd.add_component(*ent, OrderTarget(slot));
+                                start_march(cmd, &nav, *pos, slot, &[(*ent, *speed, slot)]);
+                            }
+                        }
+                    }
+                    Order::March {
+                        start,
+                        centre,
+                        slots,
+                    } => {
+                        let members = slots
+                            .iter()
+                            .filter_map(|(id, slot)| {
+                                find(id).map(|(_, ent, _, speed)| (*ent, *speed, *slot))
+                            })
+                            .collect::<Vec<_>>();
+                        for (ent, _, slot) in &members {
+                            cmd.add_component(*ent, OrderTarget(*slot));
+                        }
+                        start_march(cmd, &nav, start, centre, &members);
+                    }
+                }
+            }
+        })
+}
+
+pub fn order_systems(builder: Builder) -> Builder {
+    builder.add_thread_local(apply_orders())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::animation::Animation;
+    use crate::gameworld::DebugLines;
+    use crate::group::group_systems;
+    use crate::movement::{movement_systems, Acceleration, Forces, Velocity};
+
+    /// Run the simulation on a fresh world, the positions of the units at the end
+    fn run(orders: &[Order]) -> Vec<(PlayerId, [u32; 3])> {
+        let universe = Universe::new();
+        let mut world = universe.create_world();
+        for i in 0..4 {
+            world.insert(
+                (PlayerId::new(i),),
+                vec![(
+                    Pos(Vector3::new(i as f32 * 2., 0.4, 0.)),
+                    Velocity(Vector3::zero()),
+                    MaxSpeed(7.5),
+                    Forces::zero(),
+                    Acceleration(Vector3::zero()),
+                    Animation::Idle,
+                )],
+            );
+        }
+
+        let mut resources = Resources::default();
+        resources.insert(NavGrid::new());
+        resources.insert(Replay::new());
+        resources.insert(DebugLines::new());
+        let mut queue = Orders::new();
+        for order in orders {
+            queue.push(order.clone());
+        }
+        resources.insert(queue);
+
+        let builder = order_systems(Schedule::builder());
+        let builder = group_systems(builder);
+        let mut schedule = movement_systems(builder).build();
+        for _ in 0..120 {
+            schedule.execute(&mut world, &mut resources);
+        }
+
+        let bits = |v: Vector3| [v.x.to_bits(), v.y.to_bits(), v.z.to_bits()];
+        let mut positions = <(Read<Pos>, Tagged<PlayerId>)>::query()
+            .iter(&world)
+            .map(|(pos, id)| (*id, bits(pos.0)))
+            .collect::<Vec<_>>();
+        positions.sort_by_key(|(id, _)| *id);
+        positions
+    }
+
+    #[test]
+    fn same_orders_same_positions() {
+        let orders = vec![
+            Order::Move(vec![
+                (PlayerId::new(0), Vector3::new(10., 0.4, 5.)),
+                (PlayerId::new(1), Vector3::new(12., 0.4, 5.)),
+            ]),
+            Order::March {
+                start: Vector3::new(5., 0.4, 0.),
+                centre: Vector3::new(-5., 0.4, 10.),
+                slots: vec![
+                    (PlayerId::new(2), Vector3::new(-6., 0.4, 10.)),
+                    (PlayerId::new(3), Vector3::new(-4., 0.4, 10.)),
+                ],
+            },
+        ];
+
+        let first = run(&orders);
+        assert_eq!(first, run(&orders));
+
+        // And they did go somewhere
+        assert_ne!(first, run(&[]));
+    }
+}
@@ -4,18 +4,142 @@ use legion::prelude::*;
 use legion::systems::schedule::Builder;
 use serde::{Deserialize, Serialize};
 
-use crate::camera::{Camera, Drag, SelectionBox, RAY_LENGTH};
+use crate::assignment::AssignmentMode;
+use crate::camera::{Camera, SelectionBox, RAY_LENGTH};
 use crate::contextmenu::ContextMenuNode;
 use crate::formation::{index_to_x_y, FormationPos};
-use crate::input::{MouseButton, MousePos, LMB, RMB};
-use crate::movement::{to_2d, to_3d, Destination, Pos};
-use crate::unit::Unit;
+use crate::gamepad::Gamepad;
+use crate::gesture::{Gesture, GestureKind, Gestures};
+use crate::input::{Layer, MousePos, LMB, RMB};
+use crate::movement::{to_2d, to_3d, Pos};
+use crate::orders::{Order, Orders};
+use crate::unit::{Role, Unit};
 use crate::gameworld::ClickedState;
 use crate::safe;
+use crate::squad::{Squad, SquadId};
 
 type Rotation2 = Rotation2D<f32, UnknownUnit, UnknownUnit>;
 
 const OFFSET_MUL: f32 = 2.0;
+// Shorter drags than this are treated as a plain click
+const MIN_DRAG_LEN: f32 = 1.0;
+
+enum SelectMode {
+    Replace,
+    /// Shift
+    Add,
+    /// Ctrl
+    Toggle,
+}
+
+impl SelectMode {
+    fn from_gesture(gesture: &Gesture) -> Self {
+        if gesture.control {
+            Self::Toggle
+        } else if gesture.shift {
+            Self::Add
+        } else {
+            Self::Replace
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Resources -
+// -----------------------------------------------------------------------------
+pub struct MoveOrder {
+    start: Option<Vector3>,
+    pub width_from_drag: bool,
+    /// The slots and facing of the order being dragged out, empty otherwise
+    pub preview: Vec<Vector3>,
+    pub facing: Vector2,
+}
+
+impl MoveOrder {
+    pub fn new() -> Self {
+        Self {
+            start: None,
+            width_from_drag: false,
+            preview: Vec::new(),
+            facing: Vector2::zero(),
+        }
+    }
+}
+
+/// Grid coordinates for `(squad, formation index)` pairs.
+/// Formation indices are per squad, so each squad gets its own block of
+/// columns, left to right in the order the squads first show up.
+fn squad_grid<T: Copy + PartialEq>(units: &[(T, u16)]) -> Vec<Vector2> {
+    let mut squads = Vec::new();
+    for (squad, _) in units {
+        if !squads.contains(squad) {
+            squads.push(*squad);
+        }
+    }
+
+    let mut offsets = Vec::with_capacity(squads.len());
+    let mut next = 0;
+    for squad in &squads {
+        offsets.push(next);
+        let last_column = units
+            .iter()
+            .filter(|(s, _)| s == squad)
+            .map(|(_, index)| index_to_x_y(*index as usize).0)
+            .max()
+            .unwrap_or(0);
+        // An empty column between squads
+        next += last_column + 2;
+    }
+
+    units
+        .iter()
+        .map(|(squad, index)| {
+            let (x, y) = index_to_x_y(*index as usize);
+            let block = squads.iter().position(|s| s == squad).unwrap_or(0);
+            Vector2::new((offsets[block] + x) as f32, y as f32)
+        })
+        .collect()
+}
+
+/// World positions of the formation slots for `(squad, formation index)` pairs.
+/// The slots are centred on `centre` with the front row facing `facing`.
+/// If `width` is given the columns are spread out to cover it.
+pub fn formation_slots<T: Copy + PartialEq>(
+    units: &[(T, u16)],
+    centre: Vector3,
+    facing: Vector2,
+    width: Option<f32>,
+) -> Vec<Vector3> {
+    if units.len() == 0 {
+        return Vec::new();
+    }
+
+    let coords = squad_grid(units);
+
+    let min_x = coords.iter().map(|c| c.x).fold(std::f32::MAX, f32::min);
+    let max_x = coords.iter().map(|c| c.x).fold(std::f32::MIN, f32::max);
+    let grid_centre = coords.iter().fold(Vector2::zero(), |acc, c| acc + *c) / coords.len() as f32;
+
+    let spacing_x = match width {
+        Some(width) if max_x > min_x => (width / (max_x - min_x)).max(OFFSET_MUL),
+        _ => OFFSET_MUL,
+    };
+
+    // Row 0 is the front, so -y in the grid points along `facing`
+    let rotation = match facing.length() > 0. {
+        true => Rotation2::radians(facing.x.atan2(-facing.y)),
+        false => Rotation2::identity(),
+    };
+
+    coords
+        .iter()
+        .map(|c| {
+            let offset = *c - grid_centre;
+            let offset = Vector2::new(offset.x * spacing_x, offset.y * OFFSET_MUL);
+            centre + to_3d(rotation.transform_vector(offset))
+        })
+        .collect()
+}
 
 // -----------------------------------------------------------------------------
 //     - Tags -
@@ -23,7 +147,7 @@ const OFFSET_MUL: f32 = 2.0;
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Selected;
 
-#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize, Serialize)]
 pub struct PlayerId(u8);
 
 impl PlayerId {
@@ -32,65 +156,131 @@ impl PlayerId {
     }
 }
 
+/// Select every player unit
+pub fn select_all(world: &mut World) {
+    let units = <Read<Pos>>::query()
+        .filter(tag::<PlayerId>())
+        .iter_entities(world)
+        .map(|(ent, _)| ent)
+        .collect::<Vec<_>>();
+
+    for unit in units {
+        let _ = world.add_tag(unit, Selected);
+    }
+}
+
 // -----------------------------------------------------------------------------
 //     - Systems -
 // -----------------------------------------------------------------------------
+
+fn screen_rect(start: Vector2, end: Vector2) -> Rect2 {
+    let size = (end - start).abs();
+    let origin = Vector2::new(start.x.min(end.x), start.y.min(end.y));
+    Rect2::new(origin.to_point(), size.to_size())
+}
+
+/// The units hit by a click.
+/// A double click hits every unit on screen with the same role.
+fn click_hits(
+    camera: &Camera,
+    mouse_pos: Vector2,
+    units: &[(Entity, Vector3, i64, Role)],
+    double_click: bool,
+) -> Vec<Entity> {
+    let collider_id = camera.collider_id(mouse_pos, RAY_LENGTH, 4);
+    let clicked = units
+        .iter()
+        .find(|(_, _, instance_id, _)| Some(*instance_id) == collider_id);
+
+    let (ent, role) = match clicked {
+        Some((ent, _, _, role)) => (*ent, *role),
+        None => return Vec::new(),
+    };
+
+    if !double_click {
+        return vec![ent];
+    }
+
+    units
+        .iter()
+        .filter(|(_, pos, _, r)| *r == role && camera.is_on_screen(*pos))
+        .map(|(ent, ..)| *ent)
+        .collect()
+}
+
 fn select_units() -> Box<dyn Runnable> {
     SystemBuilder::new("mouse camera doda")
-        .read_resource::<MouseButton>()
-        .read_resource::<MousePos>()
+        .read_resource::<Gestures>()
         .read_resource::<Camera>()
         .write_resource::<SelectionBox>()
-        .write_resource::<Drag>()
-        .with_query(<Read<Pos>>::query().filter(tag::<PlayerId>()))
-        .build_thread_local(|cmd, world, resources, unit_positions| {
-            let (mouse_btn, mouse_pos, camera, selection_box, drag) = resources;
-            let selection_box = unsafe { selection_box.0.assume_safe() };
-
-            let mut pos = match camera.pos_from_camera(mouse_pos.global(), RAY_LENGTH, 2) {
-                Some(p) => p,
-                None => return,
-            };
-
-            if !mouse_btn.button_pressed(LMB) {
-                if let Drag::Start(start_pos) = drag as &mut Drag {
-                    // Selection
-                    let start_2d = Vector2::new(start_pos.x, start_pos.z).to_point();
-                    let end_2d = Vector2::new(pos.x, pos.z).to_point();
-                    let size = (start_2d - end_2d).abs();
-                    let point = Vector2::new(start_2d.x.min(end_2d.x), start_2d.y.min(end_2d.y));
-                    let selection = Rect2::new(point.to_point(), size.to_size());
-
-                    for (entity, _) in unit_positions.iter_entities(world) {
-                        cmd.remove_tag::<Selected>(entity);
-                    }
-
-                    for (entity, unit_pos) in unit_positions.iter_entities(world) {
-                        let unit_pos_2d = Vector2::new(unit_pos.0.x, unit_pos.0.z);
-                        if selection.contains(unit_pos_2d.to_point()) {
-                            cmd.add_tag(entity, Selected);
-                        }
-                    }
-                }
+        .with_query(<(Read<Pos>, Read<Unit>, Read<Role>)>::query().filter(tag::<PlayerId>()))
+        .with_query(<Read<Pos>>::query().filter(tag::<Selected>()))
+        .build_thread_local(|cmd, world, resources, (units, selected)| {
+            let (gestures, camera, selection_box) = resources;
+
+            let gestures = gestures
+                .iter(Layer::World)
+                .filter(|g| g.button == LMB)
+                .collect::<Vec<_>>();
 
-                unsafe { selection_box.set_scale(Vector3::zero()) };
-                drag.clear();
+            if gestures.len() == 0 {
                 return;
             }
 
-            pos.y = 1.0;
+            let units = units
+                .iter_entities(world)
+                .map(|(ent, (pos, unit, role))| (ent, pos.0, unit.instance_id(), *role))
+                .collect::<Vec<_>>();
 
-            match drag as &mut Drag {
-                Drag::Empty => {
-                    drag.set_start(pos);
-                    unsafe { selection_box.set_translation(pos) };
-                }
-                Drag::Start(start_pos) => {
-                    let mut size = pos - *start_pos;
-                    size.y = 0.3;
-                    selection_box.set_scale(size);
-                    let translation = pos - size / 2.;
-                    selection_box.set_translation(translation);
+            let mut selected = selected
+                .iter_entities(world)
+                .map(|(ent, _)| ent)
+                .collect::<Vec<_>>();
+
+            for gesture in gestures {
+                let hits = match gesture.kind {
+                    GestureKind::DragStart | GestureKind::DragMove => {
+                        selection_box.0 = Some(screen_rect(gesture.start, gesture.pos));
+                        continue;
+                    }
+                    GestureKind::DragEnd => {
+                        selection_box.0 = None;
+
+                        // Test the units against the box the player sees
+                        let selection = screen_rect(gesture.start, gesture.pos);
+
+                        units
+                            .iter()
+                            .filter(|(_, pos, ..)| match camera.screen_position(*pos) {
+                                Some(screen_pos) => selection.contains(screen_pos.to_point()),
+                                None => false,
+                            })
+                            .map(|(ent, ..)| *ent)
+                            .collect()
+                    }
+                    GestureKind::Click => click_hits(camera, gesture.pos, &units, false),
+                    GestureKind::DoubleClick => click_hits(camera, gesture.pos, &units, true),
+                    GestureKind::LongPress => continue,
+                };
+
+                let mode = SelectMode::from_gesture(gesture);
+
+                let was_selected = selected.drain(..).collect::<Vec<_>>();
+                for (ent, ..) in &units {
+                    let hit = hits.contains(ent);
+                    let select = match mode {
+                        SelectMode::Replace => hit,
+                        SelectMode::Add => was_selected.contains(ent) || hit,
+                        SelectMode::Toggle => was_selected.contains(ent) != hit,
+                    };
+
+                    match select {
+                        true => {
+                            cmd.add_tag(*ent, Selected);
+                            selected.push(*ent);
+                        }
+                        false => cmd.remove_tag::<Selected>(*ent),
+                    }
                 }
             }
         })
@@ -99,83 +289,157 @@ fn select_units() -> Box<dyn Runnable> {
 fn player_find_destinations() -> Box<dyn Runnable> {
     SystemBuilder::new("player find destination")
         .read_resource::<Camera>()
-        .write_resource::<MouseButton>()
+        .read_resource::<Gestures>()
         .read_resource::<MousePos>()
-        .with_query(<(Read<Pos>, Read<FormationPos>)>::query().filter(tag::<Selected>()))
-        .build_thread_local(|cmd, world, resources, positions| {
-            let (camera, mouse_btn, mouse_pos) = resources;
-
-            if !mouse_btn.button_pressed(RMB) {
-                return;
+        .read_resource::<AssignmentMode>()
+        .write_resource::<MoveOrder>()
+        .write_resource::<Orders>()
+        .with_query(
+            <(Read<Pos>, Read<FormationPos>, Read<SquadId>, Tagged<PlayerId>)>::query()
+                .filter(tag::<Selected>()),
+        )
+        .with_query(<Read<Squad>>::query())
+        .build_thread_local(|_, world, resources, (positions, squads)| {
+            let (camera, gestures, mouse_pos, assignment_mode, move_order, orders) = resources;
+            move_order.preview.clear();
+
+            // Where the drag started is the formation centre,
+            // a click orders the group to the clicked spot
+            let mut release = None;
+            for gesture in gestures.iter(Layer::World).filter(|g| g.button == RMB) {
+                match gesture.kind {
+                    GestureKind::DragStart => {
+                        move_order.start = camera.pos_from_camera(gesture.start, RAY_LENGTH, 2);
+                    }
+                    GestureKind::Click | GestureKind::DoubleClick => {
+                        move_order.start = camera.pos_from_camera(gesture.pos, RAY_LENGTH, 2);
+                        release = Some(gesture.pos);
+                    }
+                    GestureKind::DragEnd => release = Some(gesture.pos),
+                    _ => {}
+                }
             }
 
-            mouse_btn.consume();
-
-            let dest_pos = match camera.pos_from_camera(mouse_pos.global(), RAY_LENGTH, 2) {
+            let centre = match move_order.start {
                 Some(p) => p,
                 None => return,
             };
 
-            let positions = positions
+            let released = release.is_some();
+            if released {
+                move_order.start = None;
+            }
+            let mouse_pos = release.unwrap_or(mouse_pos.global());
+
+            let marching = squads
                 .iter_entities(world)
-                .map(|(ent, (pos, formation_pos))| (ent, pos.0, formation_pos.0))
+                .filter(|(_, squad)| squad.march)
+                .map(|(ent, _)| ent)
+                .collect::<Vec<_>>();
+
+            let positions = positions
+                .iter(world)
+                .map(|(pos, formation_pos, squad_id, player_id)| {
+                    let march = marching.contains(&squad_id.0);
+                    (*player_id, pos.0, (squad_id.0, formation_pos.0), march)
+                })
                 .collect::<Vec<_>>();
 
             if positions.len() == 0 {
                 return;
             }
 
-            let (offset, rotation) = {
-                let mut offset_x = 0;
-                let mut offset_y = usize::MAX;
-                let mut dir = Vector2::zero();
+            let count = positions.len() as f32;
+            let group_centre =
+                positions.iter().fold(Vector3::zero(), |acc, (_, pos, ..)| acc + *pos) / count;
+
+            // Drag: the facing (and optionally the width)
+            let drag_end = camera
+                .pos_from_camera(mouse_pos, RAY_LENGTH, 2)
+                .unwrap_or(centre);
+            let drag = to_2d(drag_end - centre);
+
+            let (facing, width) = if drag.length() >= MIN_DRAG_LEN {
+                let width = match move_order.width_from_drag {
+                    true => Some(drag.length()),
+                    false => None,
+                };
+                (drag, width)
+            } else {
+                // No drag, face away from where the group is now
+                (to_2d(centre - group_centre), None)
+            };
 
-                // Find the max x and the correct y
-                for (_, pos, formation_pos) in &positions {
-                    let (x, y) = index_to_x_y(*formation_pos as usize);
+            let indices = positions
+                .iter()
+                .map(|(_, _, squad_index, ..)| *squad_index)
+                .collect::<Vec<_>>();
+            let slots = formation_slots(&indices, centre, facing, width);
 
-                    if x > offset_x {
-                        offset_x = x;
-                        offset_y = y;
-                        dir = to_2d(dest_pos - *pos);
-                    }
-                    if y < offset_y {
-                        offset_y = y;
-                        dir = to_2d(dest_pos - *pos);
-                    }
-                }
+            if !released {
+                // Ghost preview, shown by the presentation systems
+                move_order.preview = slots;
+                move_order.facing = facing;
+                return;
+            }
 
-                (
-                    Vector2::new(offset_x as f32, offset_y as f32),
-                    Rotation2::radians(dir.y.atan2(dir.x)),
-                )
-            };
+            let cost = positions
+                .iter()
+                .map(|(_, pos, ..)| {
+                    slots
+                        .iter()
+                        .map(|slot| (to_2d(*slot) - to_2d(*pos)).length())
+                        .collect()
+                })
+                .collect::<Vec<Vec<f32>>>();
+
+            let assignment = assignment_mode
+                .solve(&cost)
+                .unwrap_or_else(|| (0..positions.len()).collect());
+
+            let unit_slots = positions
+                .iter()
+                .zip(assignment)
+                .map(|((player_id, ..), slot)| (*player_id, slots[slot]))
+                .collect::<Vec<_>>();
 
-            for (ent, _, formation_pos) in &positions {
-                let (x, y) = index_to_x_y(*formation_pos as usize);
-                let formation_pos = (Vector2::new(x as f32, y as f32) - offset) * OFFSET_MUL;
-                let formation_pos = rotation.transform_vector(formation_pos);
-                let new_dest = dest_pos + to_3d(formation_pos);
-                cmd.add_component(*ent, Destination(new_dest));
-            }
+            // March in formation only if every squad in the group wants to,
+            // either way it's carried out on the next tick
+            let order = match positions.iter().all(|(.., march)| *march) {
+                true => Order::March {
+                    start: group_centre,
+                    centre,
+                    slots: unit_slots,
+                },
+                false => Order::Move(unit_slots),
+            };
+            orders.push(order);
         })
 }
 
 fn player_open_context_menu() -> Box<dyn Runnable> {
     SystemBuilder::new("player open context menu")
         .read_resource::<Camera>()
-        .write_resource::<MouseButton>()
-        .read_resource::<MousePos>()
+        .read_resource::<Gestures>()
+        .read_resource::<Gamepad>()
         .with_query(<(Read<Unit>, Write<ContextMenuNode>)>::query().filter(tag::<PlayerId>()))
         .build_thread_local(|cmd, world, resources, units| {
-            let (camera, mouse_btn, mouse_pos) = resources;
+            let (camera, gestures, gamepad) = resources;
 
-            if !mouse_btn.button_pressed(RMB) {
+            // The gamepad gets the radial menu instead
+            if gamepad.is_active() {
                 return;
             }
 
-            // If the unit is clicked then consume the input
-            let dict = camera.object_from_camera(mouse_pos.global(), RAY_LENGTH, 4);
+            let mouse_pos = match gestures
+                .iter(Layer::World)
+                .find(|g| g.is(GestureKind::Click, RMB))
+            {
+                Some(gesture) => gesture.pos,
+                None => return,
+            };
+
+            let dict = camera.object_from_camera(mouse_pos, RAY_LENGTH, 4);
             if dict.is_empty() {
                 units
                     .iter_mut(world)
@@ -194,7 +458,7 @@ fn player_open_context_menu() -> Box<dyn Runnable> {
 
                 let instance_id = unit.get_instance_id();
                 if instance_id == collider_id {
-                    menu.set_position(mouse_pos.global(), false);
+                    menu.set_position(mouse_pos, false);
                     menu.set_visible(true);
                 } else {
                     menu.set_visible(false);
@@ -232,3 +496,38 @@ pub fn player_systems(builder: Builder) -> Builder {
         .add_thread_local(player_find_destinations())
         .add_thread_local(something_clicked())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn squads_are_side_by_side() {
+        let coords = squad_grid(&[(1, 0), (1, 5), (2, 0)]);
+        assert_eq!(
+            coords,
+            vec![
+                Vector2::new(0., 0.),
+                Vector2::new(1., 1.),
+                Vector2::new(3., 0.)
+            ]
+        );
+    }
+
+    #[test]
+    fn two_squads_dont_share_slots() {
+        let units = [(1, 0), (1, 1), (2, 0), (2, 1)];
+        let slots = formation_slots(&units, Vector3::zero(), Vector2::zero(), None);
+
+        for (i, a) in slots.iter().enumerate() {
+            for b in &slots[i + 1..] {
+                assert!((*a - *b).length() >= OFFSET_MUL);
+            }
+        }
+
+        // Each squad keeps its own shape
+        let first = slots[1] - slots[0];
+        let second = slots[3] - slots[2];
+        assert!((first - second).length() < 1e-5);
+    }
+}
@@ -0,0 +1,301 @@
+use gdextras::node_ext::NodeExt;
+use gdnative::api::{Control, MeshInstance, ProgressBar, Spatial, SpatialMaterial};
+use gdnative::{Color, Ptr, Vector2, Vector3};
+use legion::prelude::*;
+use legion::systems::schedule::Builder;
+
+use crate::camera::{Camera, SelectionBox, RAY_LENGTH};
+use crate::gamepad::Gamepad;
+use crate::input::MousePos;
+use crate::movement::Pos;
+use crate::player::{MoveOrder, Selected};
+use crate::spawner;
+use crate::unit::{Health, Unit};
+
+const SELECTED_COLOR: Color = Color { r: 0.2, g: 1., b: 0.3, a: 0.6 };
+const HOVER_COLOR: Color = Color { r: 1., g: 1., b: 1., a: 0.4 };
+const HOVER_SELECTED_COLOR: Color = Color { r: 0.6, g: 1., b: 0.6, a: 0.8 };
+
+// Distance above the unit's origin to the bar
+const BAR_OFFSET: f32 = 2.2;
+const BAR_WIDTH: f32 = 32.;
+const CURSOR_SIZE: f32 = 12.;
+
+fn ring_color(selected: bool, hovered: bool) -> Option<Color> {
+    match (selected, hovered) {
+        (true, true) => Some(HOVER_SELECTED_COLOR),
+        (true, false) => Some(SELECTED_COLOR),
+        (false, true) => Some(HOVER_COLOR),
+        (false, false) => None,
+    }
+}
+
+// Red when empty, green when full
+fn bar_color(fraction: f32) -> Color {
+    Color::rgb(1. - fraction, fraction, 0.2)
+}
+
+// -----------------------------------------------------------------------------
+//     - Tags -
+// -----------------------------------------------------------------------------
+/// The unit under the mouse cursor
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hovered;
+
+// -----------------------------------------------------------------------------
+//     - Components -
+// -----------------------------------------------------------------------------
+/// The ring and bar nodes of a unit.
+/// Only the presentation systems should touch these.
+pub struct UnitOverlay {
+    inner: Ptr<Spatial>,
+    ring: Option<Color>,
+}
+
+impl UnitOverlay {
+    pub fn new(inner: Ptr<Spatial>) -> Self {
+        Self { inner, ring: None }
+    }
+
+    fn set_ring(&mut self, ring: Option<Color>) {
+        // Don't create a new material every frame
+        if self.ring == ring {
+            return;
+        }
+        self.ring = ring;
+
+        let overlay = unsafe { self.inner.assume_safe() };
+        let mesh = overlay.get_and_cast::<MeshInstance>("Ring");
+
+        let color = match ring {
+            Some(c) => c,
+            None => {
+                mesh.set_visible(false);
+                return;
+            }
+        };
+
+        let material = SpatialMaterial::new();
+        material.set_albedo(color);
+        material.set_flag(SpatialMaterial::FLAG_UNSHADED, true);
+        material.set_feature(SpatialMaterial::FEATURE_TRANSPARENT, true);
+        mesh.set_material_override(Some(material.to_material()));
+        mesh.set_visible(true);
+    }
+
+    fn set_bar(&mut self, bar: Option<(Vector2, f32)>) {
+        let overlay = unsafe { self.inner.assume_safe() };
+        let progress = overlay.get_and_cast::<ProgressBar>("Bar");
+
+        match bar {
+            Some((screen_pos, fraction)) => {
+                progress.set_position(screen_pos - Vector2::new(BAR_WIDTH / 2., 0.), false);
+                progress.set_value(fraction as f64);
+                progress.set_self_modulate(bar_color(fraction));
+                progress.set_visible(true);
+            }
+            None => progress.set_visible(false),
+        }
+    }
+}
+
+unsafe impl Send for UnitOverlay {}
+unsafe impl Sync for UnitOverlay {}
+
+// -----------------------------------------------------------------------------
+//     - Resources -
+// -----------------------------------------------------------------------------
+/// Ghost markers for the slots of a move order while it's being dragged out.
+/// Markers are kept around and hidden when not in use.
+pub struct MovePreview {
+    parent: Ptr<Spatial>,
+    markers: Vec<Ptr<Spatial>>,
+}
+
+impl MovePreview {
+    pub fn new(parent: Ptr<Spatial>) -> Self {
+        Self {
+            parent,
+            markers: Vec::new(),
+        }
+    }
+
+    fn show(&mut self, slots: &[Vector3], facing: Vector2) {
+        let parent = unsafe { self.parent.assume_safe() };
+        while self.markers.len() < slots.len() {
+            let marker = spawner::spawn_move_marker();
+            parent.add_child(Some(unsafe { marker.assume_safe() }.to_node()), false);
+            self.markers.push(marker);
+        }
+
+        // The markers point along +Z
+        let angle = facing.x.atan2(facing.y);
+        for (i, marker) in self.markers.iter().enumerate() {
+            let marker = unsafe { marker.assume_safe() };
+            match slots.get(i) {
+                Some(slot) => {
+                    marker.set_translation(*slot);
+                    marker.set_rotation(Vector3::new(0., angle, 0.));
+                    marker.set_visible(true);
+                }
+                None => marker.set_visible(false),
+            }
+        }
+    }
+}
+
+unsafe impl Send for MovePreview {}
+unsafe impl Sync for MovePreview {}
+
+/// The node showing the box selection
+pub struct SelectionRect(pub Ptr<Control>);
+
+unsafe impl Send for SelectionRect {}
+unsafe impl Sync for SelectionRect {}
+
+/// The node showing the gamepad's virtual cursor
+pub struct GamepadCursor(pub Ptr<Control>);
+
+unsafe impl Send for GamepadCursor {}
+unsafe impl Sync for GamepadCursor {}
+
+/// When to show the bars above the units
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverheadBars {
+    Never,
+    Damaged,
+    Selected,
+    Always,
+}
+
+impl OverheadBars {
+    /// Cycle through the options
+    pub fn next(self) -> Self {
+        match self {
+            Self::Never => Self::Damaged,
+            Self::Damaged => Self::Selected,
+            Self::Selected => Self::Always,
+            Self::Always => Self::Never,
+        }
+    }
+
+    fn show(self, selected: bool, health: &Health) -> bool {
+        match self {
+            Self::Never => false,
+            Self::Damaged => health.is_damaged(),
+            Self::Selected => selected || health.is_damaged(),
+            Self::Always => true,
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Systems -
+// -----------------------------------------------------------------------------
+fn hover_units() -> Box<dyn Runnable> {
+    SystemBuilder::new("hover units")
+        .read_resource::<Camera>()
+        .read_resource::<MousePos>()
+        .with_query(<Read<Unit>>::query())
+        .with_query(<Read<Unit>>::query().filter(tag::<Hovered>()))
+        .build_thread_local(|cmd, world, (camera, mouse_pos), (units, hovered)| {
+            let collider_id = camera.collider_id(mouse_pos.global(), RAY_LENGTH, 4);
+
+            let hit = collider_id.and_then(|id| {
+                units
+                    .iter_entities(world)
+                    .find(|(_, unit)| unit.instance_id() == id)
+                    .map(|(ent, _)| ent)
+            });
+
+            let mut already_hovered = false;
+            for (ent, _) in hovered.iter_entities(world) {
+                match Some(ent) == hit {
+                    true => already_hovered = true,
+                    false => cmd.remove_tag::<Hovered>(ent),
+                }
+            }
+
+            if let (Some(ent), false) = (hit, already_hovered) {
+                cmd.add_tag(ent, Hovered);
+            }
+        })
+}
+
+fn sync_unit_overlays() -> Box<dyn Runnable> {
+    SystemBuilder::new("sync unit overlays")
+        .read_resource::<Camera>()
+        .read_resource::<OverheadBars>()
+        .with_query(<(Write<UnitOverlay>, Read<Pos>, Read<Health>)>::query())
+        .with_query(<Read<UnitOverlay>>::query().filter(tag::<Selected>()))
+        .with_query(<Read<UnitOverlay>>::query().filter(tag::<Hovered>()))
+        .build_thread_local(|_, world, (camera, bars), (overlays, selected, hovered)| {
+            let selected = selected.iter_entities(world).map(|(ent, _)| ent).collect::<Vec<_>>();
+            let hovered = hovered.iter_entities(world).map(|(ent, _)| ent).collect::<Vec<_>>();
+
+            for (ent, (mut overlay, pos, health)) in overlays.iter_entities_mut(world) {
+                let is_selected = selected.contains(&ent);
+                overlay.set_ring(ring_color(is_selected, hovered.contains(&ent)));
+
+                let bar = match bars.show(is_selected, &health) {
+                    true => camera
+                        .screen_position(pos.0 + Vector3::new(0., BAR_OFFSET, 0.))
+                        .map(|screen_pos| (screen_pos, health.fraction())),
+                    false => None,
+                };
+                overlay.set_bar(bar);
+            }
+        })
+}
+
+fn show_move_preview() -> Box<dyn Runnable> {
+    SystemBuilder::new("show move preview")
+        .read_resource::<MoveOrder>()
+        .write_resource::<MovePreview>()
+        .build_thread_local(|_, _, (move_order, preview), _| {
+            preview.show(&move_order.preview, move_order.facing);
+        })
+}
+
+fn show_selection_box() -> Box<dyn Runnable> {
+    SystemBuilder::new("show selection box")
+        .read_resource::<SelectionBox>()
+        .write_resource::<SelectionRect>()
+        .build_thread_local(|_, _, (selection_box, selection_rect), _| {
+            let node = unsafe { selection_rect.0.assume_safe() };
+            match selection_box.0 {
+                Some(rect) => {
+                    node.set_position(rect.origin, false);
+                    node.set_size(rect.size, false);
+                    node.set_visible(true);
+                }
+                None => node.set_visible(false),
+            }
+        })
+}
+
+fn show_gamepad_cursor() -> Box<dyn Runnable> {
+    SystemBuilder::new("show gamepad cursor")
+        .read_resource::<Gamepad>()
+        .write_resource::<GamepadCursor>()
+        .build_thread_local(|_, _, (gamepad, gamepad_cursor), _| {
+            let node = unsafe { gamepad_cursor.0.assume_safe() };
+            match gamepad.cursor() {
+                Some(cursor) => {
+                    let half = Vector2::new(CURSOR_SIZE / 2., CURSOR_SIZE / 2.);
+                    node.set_position(cursor - half, false);
+                    node.set_visible(true);
+                }
+                None => node.set_visible(false),
+            }
+        })
+}
+
+pub fn presentation_systems(builder: Builder) -> Builder {
+    builder
+        .add_thread_local(hover_units())
+        .add_thread_local(sync_unit_overlays())
+        .add_thread_local(show_move_preview())
+        .add_thread_local(show_selection_box())
+        .add_thread_local(show_gamepad_cursor())
+}
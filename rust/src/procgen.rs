@@ -1,8 +1,23 @@
 use gdnative::Vector2;
 use std::hash::Hasher;
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::u32;
 use twox_hash::XxHash;
 
+/// The seed the world was generated with
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WorldSeed(pub u64);
+
+impl WorldSeed {
+    pub fn from_time() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_nanos() as u64)
+            .unwrap_or(0);
+        Self(nanos)
+    }
+}
+
 pub fn pack_vec2(pos: Vector2) -> u64 {
     pack(pos.x as i32, pos.y as i32)
 }
@@ -28,3 +43,213 @@ pub fn random_bool(seed: u64, weight: usize) -> bool {
     let val = hash as usize % 10000;
     val <= weight
 }
+
+/// Numbers from a seed, the same seed always gives the same numbers
+pub struct Rng {
+    seed: u64,
+    count: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self { seed, count: 0 }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut hasher = XxHash::with_seed(self.seed);
+        hasher.write_u64(self.count);
+        self.count += 1;
+        hasher.finish()
+    }
+
+    /// From `min` up to, but not including, `max`
+    pub fn range(&mut self, min: i32, max: i32) -> i32 {
+        if max <= min {
+            return min;
+        }
+        min + (self.next_u64() % (max - min) as u64) as i32
+    }
+
+    pub fn chance(&mut self, percent: u32) -> bool {
+        self.next_u64() % 100 < percent as u64
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Noise -
+// -----------------------------------------------------------------------------
+/// A value between 0 and 1 for every whole coordinate
+fn lattice(seed: u64, x: i32, y: i32) -> f32 {
+    let mut hasher = XxHash::with_seed(seed);
+    hasher.write_u64(pack(x, y));
+    (hasher.finish() >> 40) as f32 / (1u64 << 24) as f32
+}
+
+fn smoothstep(t: f32) -> f32 {
+    t * t * (3. - 2. * t)
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+/// Smoothly interpolated between the lattice values, between 0 and 1
+pub fn value_noise(seed: u64, x: f32, y: f32) -> f32 {
+    let (x0, y0) = (x.floor(), y.floor());
+    let (tx, ty) = (smoothstep(x - x0), smoothstep(y - y0));
+    let (x0, y0) = (x0 as i32, y0 as i32);
+
+    let top = lerp(lattice(seed, x0, y0), lattice(seed, x0 + 1, y0), tx);
+    let bottom = lerp(lattice(seed, x0, y0 + 1), lattice(seed, x0 + 1, y0 + 1), tx);
+    lerp(top, bottom, ty)
+}
+
+/// Octaves of value noise, each twice the detail of the last.
+/// Still between 0 and 1.
+pub fn fractal_noise(seed: u64, x: f32, y: f32, octaves: u32, persistence: f32) -> f32 {
+    let mut total = 0.;
+    let mut amplitude = 1.;
+    let mut max = 0.;
+    let mut frequency = 1.;
+
+    for octave in 0..octaves {
+        let octave_seed = seed.wrapping_add(octave as u64);
+        total += value_noise(octave_seed, x * frequency, y * frequency) * amplitude;
+        max += amplitude;
+        amplitude *= persistence;
+        frequency *= 2.;
+    }
+
+    match max > 0. {
+        true => total / max,
+        false => 0.,
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Terrain -
+// -----------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TerrainConfig {
+    /// Cells per unit of noise, bigger is smoother
+    pub scale: f32,
+    pub octaves: u32,
+    pub persistence: f32,
+    /// Heights below this are water
+    pub water_level: f32,
+    /// Heights above this are rock
+    pub rock_level: f32,
+}
+
+impl Default for TerrainConfig {
+    fn default() -> Self {
+        Self {
+            scale: 24.,
+            octaves: 4,
+            persistence: 0.5,
+            water_level: 0.3,
+            rock_level: 0.7,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Biome {
+    Water,
+    Ground,
+    Rock,
+}
+
+impl Biome {
+    pub fn classify(height: f32, config: &TerrainConfig) -> Self {
+        if height < config.water_level {
+            Biome::Water
+        } else if height > config.rock_level {
+            Biome::Rock
+        } else {
+            Biome::Ground
+        }
+    }
+
+    /// The GridMap mesh library item, water is the thin flat tile
+    pub fn item(&self) -> i64 {
+        match self {
+            Biome::Water => 3,
+            Biome::Ground => 0,
+            Biome::Rock => 1,
+        }
+    }
+
+    pub fn is_walkable(&self) -> bool {
+        *self == Biome::Ground
+    }
+}
+
+/// The height of a cell, between 0 and 1
+pub fn height(seed: u64, x: i32, z: i32, config: &TerrainConfig) -> f32 {
+    let x = x as f32 / config.scale;
+    let z = z as f32 / config.scale;
+    fractal_noise(seed, x, z, config.octaves, config.persistence)
+}
+
+/// Same seed, same cell, same biome
+pub fn terrain_cell(seed: u64, x: i32, z: i32, config: &TerrainConfig) -> Biome {
+    Biome::classify(height(seed, x, z, config), config)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn noise_is_deterministic() {
+        for (x, y) in vec![(0.5, 0.25), (-10.3, 4.), (100., -7.9)] {
+            assert_eq!(value_noise(3, x, y), value_noise(3, x, y));
+        }
+
+        let config = TerrainConfig::default();
+        let a = (0..32).map(|x| height(1, x, -x, &config)).collect::<Vec<_>>();
+        let b = (0..32).map(|x| height(1, x, -x, &config)).collect::<Vec<_>>();
+        let c = (0..32).map(|x| height(2, x, -x, &config)).collect::<Vec<_>>();
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+    }
+
+    #[test]
+    fn noise_is_smooth_and_in_range() {
+        for i in -200..200 {
+            let x = i as f32 * 0.05;
+            let here = fractal_noise(9, x, 1.5, 4, 0.5);
+            let next = fractal_noise(9, x + 0.01, 1.5, 4, 0.5);
+            assert!(here >= 0. && here <= 1.);
+            assert!((here - next).abs() < 0.1);
+        }
+    }
+
+    #[test]
+    fn noise_matches_the_lattice() {
+        assert_eq!(value_noise(5, 2., -3.), lattice(5, 2, -3));
+    }
+
+    #[test]
+    fn rng_stays_in_range() {
+        let mut a = Rng::new(4);
+        let mut b = Rng::new(4);
+        for _ in 0..100 {
+            let n = a.range(-3, 5);
+            assert!(n >= -3 && n < 5);
+            assert_eq!(n, b.range(-3, 5));
+        }
+        assert_eq!(a.range(2, 2), 2);
+    }
+
+    #[test]
+    fn biomes_follow_height() {
+        let config = TerrainConfig::default();
+        assert_eq!(Biome::classify(0.1, &config), Biome::Water);
+        assert_eq!(Biome::classify(0.5, &config), Biome::Ground);
+        assert_eq!(Biome::classify(0.9, &config), Biome::Rock);
+        assert_ne!(Biome::Water.item(), Biome::Ground.item());
+        assert!(!Biome::Water.is_walkable());
+    }
+}
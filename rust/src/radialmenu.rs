@@ -0,0 +1,215 @@
+use std::f32::consts::PI;
+
+use gdnative::api::Control;
+use gdnative::{Color, Ptr, Rect2, Vector2};
+use legion::prelude::*;
+use legion::systems::schedule::Builder;
+
+use crate::camera::{Camera, RAY_LENGTH};
+use crate::gamepad::Gamepad;
+use crate::gameworld::ClickedState;
+use crate::gesture::{GestureKind, Gestures, UiRects};
+use crate::input::{Layer, MousePos, LMB, RMB};
+use crate::player::PlayerId;
+use crate::unit::Unit;
+
+// Distance from the centre to the options, in pixels
+const RADIUS: f32 = 80.;
+// Closer to the centre than this picks nothing
+const DEAD_RADIUS: f32 = 16.;
+const HIGHLIGHT_COLOR: Color = Color { r: 1.6, g: 1.6, b: 1.6, a: 1. };
+const UI_RECT: &str = "radial menu";
+
+/// The direction of an option, the first one is straight up
+/// and the rest go clockwise
+fn option_dir(index: usize, count: usize) -> Vector2 {
+    let angle = index as f32 * 2. * PI / count as f32;
+    Vector2::new(angle.sin(), -angle.cos())
+}
+
+/// The option pointed at by `offset` from the centre of the menu
+pub fn option_at(offset: Vector2, count: usize) -> Option<usize> {
+    if count == 0 || offset.length() < DEAD_RADIUS {
+        return None;
+    }
+
+    let slice = 2. * PI / count as f32;
+    let angle = offset.x.atan2(-offset.y);
+    Some(((angle / slice).round() as i32).rem_euclid(count as i32) as usize)
+}
+
+// -----------------------------------------------------------------------------
+//     - Resources -
+// -----------------------------------------------------------------------------
+/// The gamepad version of the context menu.
+/// The options are laid out in a circle, point at one and click.
+pub struct RadialMenu {
+    inner: Ptr<Control>,
+    centre: Option<Vector2>,
+}
+
+unsafe impl Send for RadialMenu {}
+unsafe impl Sync for RadialMenu {}
+
+impl RadialMenu {
+    pub fn new(inner: Ptr<Control>) -> Self {
+        Self {
+            inner,
+            centre: None,
+        }
+    }
+
+    fn options(&self) -> Vec<Ptr<Control>> {
+        let menu = unsafe { self.inner.assume_safe() };
+        (0..menu.get_child_count())
+            .filter_map(|i| menu.get_child(i))
+            .filter_map(|child| unsafe { child.assume_safe() }.cast::<Control>())
+            .map(|option| option.claim())
+            .collect()
+    }
+
+    fn open(&mut self, centre: Vector2) {
+        let menu = unsafe { self.inner.assume_safe() };
+        menu.set_position(centre, false);
+        menu.set_visible(true);
+
+        let options = self.options();
+        for (i, option) in options.iter().enumerate() {
+            let option = unsafe { option.assume_safe() };
+            let pos = option_dir(i, options.len()) * RADIUS - option.size() / 2.;
+            option.set_position(pos, false);
+        }
+
+        self.centre = Some(centre);
+    }
+
+    fn close(&mut self) {
+        let menu = unsafe { self.inner.assume_safe() };
+        menu.set_visible(false);
+        self.centre = None;
+    }
+
+    fn highlight(&self, index: Option<usize>) {
+        for (i, option) in self.options().iter().enumerate() {
+            let option = unsafe { option.assume_safe() };
+            match Some(i) == index {
+                true => option.set_modulate(HIGHLIGHT_COLOR),
+                false => option.set_modulate(Color::rgb(1., 1., 1.)),
+            }
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Systems -
+// -----------------------------------------------------------------------------
+fn choose_radial_option() -> Box<dyn Runnable> {
+    SystemBuilder::new("choose radial option")
+        .read_resource::<Gestures>()
+        .read_resource::<MousePos>()
+        .write_resource::<RadialMenu>()
+        .write_resource::<UiRects>()
+        .write_resource::<ClickedState>()
+        .build_thread_local(|_, _, resources, _| {
+            let (gestures, mouse_pos, radial_menu, ui_rects, clicked_state) = resources;
+
+            let centre = match radial_menu.centre {
+                Some(c) => c,
+                None => return,
+            };
+
+            let count = radial_menu.options().len();
+            radial_menu.highlight(option_at(mouse_pos.global() - centre, count));
+
+            let clicked = gestures.iter(Layer::Ui).find(|g| match g.kind {
+                GestureKind::Click | GestureKind::DoubleClick => true,
+                _ => false,
+            });
+
+            // Any click closes the menu, only LMB on an option picks it
+            if let Some(gesture) = clicked {
+                let option = option_at(gesture.pos - centre, count);
+                if gesture.button == LMB && option.is_some() {
+                    clicked_state.clicked = true;
+                }
+
+                radial_menu.close();
+                ui_rects.set(UI_RECT, None);
+            }
+        })
+}
+
+fn open_radial_menu() -> Box<dyn Runnable> {
+    SystemBuilder::new("open radial menu")
+        .read_resource::<Camera>()
+        .read_resource::<Gamepad>()
+        .read_resource::<Gestures>()
+        .write_resource::<RadialMenu>()
+        .write_resource::<UiRects>()
+        .with_query(<Read<Unit>>::query().filter(tag::<PlayerId>()))
+        .build_thread_local(|_, world, resources, units| {
+            let (camera, gamepad, gestures, radial_menu, ui_rects) = resources;
+
+            // Back to the mouse, back to the context menu
+            if !gamepad.is_active() {
+                if radial_menu.centre.is_some() {
+                    radial_menu.close();
+                    ui_rects.set(UI_RECT, None);
+                }
+                return;
+            }
+
+            if radial_menu.centre.is_some() {
+                return;
+            }
+
+            let mouse_pos = match gestures
+                .iter(Layer::World)
+                .find(|g| g.is(GestureKind::Click, RMB))
+            {
+                Some(gesture) => gesture.pos,
+                None => return,
+            };
+
+            let collider_id = camera.collider_id(mouse_pos, RAY_LENGTH, 4);
+            if !units.iter(world).any(|unit| Some(unit.instance_id()) == collider_id) {
+                return;
+            }
+
+            let size = match camera.viewport_size() {
+                Some(s) => s,
+                None => return,
+            };
+
+            // The menu takes every click until it's closed
+            radial_menu.open(mouse_pos);
+            ui_rects.set(UI_RECT, Some(Rect2::new(Vector2::zero().to_point(), size.to_size())));
+        })
+}
+
+pub fn radial_menu_systems(builder: Builder) -> Builder {
+    builder
+        .add_thread_local(choose_radial_option())
+        .add_thread_local(open_radial_menu())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn options_go_clockwise_from_the_top() {
+        assert_eq!(option_at(Vector2::new(0., -50.), 4), Some(0));
+        assert_eq!(option_at(Vector2::new(50., 0.), 4), Some(1));
+        assert_eq!(option_at(Vector2::new(0., 50.), 4), Some(2));
+        assert_eq!(option_at(Vector2::new(-50., 0.), 4), Some(3));
+        assert_eq!(option_at(Vector2::new(-50., -40.), 4), Some(3));
+        assert_eq!(option_at(Vector2::new(-40., -50.), 4), Some(0));
+    }
+
+    #[test]
+    fn centre_picks_nothing() {
+        assert_eq!(option_at(Vector2::new(2., 2.), 4), None);
+        assert_eq!(option_at(Vector2::new(0., -50.), 0), None);
+    }
+}
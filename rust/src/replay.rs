@@ -0,0 +1,359 @@
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::Result;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use gdnative::api::OS;
+use gdnative::Vector3;
+use lazy_static::lazy_static;
+use legion::prelude::*;
+use serde::{Deserialize, Serialize};
+use twox_hash::XxHash;
+
+use crate::formation::FormationPos;
+use crate::movement::{Pos, Velocity};
+use crate::orders::{Order, OrderTarget};
+use crate::player::PlayerId;
+use crate::unit::Health;
+
+// Differences smaller than this don't change the checksum
+const QUANTUM: f32 = 1e-3;
+
+fn file_path(name: &str) -> PathBuf {
+    let os = OS::godot_singleton();
+    let mut path = PathBuf::from(os.get_user_data_dir().to_string());
+    path.push(format!("{}.replay.json", name));
+    path
+}
+
+fn write_vec3(hasher: &mut XxHash, v: Vector3) {
+    hasher.write_i64((v.x / QUANTUM).round() as i64);
+    hasher.write_i64((v.y / QUANTUM).round() as i64);
+    hasher.write_i64((v.z / QUANTUM).round() as i64);
+}
+
+/// Hash of the simulation state of the player units, two worlds that were
+/// given the same orders should have the same checksum every tick
+pub fn world_checksum(world: &World) -> u64 {
+    let query = <(
+        Read<Pos>,
+        Read<Velocity>,
+        Read<FormationPos>,
+        Read<Health>,
+        Tagged<PlayerId>,
+    )>::query();
+    let mut units = query
+        .iter_entities(world)
+        .map(|(ent, (pos, velocity, formation_pos, health, player_id))| {
+            let target = world
+                .get_component::<OrderTarget>(ent)
+                .map(|target| target.0);
+            let state = (pos.0, velocity.0, formation_pos.0, health.current);
+            (*player_id, state, target)
+        })
+        .collect::<Vec<_>>();
+    // The order the units are stored in isn't part of the state
+    units.sort_by_key(|(player_id, ..)| *player_id);
+
+    let mut hasher = XxHash::with_seed(0);
+    for (player_id, (pos, velocity, formation_pos, health), target) in units {
+        player_id.hash(&mut hasher);
+        write_vec3(&mut hasher, pos);
+        write_vec3(&mut hasher, velocity);
+        hasher.write_u16(formation_pos);
+        hasher.write_u32(health.to_bits());
+        match target {
+            Some(target) => {
+                hasher.write_u8(1);
+                write_vec3(&mut hasher, target);
+            }
+            None => hasher.write_u8(0),
+        }
+    }
+
+    hasher.finish()
+}
+
+// -----------------------------------------------------------------------------
+//     - Recording -
+// -----------------------------------------------------------------------------
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Recording {
+    pub seed: u64,
+    /// Orders, and the tick that carried them out
+    orders: Vec<(u64, Order)>,
+    /// The world checksum at the end of every tick
+    checksums: Vec<u64>,
+}
+
+impl Recording {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            orders: Vec::new(),
+            checksums: Vec::new(),
+        }
+    }
+
+    pub fn load(name: &str) -> Result<Self> {
+        let file = File::open(file_path(name))?;
+        Ok(serde_json::from_reader(&file)?)
+    }
+
+    pub fn save(&self, name: &str) -> Result<()> {
+        let mut file = File::create(file_path(name))?;
+        serde_json::to_writer(&mut file, self)?;
+        Ok(())
+    }
+}
+
+enum ReplayState {
+    Idle,
+    Recording(Recording),
+    Playing {
+        recording: Recording,
+        next_order: usize,
+        desync: Option<u64>,
+    },
+}
+
+// -----------------------------------------------------------------------------
+//     - Resources -
+// -----------------------------------------------------------------------------
+/// Records the orders, or plays them back and checks the world against the
+/// recorded checksums.
+/// Only the orders are replayed, the camera and the selection are left to
+/// whoever is watching.
+pub struct Replay {
+    tick: u64,
+    state: ReplayState,
+}
+
+impl Replay {
+    pub fn new() -> Self {
+        Self {
+            tick: 0,
+            state: ReplayState::Idle,
+        }
+    }
+
+    pub fn recording(seed: u64) -> Self {
+        Self {
+            tick: 0,
+            state: ReplayState::Recording(Recording::new(seed)),
+        }
+    }
+
+    pub fn playing(recording: Recording) -> Self {
+        Self {
+            tick: 0,
+            state: ReplayState::Playing {
+                recording,
+                next_order: 0,
+                desync: None,
+            },
+        }
+    }
+
+    pub fn is_idle(&self) -> bool {
+        match self.state {
+            ReplayState::Idle => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        match self.state {
+            ReplayState::Playing { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// The seed of the world being recorded or played back
+    pub fn seed(&self) -> Option<u64> {
+        match &self.state {
+            ReplayState::Idle => None,
+            ReplayState::Recording(recording) => Some(recording.seed),
+            ReplayState::Playing { recording, .. } => Some(recording.seed),
+        }
+    }
+
+    /// The first tick where the world didn't match the recording
+    pub fn desync(&self) -> Option<u64> {
+        match self.state {
+            ReplayState::Playing { desync, .. } => desync,
+            _ => None,
+        }
+    }
+
+    /// Stop recording (or playing back)
+    pub fn stop(&mut self) -> Option<Recording> {
+        match std::mem::replace(&mut self.state, ReplayState::Idle) {
+            ReplayState::Recording(recording) => Some(recording),
+            _ => None,
+        }
+    }
+
+    /// The orders for the current tick.
+    /// While playing back these are the recorded ones, the orders given
+    /// live are dropped.
+    pub fn orders(&mut self, live: Vec<Order>) -> Vec<Order> {
+        let tick = self.tick;
+        match &mut self.state {
+            ReplayState::Idle => live,
+            ReplayState::Recording(recording) => {
+                recording
+                    .orders
+                    .extend(live.iter().map(|order| (tick, order.clone())));
+                live
+            }
+            ReplayState::Playing {
+                recording,
+                next_order,
+                ..
+            } => {
+                let due = recording.orders[*next_order..]
+                    .iter()
+                    .take_while(|(t, _)| *t <= tick)
+                    .map(|(_, order)| order.clone())
+                    .collect::<Vec<_>>();
+                *next_order += due.len();
+                due
+            }
+        }
+    }
+
+    /// Store (or compare) the checksum of the tick that just ran
+    pub fn end_tick(&mut self, checksum: u64) {
+        let tick = self.tick;
+        self.tick += 1;
+
+        let finished = match &mut self.state {
+            ReplayState::Idle => return,
+            ReplayState::Recording(recording) => {
+                recording.checksums.push(checksum);
+                return;
+            }
+            ReplayState::Playing {
+                recording, desync, ..
+            } => {
+                let expected = recording.checksums.get(tick as usize);
+                if desync.is_none() && expected.is_some() && expected != Some(&checksum) {
+                    *desync = Some(tick);
+                    eprintln!("Replay desync at tick {}", tick);
+                }
+                self.tick as usize >= recording.checksums.len()
+            }
+        };
+
+        if finished {
+            match self.desync() {
+                Some(tick) => eprintln!("Replay finished, out of sync since tick {}", tick),
+                None => eprintln!("Replay finished in sync"),
+            }
+            self.state = ReplayState::Idle;
+        }
+    }
+}
+
+lazy_static! {
+    // Replays start from a fresh world, this carries them over the scene reload
+    static ref PENDING: Mutex<Option<Replay>> = Mutex::new(None);
+}
+
+pub fn set_pending(replay: Replay) {
+    *PENDING.lock().unwrap() = Some(replay);
+}
+
+pub fn take_pending() -> Option<Replay> {
+    PENDING.lock().unwrap().take()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn move_to(x: f32) -> Order {
+        Order::Move(vec![(PlayerId::new(1), Vector3::new(x, 0., 0.))])
+    }
+
+    fn record() -> Recording {
+        let mut replay = Replay::recording(7);
+        assert_eq!(replay.orders(vec![move_to(0.)]), vec![move_to(0.)]);
+        replay.end_tick(10);
+        replay.orders(vec![]);
+        replay.end_tick(11);
+        replay.orders(vec![move_to(1.), move_to(2.)]);
+        replay.end_tick(12);
+        replay.stop().unwrap()
+    }
+
+    #[test]
+    fn playback_follows_the_ticks() {
+        let mut replay = Replay::playing(record());
+
+        // Orders given while watching are ignored
+        assert_eq!(replay.orders(vec![move_to(5.)]), vec![move_to(0.)]);
+        replay.end_tick(10);
+        assert_eq!(replay.orders(vec![]), vec![]);
+        replay.end_tick(11);
+        assert_eq!(replay.orders(vec![]), vec![move_to(1.), move_to(2.)]);
+        assert!(replay.is_playing());
+
+        replay.end_tick(12);
+        assert!(!replay.is_playing());
+    }
+
+    #[test]
+    fn desync_is_the_first_bad_tick() {
+        let mut replay = Replay::playing(record());
+
+        replay.end_tick(10);
+        replay.end_tick(99);
+        assert_eq!(replay.desync(), Some(1));
+
+        replay.end_tick(98);
+        assert!(!replay.is_playing());
+    }
+
+    fn unit(world: &mut World, id: u8, x: f32) -> Entity {
+        world.insert(
+            (PlayerId::new(id),),
+            vec![(
+                FormationPos(0),
+                Health::new(100.),
+                Pos(Vector3::new(x, 0., 0.)),
+                Velocity(Vector3::zero()),
+            )],
+        )[0]
+    }
+
+    #[test]
+    fn checksum_follows_the_simulation() {
+        let universe = Universe::new();
+        let mut a = universe.create_world();
+        let mut b = universe.create_world();
+
+        // Stored in a different order
+        unit(&mut a, 1, 0.);
+        unit(&mut a, 2, 5.);
+        unit(&mut b, 2, 5.);
+        let b_unit = unit(&mut b, 1, 0.);
+        assert_eq!(world_checksum(&a), world_checksum(&b));
+
+        // Only player units count
+        b.insert((), vec![(FormationPos(3), Health::new(10.))]);
+        assert_eq!(world_checksum(&a), world_checksum(&b));
+
+        // Float noise is below the quantum, a real move isn't
+        b.get_component_mut::<Pos>(b_unit).unwrap().0.x = QUANTUM * 0.1;
+        assert_eq!(world_checksum(&a), world_checksum(&b));
+        b.get_component_mut::<Pos>(b_unit).unwrap().0.x = QUANTUM * 2.;
+        assert_ne!(world_checksum(&a), world_checksum(&b));
+        b.get_component_mut::<Pos>(b_unit).unwrap().0.x = 0.;
+
+        let _ = b.add_component(b_unit, OrderTarget(Vector3::new(1., 0., 2.)));
+        assert_ne!(world_checksum(&a), world_checksum(&b));
+    }
+}
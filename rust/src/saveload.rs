@@ -7,8 +7,12 @@ use legion::prelude::*;
 use serde::{Deserialize, Serialize};
 
 // use crate::combat::{AttackCooldown, AttackRange, AttackResponse};
+use crate::camera::{CameraBookmarks, RigState};
+use crate::control_group::ControlGroups;
 use crate::gameworld::with_world;
 use crate::player::PlayerId;
+use crate::procgen::WorldSeed;
+use crate::squad::{self, SquadData};
 // use crate::unit::{Hitpoints, UnitPos, Speed};
 use crate::movement::{MaxSpeed, Pos};
 // use crate::enemy::Enemy;
@@ -61,20 +65,38 @@ type PlayerUnitDataQuery = (
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SaveData {
+    /// The terrain is generated again from this
+    #[serde(default)]
+    pub seed: u64,
     pub player_units: Vec<PlayerUnitData>,
     // pub enemy_units: Vec<EnemyUnitData>,
+    #[serde(default)]
+    pub control_groups: Vec<Vec<PlayerId>>,
+    #[serde(default)]
+    pub camera_bookmarks: Vec<Option<RigState>>,
+    #[serde(default)]
+    pub squads: Vec<SquadData>,
 }
 
 impl SaveData {
     pub fn new() -> Self {
         Self {
+            seed: 0,
             player_units: Vec::with_capacity(4),
             // enemy_units: Vec::new(),
+            control_groups: Vec::new(),
+            camera_bookmarks: Vec::new(),
+            squads: Vec::new(),
         }
     }
 }
 
-pub fn save(slot: u8) -> Result<()> {
+pub fn save(
+    slot: u8,
+    seed: WorldSeed,
+    control_groups: &ControlGroups,
+    bookmarks: &CameraBookmarks,
+) -> Result<()> {
     let mut file = match File::create(file_path(slot)?) {
         Ok(file) => file,
         Err(e) => {
@@ -84,6 +106,8 @@ pub fn save(slot: u8) -> Result<()> {
     };
 
     let mut save_data = SaveData::new();
+    save_data.seed = seed.0;
+    save_data.camera_bookmarks = bookmarks.to_save_data();
 
     with_world(|world| {
         for (player_id, pos, /*hp, attack_range, attack_cooldown, attack_response,*/ speed) in
@@ -113,6 +137,9 @@ pub fn save(slot: u8) -> Result<()> {
         //         *speed,
         //     ));
         // }
+
+        save_data.control_groups = control_groups.to_save_data(world);
+        save_data.squads = squad::to_save_data(world);
     });
 
     serde_json::to_writer_pretty(&mut file, &save_data)?;
@@ -123,8 +150,7 @@ pub fn save(slot: u8) -> Result<()> {
 pub fn load(slot: u8) -> Result<SaveData> {
     let file = File::open(file_path(slot)?)?;
 
-    match serde_json::from_reader(&file) {
-        Ok(save_data) => Ok(save_data),
-        Err(_) => panic!("Could not deserialize game state"),
-    }
+    // A broken save is an `InvalidData` error, not a crash
+    let save_data = serde_json::from_reader(&file)?;
+    Ok(save_data)
 }
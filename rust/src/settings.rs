@@ -0,0 +1,155 @@
+use std::fs::File;
+use std::io::Result;
+use std::time::Duration;
+use std::path::PathBuf;
+
+use gdnative::api::OS;
+use serde::{Deserialize, Serialize};
+
+use crate::assignment::AssignmentMode;
+
+const SETTINGS_FILE: &str = "settings.json";
+
+fn file_path() -> PathBuf {
+    let os = OS::godot_singleton();
+    let mut path = PathBuf::from(os.get_user_data_dir().to_string());
+    path.push(SETTINGS_FILE);
+    path
+}
+
+// -----------------------------------------------------------------------------
+//     - Camera -
+// -----------------------------------------------------------------------------
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CameraSettings {
+    /// Pan when the mouse is close to the edge of the screen.
+    /// Off by default: the mouse is at the top left corner until it first moves.
+    pub edge_scroll: bool,
+    /// Distance from the edge, in pixels
+    pub edge_margin: f32,
+    pub edge_speed: f32,
+    /// Pan by dragging the ground with the middle mouse button.
+    /// Orbiting moves to shift + middle mouse button.
+    pub grab_pan: bool,
+}
+
+impl Default for CameraSettings {
+    fn default() -> Self {
+        Self {
+            edge_scroll: false,
+            edge_margin: 8.,
+            edge_speed: 60.,
+            grab_pan: false,
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Gestures -
+// -----------------------------------------------------------------------------
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GestureSettings {
+    /// Moving further than this (in pixels) while pressed starts a drag
+    pub drag_distance: f32,
+    pub double_click_ms: u64,
+    /// How far apart (in pixels) the clicks of a double click can be
+    pub double_click_distance: f32,
+    pub long_press_ms: u64,
+}
+
+impl GestureSettings {
+    pub fn double_click(&self) -> Duration {
+        Duration::from_millis(self.double_click_ms)
+    }
+
+    pub fn long_press(&self) -> Duration {
+        Duration::from_millis(self.long_press_ms)
+    }
+}
+
+impl Default for GestureSettings {
+    fn default() -> Self {
+        Self {
+            drag_distance: 4.,
+            double_click_ms: 400,
+            double_click_distance: 6.,
+            long_press_ms: 600,
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Gamepad -
+// -----------------------------------------------------------------------------
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GamepadSettings {
+    /// Stick input below this is ignored, from 0 to 1
+    pub deadzone: f32,
+    /// Virtual cursor speed with the right stick all the way out, in pixels per second
+    pub cursor_speed: f32,
+}
+
+impl Default for GamepadSettings {
+    fn default() -> Self {
+        Self {
+            deadzone: 0.2,
+            cursor_speed: 800.,
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Orders -
+// -----------------------------------------------------------------------------
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OrderSettings {
+    /// How units are matched to formation slots on a move order
+    pub assignment: AssignmentMode,
+}
+
+impl Default for OrderSettings {
+    fn default() -> Self {
+        Self {
+            assignment: AssignmentMode::MinTotal,
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Resources -
+// -----------------------------------------------------------------------------
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub camera: CameraSettings,
+    pub gestures: GestureSettings,
+    pub gamepad: GamepadSettings,
+    pub orders: OrderSettings,
+}
+
+impl Settings {
+    pub fn load() -> Self {
+        let file = match File::open(file_path()) {
+            Ok(f) => f,
+            Err(_) => return Self::default(),
+        };
+
+        match serde_json::from_reader(&file) {
+            Ok(settings) => settings,
+            Err(e) => {
+                eprintln!("Could not read settings: {:?}", e);
+                Self::default()
+            }
+        }
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let mut file = File::create(file_path())?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        Ok(())
+    }
+}
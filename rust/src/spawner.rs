@@ -1,4 +1,4 @@
-use gdnative::api::{Control, KinematicBody, PackedScene, ResourceLoader, TextureRect, Node};
+use gdnative::api::{Control, KinematicBody, PackedScene, ResourceLoader, Spatial, TextureRect, Node};
 use gdnative::{GodotObject, Ptr};
 
 pub fn spawn_unit() -> Ptr<KinematicBody> {
@@ -62,3 +62,33 @@ pub fn spawn_context_menu() -> Ptr<Control> {
     unsafe { context_menu.assume_safe().set_visible(false) };
     context_menu
 }
+
+pub fn spawn_unit_overlay() -> Ptr<Spatial> {
+    unsafe {
+        load_resource("res://UnitOverlay.tscn")
+        .assume_safe()
+        .cast::<Spatial>()
+        .unwrap()
+        .claim()
+    }
+}
+
+pub fn spawn_radial_menu() -> Ptr<Control> {
+    unsafe {
+        load_resource("res://RadialMenu.tscn")
+        .assume_safe()
+        .cast::<Control>()
+        .unwrap()
+        .claim()
+    }
+}
+
+pub fn spawn_move_marker() -> Ptr<Spatial> {
+    unsafe {
+        load_resource("res://MoveMarker.tscn")
+        .assume_safe()
+        .cast::<Spatial>()
+        .unwrap()
+        .claim()
+    }
+}
@@ -0,0 +1,412 @@
+use std::collections::HashMap;
+
+use gdnative::Color;
+use legion::prelude::*;
+use legion::systems::schedule::Builder;
+use serde::{Deserialize, Serialize};
+
+use crate::formation::{occupancy, Formation, FormationPos, FormationUI, FormationUnit};
+use crate::player::{PlayerId, Selected};
+
+const SQUAD_COLORS: [Color; 4] = [
+    Color { r: 0.9, g: 0.3, b: 0.3, a: 1. },
+    Color { r: 0.3, g: 0.8, b: 0.4, a: 1. },
+    Color { r: 0.3, g: 0.5, b: 0.9, a: 1. },
+    Color { r: 0.9, g: 0.8, b: 0.3, a: 1. },
+];
+
+// -----------------------------------------------------------------------------
+//     - Components -
+// -----------------------------------------------------------------------------
+
+/// A squad lives on its own entity together with its `Formation`
+#[derive(Debug, Clone)]
+pub struct Squad {
+    pub name: String,
+    pub color: Color,
+    /// March in formation when given a move order
+    pub march: bool,
+}
+
+/// The squad a unit belongs to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SquadId(pub Entity);
+
+// -----------------------------------------------------------------------------
+//     - Resources -
+// -----------------------------------------------------------------------------
+
+/// The squad shown in the formation UI
+pub struct ActiveSquad(pub Option<Entity>);
+
+// -----------------------------------------------------------------------------
+//     - Commands -
+// -----------------------------------------------------------------------------
+pub fn create_squad(world: &mut World, name: String) -> Entity {
+    let count = <Read<Squad>>::query().iter(world).count();
+    let color = SQUAD_COLORS[count % SQUAD_COLORS.len()];
+
+    let squad = Squad {
+        name,
+        color,
+        march: false,
+    };
+
+    world.insert((), Some((squad, Formation::new())))[0]
+}
+
+/// Move the units to a squad.
+/// They lose their formation position and are slotted in by the formation
+/// membership system.
+fn move_to_squad(world: &mut World, units: &[Entity], squad: Entity) {
+    for unit in units {
+        let _ = world.remove_component::<FormationPos>(*unit);
+        let _ = world.add_component(*unit, SquadId(squad));
+    }
+}
+
+fn selected_units(world: &World) -> Vec<(Entity, Entity)> {
+    <Read<SquadId>>::query()
+        .filter(tag::<Selected>())
+        .iter_entities(world)
+        .map(|(ent, squad_id)| (ent, squad_id.0))
+        .collect()
+}
+
+/// Put the selected units in a new squad
+pub fn squad_from_selection(world: &mut World) -> Option<Entity> {
+    let units = selected_units(world);
+    if units.len() == 0 {
+        return None;
+    }
+
+    let count = <Read<Squad>>::query().iter(world).count();
+    let squad = create_squad(world, format!("Squad {}", count + 1));
+    let units = units.iter().map(|(ent, _)| *ent).collect::<Vec<_>>();
+    move_to_squad(world, &units, squad);
+
+    Some(squad)
+}
+
+/// Move the selected units out of their squad into a new one named after it
+pub fn split_selection(world: &mut World) -> Option<Entity> {
+    let units = selected_units(world);
+    let from = units.first()?.1;
+
+    let name = match world.get_component::<Squad>(from) {
+        Some(squad) => format!("{} (split)", squad.name),
+        None => return squad_from_selection(world),
+    };
+
+    let squad = create_squad(world, name);
+    let units = units.iter().map(|(ent, _)| *ent).collect::<Vec<_>>();
+    move_to_squad(world, &units, squad);
+
+    Some(squad)
+}
+
+/// Merge every squad that has a selected unit into the first one.
+/// The emptied squads are disbanded by the formation membership system.
+pub fn merge_selected_squads(world: &mut World) -> Option<Entity> {
+    let mut squads = Vec::new();
+    for (_, squad) in selected_units(world) {
+        if !squads.contains(&squad) {
+            squads.push(squad);
+        }
+    }
+
+    let (into, others) = squads.split_first()?;
+
+    let units = <Read<SquadId>>::query()
+        .iter_entities(world)
+        .filter(|(_, squad_id)| others.contains(&squad_id.0))
+        .map(|(ent, _)| ent)
+        .collect::<Vec<_>>();
+    move_to_squad(world, &units, *into);
+
+    Some(*into)
+}
+
+// -----------------------------------------------------------------------------
+//     - Save data -
+// -----------------------------------------------------------------------------
+
+/// A squad in the save file, the members are `(player id, formation index)`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SquadData {
+    pub name: String,
+    pub color: (f32, f32, f32, f32),
+    pub march: bool,
+    pub members: Vec<(PlayerId, u16)>,
+}
+
+pub fn to_save_data(world: &World) -> Vec<SquadData> {
+    let members = <(Read<SquadId>, Read<FormationPos>)>::query()
+        .iter_entities(world)
+        .filter_map(|(ent, (squad_id, formation_pos))| {
+            let player_id = world.get_tag::<PlayerId>(ent)?;
+            Some((squad_id.0, (*player_id, formation_pos.0)))
+        })
+        .collect::<Vec<_>>();
+
+    <Read<Squad>>::query()
+        .iter_entities(world)
+        .map(|(ent, squad)| SquadData {
+            name: squad.name.clone(),
+            color: (squad.color.r, squad.color.g, squad.color.b, squad.color.a),
+            march: squad.march,
+            members: members
+                .iter()
+                .filter(|(squad, _)| *squad == ent)
+                .map(|(_, member)| *member)
+                .collect(),
+        })
+        .collect()
+}
+
+/// Recreate the saved squads and move their units back in.
+/// The squads the units were in before are left empty and get disbanded.
+pub fn restore(world: &mut World, saved: &[SquadData]) {
+    let units = <Read<SquadId>>::query()
+        .iter_entities(world)
+        .filter_map(|(ent, _)| world.get_tag::<PlayerId>(ent).map(|id| (*id, ent)))
+        .collect::<Vec<_>>();
+
+    for data in saved {
+        let squad = create_squad(world, data.name.clone());
+        if let Some(mut squad) = world.get_component_mut::<Squad>(squad) {
+            let (r, g, b, a) = data.color;
+            squad.color = Color { r, g, b, a };
+            squad.march = data.march;
+        }
+
+        let mut slots = Vec::with_capacity(data.members.len());
+        for (player_id, index) in &data.members {
+            let unit = match units.iter().find(|(id, _)| id == player_id) {
+                Some((_, ent)) => *ent,
+                None => continue,
+            };
+            let _ = world.add_component(unit, SquadId(squad));
+            let _ = world.add_component(unit, FormationPos(*index));
+            slots.push((unit, *index));
+        }
+
+        if let Some(mut formation) = world.get_component_mut::<Formation>(squad) {
+            formation.0 = occupancy(&slots);
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Systems -
+// -----------------------------------------------------------------------------
+
+/// The active squad is the one with the most selected units
+fn select_active_squad() -> Box<dyn Runnable> {
+    SystemBuilder::new("select active squad")
+        .write_resource::<ActiveSquad>()
+        .with_query(<Read<SquadId>>::query().filter(tag::<Selected>()))
+        .with_query(<Read<Squad>>::query())
+        .build_thread_local(|_, world, active_squad, (selected, squads)| {
+            let mut counts = HashMap::new();
+            for squad_id in selected.iter(world) {
+                *counts.entry(squad_id.0).or_insert(0) += 1;
+            }
+
+            if let Some((squad, _)) = counts.into_iter().max_by_key(|(_, count)| *count) {
+                active_squad.0 = Some(squad);
+            }
+
+            // The squad could have been merged away
+            let exists = squads
+                .iter_entities(world)
+                .any(|(ent, _)| Some(ent) == active_squad.0);
+            if !exists {
+                active_squad.0 = squads.iter_entities(world).map(|(ent, _)| ent).next();
+            }
+        })
+}
+
+/// Only show the formation of the active squad
+fn show_active_squad() -> Box<dyn Runnable> {
+    SystemBuilder::new("show active squad")
+        .read_resource::<ActiveSquad>()
+        .read_resource::<FormationUI>()
+        .with_query(<Read<Squad>>::query())
+        .with_query(<(Read<SquadId>, Write<FormationUnit>)>::query())
+        .build_thread_local(|_, world, (active_squad, formation_ui), (squads, units)| {
+            for (ent, squad) in squads.iter_entities(world) {
+                if Some(ent) == active_squad.0 {
+                    formation_ui.set_color(squad.color);
+                }
+            }
+
+            for (squad_id, mut unit) in units.iter_mut(world) {
+                unit.set_visible(Some(squad_id.0) == active_squad.0);
+            }
+        })
+}
+
+pub fn squad_systems(builder: Builder) -> Builder {
+    builder
+        .add_thread_local(select_active_squad())
+        .add_thread_local(show_active_squad())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::formation::formation_membership;
+
+    fn run_membership(world: &mut World) {
+        let mut schedule = Schedule::builder()
+            .add_thread_local(formation_membership())
+            .build();
+        schedule.execute(world, &mut Resources::default());
+    }
+
+    fn members(world: &World, squad: Entity) -> Vec<Entity> {
+        <Read<SquadId>>::query()
+            .iter_entities(world)
+            .filter(|(_, squad_id)| squad_id.0 == squad)
+            .map(|(ent, _)| ent)
+            .collect()
+    }
+
+    fn exists(world: &World, squad: Entity) -> bool {
+        world.get_component::<Squad>(squad).is_some()
+    }
+
+    // Two selected and two unselected units in "Alpha"
+    fn setup(world: &mut World) -> (Entity, Vec<Entity>, Vec<Entity>) {
+        let alpha = create_squad(world, "Alpha".to_string());
+        let selected = world
+            .insert((Selected,), (0..2).map(|_| (SquadId(alpha),)))
+            .to_vec();
+        let others = world.insert((), (0..2).map(|_| (SquadId(alpha),))).to_vec();
+        run_membership(world);
+        (alpha, selected, others)
+    }
+
+    #[test]
+    fn test_create_squad() {
+        let mut world = Universe::new().create_world();
+        let a = create_squad(&mut world, "A".to_string());
+        let b = create_squad(&mut world, "B".to_string());
+
+        assert_eq!(world.get_component::<Squad>(a).unwrap().name, "A");
+        assert_eq!(world.get_component::<Formation>(a).unwrap().0, 0);
+        assert_ne!(
+            world.get_component::<Squad>(a).unwrap().color,
+            world.get_component::<Squad>(b).unwrap().color
+        );
+    }
+
+    #[test]
+    fn test_squad_from_selection() {
+        let mut world = Universe::new().create_world();
+        let (alpha, selected, others) = setup(&mut world);
+
+        let squad = squad_from_selection(&mut world).unwrap();
+        run_membership(&mut world);
+
+        assert_eq!(world.get_component::<Squad>(squad).unwrap().name, "Squad 2");
+        assert_eq!(members(&world, squad).len(), selected.len());
+        assert_eq!(members(&world, alpha).len(), others.len());
+
+        // Slotted into the new formation from the front
+        let mut indices = selected
+            .iter()
+            .map(|ent| world.get_component::<FormationPos>(*ent).unwrap().0)
+            .collect::<Vec<_>>();
+        indices.sort();
+        assert_eq!(indices, vec![0, 1]);
+        assert_eq!(world.get_component::<Formation>(squad).unwrap().0, 0b11);
+    }
+
+    #[test]
+    fn test_split_selection() {
+        let mut world = Universe::new().create_world();
+        let (alpha, selected, _) = setup(&mut world);
+
+        let squad = split_selection(&mut world).unwrap();
+        run_membership(&mut world);
+
+        assert_eq!(world.get_component::<Squad>(squad).unwrap().name, "Alpha (split)");
+        assert_eq!(members(&world, squad).len(), selected.len());
+        assert!(exists(&world, alpha));
+    }
+
+    #[test]
+    fn test_merge_selected_squads() {
+        let mut world = Universe::new().create_world();
+        let (alpha, _, others) = setup(&mut world);
+        let split = split_selection(&mut world).unwrap();
+        run_membership(&mut world);
+
+        // One selected unit in each squad
+        assert!(world.add_tag(others[0], Selected).is_ok());
+        let into = merge_selected_squads(&mut world).unwrap();
+        let merged = match into == alpha {
+            true => split,
+            false => alpha,
+        };
+
+        // The merged squad is still there until the membership system runs
+        assert!(exists(&world, merged));
+        run_membership(&mut world);
+
+        assert_eq!(members(&world, into).len(), 4);
+        assert!(!exists(&world, merged));
+        assert_eq!(world.get_component::<Formation>(into).unwrap().0.count_ones(), 4);
+    }
+
+    #[test]
+    fn test_save_and_restore() {
+        let mut world = Universe::new().create_world();
+        let alpha = create_squad(&mut world, "Alpha".to_string());
+        world.get_component_mut::<Squad>(alpha).unwrap().march = true;
+        let units = world
+            .insert((PlayerId::new(1),), vec![(SquadId(alpha), FormationPos(5))])
+            .to_vec();
+        world.insert((PlayerId::new(2),), vec![(SquadId(alpha), FormationPos(0))]);
+        run_membership(&mut world);
+
+        let saved = to_save_data(&world);
+        assert_eq!(saved.len(), 1);
+        assert_eq!(saved[0].members.len(), 2);
+
+        // The units were moved around since the save
+        let other = create_squad(&mut world, "Other".to_string());
+        let _ = world.add_component(units[0], SquadId(other));
+        let _ = world.add_component(units[0], FormationPos(0));
+        run_membership(&mut world);
+
+        restore(&mut world, &saved);
+        run_membership(&mut world);
+
+        let squads = <Read<Squad>>::query()
+            .iter_entities(&world)
+            .map(|(ent, squad)| (ent, squad.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(squads.len(), 1);
+        let (restored, squad) = &squads[0];
+        assert_eq!(squad.name, "Alpha");
+        assert!(squad.march);
+        assert_eq!(members(&world, *restored).len(), 2);
+        assert_eq!(world.get_component::<FormationPos>(units[0]).unwrap().0, 5);
+        assert_eq!(world.get_component::<Formation>(*restored).unwrap().0, 1 << 5 | 1);
+    }
+
+    #[test]
+    fn test_empty_squad_is_disbanded() {
+        let mut world = Universe::new().create_world();
+        let (alpha, _, _) = setup(&mut world);
+        let empty = create_squad(&mut world, "Empty".to_string());
+
+        run_membership(&mut world);
+
+        assert!(!exists(&world, empty));
+        assert!(exists(&world, alpha));
+    }
+}
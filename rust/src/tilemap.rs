@@ -1,60 +1,610 @@
+use std::collections::{HashMap, HashSet};
+
+use bracket_pathfinding::prelude::{a_star_search, BaseMap};
 use gdnative::api::GridMap;
-use gdnative::{Ptr, Vector2};
+use gdnative::{Point2, Ptr, Rect2, Size2, Vector2, Vector3};
 use legion::prelude::*;
 
-use crate::procgen::{pack_vec2, random_bool};
+use crate::camera::CameraRig;
+use crate::procgen::{terrain_cell, TerrainConfig, WorldSeed};
+
+pub const CHUNK_SIZE: i32 = 16;
+// Chunks this close to the camera are loaded...
+const LOAD_RADIUS: i32 = 3;
+// ...and only unloaded once they're this far, so they don't flicker at the edge
+const UNLOAD_RADIUS: i32 = 5;
+// Cells set or cleared per frame
+const CELLS_PER_FRAME: usize = 512;
+// GridMap's INVALID_CELL_ITEM, clears the cell
+const EMPTY_CELL: i64 = -1;
+// How far outside the box around the start and the end a path can go, in cells
+const PATH_MARGIN: i32 = CHUNK_SIZE;
+
+/// The area covered by the cells, on the x / z plane
+pub fn cell_bounds(cells: impl Iterator<Item = Vector3>, cell_size: Vector3) -> Option<Rect2> {
+    let mut bounds: Option<(Vector2, Vector2)> = None;
+
+    for cell in cells {
+        let cell = Vector2::new(cell.x, cell.z);
+        bounds = Some(match bounds {
+            None => (cell, cell),
+            Some((min, max)) => (
+                Vector2::new(min.x.min(cell.x), min.y.min(cell.y)),
+                Vector2::new(max.x.max(cell.x), max.y.max(cell.y)),
+            ),
+        });
+    }
+
+    // Include the far side of the last cell
+    bounds.map(|(min, max)| {
+        let origin = Point2::new(min.x * cell_size.x, min.y * cell_size.z);
+        let size = Size2::new(
+            (max.x - min.x + 1.) * cell_size.x,
+            (max.y - min.y + 1.) * cell_size.z,
+        );
+        Rect2::new(origin, size)
+    })
+}
 
 pub struct TileMap(pub Ptr<GridMap>);
 
+impl TileMap {
+    pub fn cell_size(&self) -> Vector3 {
+        let gridmap = unsafe { self.0.assume_safe_during(self) };
+        gridmap.cell_size()
+    }
+
+    pub fn used_bounds(&self) -> Option<Rect2> {
+        let gridmap = unsafe { self.0.assume_safe_during(self) };
+        let cells = gridmap.get_used_cells();
+        let cells = (0..cells.len()).map(|i| cells.get(i).to_vector3());
+        cell_bounds(cells, gridmap.cell_size())
+    }
+}
+
 unsafe impl Send for TileMap {}
 unsafe impl Sync for TileMap {}
 
-// TODO: delete this (why?)
-pub struct Coords {
-    cells: Vec<Vector2>,
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ChunkPos {
+    pub x: i32,
+    pub z: i32,
+}
+
+impl ChunkPos {
+    pub fn new(x: i32, z: i32) -> Self {
+        Self { x, z }
+    }
+
+    /// The chunk a cell is in
+    pub fn from_cell(x: i32, z: i32) -> Self {
+        Self::new(x.div_euclid(CHUNK_SIZE), z.div_euclid(CHUNK_SIZE))
+    }
+
+    /// The chunk under a point in the world
+    pub fn from_world(pos: Vector3, cell_size: Vector3) -> Self {
+        let x = (pos.x / cell_size.x).floor() as i32;
+        let z = (pos.z / cell_size.z).floor() as i32;
+        Self::from_cell(x, z)
+    }
+
+    /// Distance in chunks, diagonals count as one
+    fn distance(&self, other: ChunkPos) -> i32 {
+        (self.x - other.x).abs().max((self.z - other.z).abs())
+    }
+
+    /// The `index`th cell of the chunk, row by row
+    fn cell(&self, index: usize) -> (i32, i32) {
+        let index = index as i32;
+        (self.x * CHUNK_SIZE + index % CHUNK_SIZE, self.z * CHUNK_SIZE + index / CHUNK_SIZE)
+    }
+}
+
+const CELLS_PER_CHUNK: usize = (CHUNK_SIZE * CHUNK_SIZE) as usize;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChunkState {
+    /// Number of cells generated so far
+    Loading(usize),
+    Loaded,
+    /// Number of cells cleared so far
+    Unloading(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CellOp {
+    Generate(i32, i32),
+    Clear(i32, i32),
+}
+
+// -----------------------------------------------------------------------------
+//     - Resources -
+// -----------------------------------------------------------------------------
+/// The map is split into chunks that are streamed in around the camera.
+/// Chunks that aren't in here don't have any cells in the GridMap.
+pub struct Chunks {
+    // The corners of the map, both included
+    min: ChunkPos,
+    max: ChunkPos,
+    centre: Option<ChunkPos>,
+    states: HashMap<ChunkPos, ChunkState>,
+    streaming: bool,
+}
+
+impl Chunks {
+    pub fn new(min: ChunkPos, max: ChunkPos) -> Self {
+        Self {
+            min,
+            max,
+            centre: None,
+            states: HashMap::new(),
+            streaming: true,
+        }
+    }
+
+    pub fn state(&self, chunk: ChunkPos) -> Option<ChunkState> {
+        self.states.get(&chunk).copied()
+    }
+
+    /// If the terrain at the cell is there to walk on
+    pub fn is_cell_loaded(&self, x: i32, z: i32) -> bool {
+        self.state(ChunkPos::from_cell(x, z)) == Some(ChunkState::Loaded)
+    }
+
+    pub fn loaded(&self) -> impl Iterator<Item = ChunkPos> + '_ {
+        self.states
+            .iter()
+            .filter(|(_, state)| **state == ChunkState::Loaded)
+            .map(|(chunk, _)| *chunk)
+    }
+
+    /// The whole map, loaded or not
+    pub fn bounds(&self, cell_size: Vector3) -> Rect2 {
+        let corners = vec![
+            Vector3::new((self.min.x * CHUNK_SIZE) as f32, 0., (self.min.z * CHUNK_SIZE) as f32),
+            Vector3::new(
+                ((self.max.x + 1) * CHUNK_SIZE - 1) as f32,
+                0.,
+                ((self.max.z + 1) * CHUNK_SIZE - 1) as f32,
+            ),
+        ];
+        cell_bounds(corners.into_iter(), cell_size).expect("there are two corners")
+    }
+
+    pub fn is_streaming(&self) -> bool {
+        self.streaming
+    }
+
+    fn in_map(&self, chunk: ChunkPos) -> bool {
+        chunk.x >= self.min.x && chunk.x <= self.max.x && chunk.z >= self.min.z && chunk.z <= self.max.z
+    }
+
+    /// Forget every chunk and leave the GridMap alone,
+    /// for when the map is built some other way
+    pub fn stop_streaming(&mut self) {
+        self.streaming = false;
+        self.centre = None;
+        self.states.clear();
+    }
+
+    /// Queue the chunks around `centre` and drop the ones too far away
+    pub fn update(&mut self, centre: ChunkPos) {
+        if !self.streaming || self.centre == Some(centre) {
+            return;
+        }
+        self.centre = Some(centre);
+
+        for (chunk, state) in self.states.iter_mut() {
+            let distance = chunk.distance(centre);
+            match state {
+                ChunkState::Unloading(_) if distance <= LOAD_RADIUS => *state = ChunkState::Loading(0),
+                ChunkState::Loading(_) | ChunkState::Loaded if distance > UNLOAD_RADIUS => {
+                    *state = ChunkState::Unloading(0)
+                }
+                _ => {}
+            }
+        }
+
+        for x in centre.x - LOAD_RADIUS..=centre.x + LOAD_RADIUS {
+            for z in centre.z - LOAD_RADIUS..=centre.z + LOAD_RADIUS {
+                let chunk = ChunkPos::new(x, z);
+                if self.in_map(chunk) {
+                    self.states.entry(chunk).or_insert(ChunkState::Loading(0));
+                }
+            }
+        }
+    }
+
+    /// Generate everything that's loaded again, after the seed changed.
+    /// Chunks half way through loading start over too, or their first cells
+    /// would keep the old terrain.
+    pub fn redraw(&mut self) {
+        for state in self.states.values_mut() {
+            match state {
+                ChunkState::Loaded | ChunkState::Loading(_) => *state = ChunkState::Loading(0),
+                ChunkState::Unloading(_) => {}
+            }
+        }
+    }
+
+    /// The next cells to set or clear, at most `budget` of them.
+    /// Chunks closest to the camera load first.
+    pub fn work(&mut self, budget: usize) -> Vec<CellOp> {
+        let centre = match self.centre {
+            Some(c) => c,
+            None => return Vec::new(),
+        };
+
+        let mut pending = self
+            .states
+            .iter()
+            .filter(|(_, state)| **state != ChunkState::Loaded)
+            .map(|(chunk, state)| (*chunk, *state))
+            .collect::<Vec<_>>();
+        pending.sort_by_key(|(chunk, state)| {
+            let unloading = match state {
+                ChunkState::Unloading(_) => true,
+                _ => false,
+            };
+            (unloading, chunk.distance(centre), chunk.x, chunk.z)
+        });
+
+        let mut ops = Vec::with_capacity(budget);
+        for (chunk, state) in pending {
+            let (done, op): (usize, fn(i32, i32) -> CellOp) = match state {
+                ChunkState::Loading(done) => (done, CellOp::Generate),
+                ChunkState::Unloading(done) => (done, CellOp::Clear),
+                ChunkState::Loaded => continue,
+            };
+
+            let count = (CELLS_PER_CHUNK - done).min(budget - ops.len());
+            for i in done..done + count {
+                let (x, z) = chunk.cell(i);
+                ops.push(op(x, z));
+            }
+
+            let done = done + count;
+            match (state, done == CELLS_PER_CHUNK) {
+                (ChunkState::Loading(_), true) => {
+                    self.states.insert(chunk, ChunkState::Loaded);
+                }
+                (ChunkState::Unloading(_), true) => {
+                    self.states.remove(&chunk);
+                }
+                (ChunkState::Loading(_), false) => {
+                    self.states.insert(chunk, ChunkState::Loading(done));
+                }
+                _ => {
+                    self.states.insert(chunk, ChunkState::Unloading(done));
+                }
+            }
+
+            if ops.len() == budget {
+                break;
+            }
+        }
+
+        ops
+    }
 }
 
-impl Coords {
+/// The cells units can walk on
+pub struct NavGrid {
+    walkable: HashSet<(i32, i32)>,
+    cell_size: Vector3,
+}
+
+impl NavGrid {
     pub fn new() -> Self {
-        let cells = make_cells();
-        Self { cells }
+        Self {
+            walkable: HashSet::new(),
+            // GridMap's default
+            cell_size: Vector3::new(2., 2., 2.),
+        }
+    }
+
+    pub fn set_cell_size(&mut self, cell_size: Vector3) {
+        self.cell_size = cell_size;
+    }
+
+    pub fn clear(&mut self) {
+        self.walkable.clear();
+    }
+
+    pub fn set_walkable(&mut self, x: i32, z: i32, walkable: bool) {
+        match walkable {
+            true => self.walkable.insert((x, z)),
+            false => self.walkable.remove(&(x, z)),
+        };
+    }
+
+    pub fn is_walkable(&self, x: i32, z: i32) -> bool {
+        self.walkable.contains(&(x, z))
     }
+
+    /// The cell under a point in the world
+    pub fn cell(&self, pos: Vector3) -> (i32, i32) {
+        let x = (pos.x / self.cell_size.x).floor() as i32;
+        let z = (pos.z / self.cell_size.z).floor() as i32;
+        (x, z)
+    }
+
+    /// The middle of a cell, on the ground
+    pub fn world_pos(&self, (x, z): (i32, i32)) -> Vector3 {
+        Vector3::new(
+            (x as f32 + 0.5) * self.cell_size.x,
+            0.,
+            (z as f32 + 0.5) * self.cell_size.z,
+        )
+    }
+
+    /// Waypoints from `from` to `to` through walkable cells, ending at `to`.
+    /// `None` if either end isn't walkable or there is no way through.
+    pub fn find_path(&self, from: Vector3, to: Vector3) -> Option<Vec<Vector3>> {
+        let (start, end) = (self.cell(from), self.cell(to));
+        if !self.is_walkable(start.0, start.1) || !self.is_walkable(end.0, end.1) {
+            return None;
+        }
+
+        let area = NavArea::around(self, start, end);
+        let path = a_star_search(area.index(start), area.index(end), &area);
+        if !path.success {
+            return None;
+        }
+
+        let mut waypoints = path
+            .steps
+            .into_iter()
+            .map(|index| area.cell(index))
+            .filter(|cell| *cell != start && *cell != end)
+            .map(|cell| self.world_pos(cell))
+            .collect::<Vec<_>>();
+        waypoints.push(to);
+        Some(waypoints)
+    }
+}
+
+// The part of the nav grid a path is searched in
+struct NavArea<'a> {
+    nav: &'a NavGrid,
+    min: (i32, i32),
+    width: i32,
+    height: i32,
 }
 
-fn make_cells() -> Vec<Vector2> {
-    let mut v = Vec::with_capacity(100 * 100);
-    for x in -1..100 {
-        for z in -1..100 {
-            v.push(Vector2::new(x as f32, z as f32))
+impl<'a> NavArea<'a> {
+    fn around(nav: &'a NavGrid, a: (i32, i32), b: (i32, i32)) -> Self {
+        let min = (a.0.min(b.0) - PATH_MARGIN, a.1.min(b.1) - PATH_MARGIN);
+        let max = (a.0.max(b.0) + PATH_MARGIN, a.1.max(b.1) + PATH_MARGIN);
+        Self {
+            nav,
+            min,
+            width: max.0 - min.0 + 1,
+            height: max.1 - min.1 + 1,
         }
     }
 
-    v
+    fn index(&self, (x, z): (i32, i32)) -> usize {
+        ((z - self.min.1) * self.width + x - self.min.0) as usize
+    }
+
+    fn cell(&self, index: usize) -> (i32, i32) {
+        let index = index as i32;
+        (self.min.0 + index % self.width, self.min.1 + index / self.width)
+    }
+
+    fn is_walkable(&self, (x, z): (i32, i32)) -> bool {
+        let inside = x >= self.min.0
+            && z >= self.min.1
+            && x < self.min.0 + self.width
+            && z < self.min.1 + self.height;
+        inside && self.nav.is_walkable(x, z)
+    }
 }
 
+impl<'a> BaseMap for NavArea<'a> {
+    fn is_opaque(&self, index: usize) -> bool {
+        !self.is_walkable(self.cell(index))
+    }
+
+    fn get_available_exits(&self, index: usize) -> Vec<(usize, f32)> {
+        let (x, z) = self.cell(index);
+        let mut exits = Vec::new();
+        for &(dx, dz) in &[(-1, -1), (0, -1), (1, -1), (-1, 0), (1, 0), (-1, 1), (0, 1), (1, 1)] {
+            let next = (x + dx, z + dz);
+            if !self.is_walkable(next) {
+                continue;
+            }
+
+            match dx != 0 && dz != 0 {
+                // Don't cut corners
+                true => {
+                    if self.is_walkable((x + dx, z)) && self.is_walkable((x, z + dz)) {
+                        exits.push((self.index(next), std::f32::consts::SQRT_2));
+                    }
+                }
+                false => exits.push((self.index(next), 1.)),
+            }
+        }
+        exits
+    }
+
+    fn get_pathing_distance(&self, a: usize, b: usize) -> f32 {
+        let (a, b) = (self.cell(a), self.cell(b));
+        let (dx, dz) = ((a.0 - b.0) as f32, (a.1 - b.1) as f32);
+        (dx * dx + dz * dz).sqrt()
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Systems -
+// -----------------------------------------------------------------------------
 pub fn draw_tilemap() -> Box<dyn Runnable> {
     SystemBuilder::new("draw tilemap")
-        .write_resource::<Coords>()
+        .read_resource::<WorldSeed>()
+        .read_resource::<CameraRig>()
+        .write_resource::<Chunks>()
         .write_resource::<TileMap>()
-        .build_thread_local(|_, _, (coords, tilemap), _| {
-            return; // TODO: eh???
+        .write_resource::<NavGrid>()
+        .build_thread_local(|_, _, (seed, rig, chunks, tilemap, nav), _| {
+            let centre = ChunkPos::from_world(rig.current.focus, tilemap.cell_size());
+            chunks.update(centre);
 
             let tilemap = unsafe { tilemap.0.assume_safe() };
+            let config = TerrainConfig::default();
 
-            if coords.cells.len() == 0 {
-                return;
+            for op in chunks.work(CELLS_PER_FRAME) {
+                // Unloaded chunks can't be walked through either
+                let (x, z, item, walkable) = match op {
+                    CellOp::Generate(x, z) => {
+                        let biome = terrain_cell(seed.0, x, z, &config);
+                        (x, z, biome.item(), biome.is_walkable())
+                    }
+                    CellOp::Clear(x, z) => (x, z, EMPTY_CELL, false),
+                };
+                tilemap.set_cell_item(x as i64, 0, z as i64, item, 0);
+                nav.set_walkable(x, z, walkable);
             }
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bounds_cover_whole_cells() {
+        let cells = vec![
+            Vector3::new(-3., 0., 2.),
+            Vector3::new(5., 1., -1.),
+            Vector3::new(0., 0., 0.),
+        ];
+
+        let bounds = cell_bounds(cells.into_iter(), Vector3::new(2., 2., 2.)).unwrap();
+        assert_eq!(bounds.origin, Point2::new(-6., -2.));
+        assert_eq!(bounds.size, Size2::new(18., 8.));
+    }
+
+    #[test]
+    fn no_cells_no_bounds() {
+        assert!(cell_bounds(Vec::new().into_iter(), Vector3::new(2., 2., 2.)).is_none());
+    }
+
+    fn loaded_after(chunks: &mut Chunks, frames: usize) -> Vec<CellOp> {
+        (0..frames).flat_map(|_| chunks.work(CELLS_PER_CHUNK)).collect()
+    }
+
+    #[test]
+    fn chunks_of_negative_cells() {
+        assert_eq!(ChunkPos::from_cell(0, 15), ChunkPos::new(0, 0));
+        assert_eq!(ChunkPos::from_cell(-1, 16), ChunkPos::new(-1, 1));
+        assert_eq!(ChunkPos::new(-1, 1).cell(CHUNK_SIZE as usize + 2), (-14, 17));
+    }
+
+    #[test]
+    fn nearest_chunks_load_first_within_budget() {
+        let mut chunks = Chunks::new(ChunkPos::new(0, 0), ChunkPos::new(9, 9));
+        chunks.update(ChunkPos::new(0, 0));
+
+        // Clipped to the map
+        let side = (LOAD_RADIUS + 1) as usize;
+        assert_eq!(chunks.states.len(), side * side);
+
+        let ops = chunks.work(CELLS_PER_CHUNK / 2);
+        assert_eq!(ops.len(), CELLS_PER_CHUNK / 2);
+        assert_eq!(ops[0], CellOp::Generate(0, 0));
+        assert_eq!(chunks.state(ChunkPos::new(0, 0)), Some(ChunkState::Loading(CELLS_PER_CHUNK / 2)));
+
+        chunks.work(CELLS_PER_CHUNK / 2);
+        assert!(chunks.is_cell_loaded(15, 15));
+        assert!(!chunks.is_cell_loaded(16, 0));
+    }
 
-            for cell in coords.cells.drain(..) {
-                let x = cell.x as i64;
-                let y = 0;
-                let z = cell.y as i64;
+    #[test]
+    fn redraw_restarts_loading_chunks() {
+        let mut chunks = Chunks::new(ChunkPos::new(0, 0), ChunkPos::new(0, 0));
+        chunks.update(ChunkPos::new(0, 0));
+        chunks.work(CELLS_PER_CHUNK / 2);
 
-                let seed = pack_vec2(cell);
-                let cell_type = if random_bool(seed, 100) { 1 } else { 0 };
+        chunks.redraw();
+        assert_eq!(chunks.state(ChunkPos::new(0, 0)), Some(ChunkState::Loading(0)));
 
-                // random_choice(&[2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1], seed);
-                tilemap.set_cell_item(x, y, z, cell_type, 0);
+        let ops = loaded_after(&mut chunks, 1);
+        assert_eq!(ops.len(), CELLS_PER_CHUNK);
+        assert_eq!(ops[0], CellOp::Generate(0, 0));
+        assert_eq!(chunks.state(ChunkPos::new(0, 0)), Some(ChunkState::Loaded));
+    }
+
+    #[test]
+    fn far_chunks_are_unloaded() {
+        let mut chunks = Chunks::new(ChunkPos::new(0, 0), ChunkPos::new(19, 0));
+        chunks.update(ChunkPos::new(0, 0));
+        loaded_after(&mut chunks, 4);
+        assert_eq!(chunks.loaded().count(), 4);
+
+        // Still within the unload radius
+        chunks.update(ChunkPos::new(2, 0));
+        assert_eq!(chunks.state(ChunkPos::new(0, 0)), Some(ChunkState::Loaded));
+
+        chunks.update(ChunkPos::new(8, 0));
+        let ops = loaded_after(&mut chunks, 20);
+        assert!(ops.contains(&CellOp::Clear(0, 0)));
+        assert_eq!(chunks.state(ChunkPos::new(0, 0)), None);
+        assert_eq!(chunks.state(ChunkPos::new(2, 0)), None);
+        assert_eq!(chunks.state(ChunkPos::new(3, 0)), Some(ChunkState::Loaded));
+        assert_eq!(chunks.state(ChunkPos::new(5, 0)), Some(ChunkState::Loaded));
+
+        chunks.stop_streaming();
+        chunks.update(ChunkPos::new(0, 0));
+        assert!(chunks.work(CELLS_PER_CHUNK).is_empty());
+    }
+
+    fn nav_grid(rows: &[&str]) -> NavGrid {
+        let mut nav = NavGrid::new();
+        for (z, row) in rows.iter().enumerate() {
+            for (x, c) in row.chars().enumerate() {
+                nav.set_walkable(x as i32, z as i32, c == '.');
             }
-        })
+        }
+        nav
+    }
+
+    #[test]
+    fn path_goes_around_walls() {
+        let nav = nav_grid(&[
+            ".....",
+            "####.",
+            ".....",
+        ]);
+        let from = nav.world_pos((0, 0));
+        let to = nav.world_pos((0, 2));
+
+        let path = nav.find_path(from, to).unwrap();
+        assert_eq!(path.last(), Some(&to));
+        // Through the gap on the right
+        assert!(path.contains(&nav.world_pos((4, 1))));
+        for waypoint in &path {
+            let (x, z) = nav.cell(*waypoint);
+            assert!(nav.is_walkable(x, z));
+        }
+    }
+
+    #[test]
+    fn no_path_through_walls() {
+        let nav = nav_grid(&[
+            "...",
+            "###",
+            "...",
+        ]);
+        assert!(nav.find_path(nav.world_pos((0, 0)), nav.world_pos((0, 2))).is_none());
+        assert!(nav.find_path(nav.world_pos((0, 0)), nav.world_pos((0, 1))).is_none());
+    }
+
+    #[test]
+    fn map_bounds_cover_every_chunk() {
+        let chunks = Chunks::new(ChunkPos::new(-1, 0), ChunkPos::new(1, 0));
+        let bounds = chunks.bounds(Vector3::new(2., 2., 2.));
+        assert_eq!(bounds.origin, Point2::new(-32., 0.));
+        assert_eq!(bounds.size, Size2::new(96., 32.));
+    }
 }
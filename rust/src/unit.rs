@@ -1,6 +1,7 @@
 use gdextras::node_ext::NodeExt;
 use gdnative::api::{KinematicBody, MeshInstance, SpatialMaterial};
 use gdnative::{Color, Ptr, Vector3};
+use serde::{Deserialize, Serialize};
 
 // -----------------------------------------------------------------------------
 //     - Components -
@@ -14,10 +15,18 @@ impl Unit {
         Self { inner }
     }
 
+    pub fn instance_id(&self) -> i64 {
+        unsafe { self.inner.assume_safe_during(self).get_instance_id() }
+    }
+
     pub fn translation(&self) -> Vector3 {
         unsafe { self.inner.assume_safe_during(self).translation() }
     }
 
+    pub fn set_translation(&self, pos: Vector3) {
+        unsafe { self.inner.assume_safe_during(self).set_translation(pos) }
+    }
+
     pub fn set_color(&mut self, color: Color) {
         let mesh = unsafe { self.inner.assume_safe() }
             .get_and_cast::<MeshInstance>("Armature/Skeleton/Humanoid");
@@ -29,3 +38,34 @@ impl Unit {
 
 unsafe impl Send for Unit {}
 unsafe impl Sync for Unit {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Health {
+    pub current: f32,
+    pub max: f32,
+}
+
+impl Health {
+    pub fn new(max: f32) -> Self {
+        Self { current: max, max }
+    }
+
+    /// Remaining health between 0 and 1
+    pub fn fraction(&self) -> f32 {
+        if self.max <= 0. {
+            return 0.;
+        }
+        (self.current / self.max).max(0.).min(1.)
+    }
+
+    pub fn is_damaged(&self) -> bool {
+        self.current < self.max
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum Role {
+    Infantry,
+    Support,
+    Medic,
+}